@@ -1,9 +1,9 @@
 extern crate walkdir;
 
 use std::env;
-use std::fs::metadata;
+use std::fs::{metadata, write};
 use std::io::Result;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
 
 use walkdir::WalkDir;
@@ -46,6 +46,38 @@ fn main() {
     } else {
         println!("Skipping running 'webpack'");
     }
+
+    write_asset_list(&out_dir).unwrap();
+}
+
+// Writes the names and contents of every file to embed in the binary, as
+// an array for the server to include
+fn write_asset_list(out_dir: &str) -> Result<()> {
+    let mut assets: Vec<(String, PathBuf)> = Vec::new();
+    for entry in WalkDir::new("public") {
+        let entry = entry.unwrap_or_else(|err| panic!("Error walking files: {}", err));
+        if entry.file_type().is_file() {
+            let name = entry.path().strip_prefix("public").unwrap();
+            assets.push((name.to_string_lossy().replace('\\', "/"), entry.path().to_path_buf()));
+        }
+    }
+    for name in ["app.js", "app.js.map"].iter() {
+        let path = Path::new(out_dir).join(name);
+        if path.exists() {
+            assets.push((name.to_string(), path));
+        }
+    }
+
+    let mut list = String::from("&[\n");
+    for (name, path) in assets {
+        list.push_str(&format!(
+            "    ({:?}, include_bytes!({:?})),\n",
+            name,
+            path.canonicalize()?
+        ));
+    }
+    list.push_str("]\n");
+    return write(Path::new(out_dir).join("assets.rs"), list);
 }
 
 fn assert_success(command_name: &str, status: ExitStatus) {
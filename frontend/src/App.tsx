@@ -7,9 +7,19 @@ import { Footer } from "./view/Footer";
 import { Library } from "./state/Library";
 import { UpcomingSongs } from "./view/UpcomingSongs";
 import { SongQueue } from "./state/song-queue";
+import { Login } from "./view/Login";
+import Api from "./api";
+
+const LIBRARY_REFRESH_INTERVAL_MS = 60 * 1000;
+const SESSION_UPDATE_INTERVAL_MS = 15 * 1000;
+const SESSION_NAME = navigator.platform || "Browser";
 
 interface AppState {
+  // Undefined until the server has said whether there is a session
+  user?: CurrentUser;
+  loggedOut: boolean;
   library?: Library;
+  savedPlaylists: SavedPlaylist[];
   songQueue: SongQueue;
   filteredSongIds?: string[];
   currentSongId?: string;
@@ -20,6 +30,10 @@ interface AppState {
 
 class App extends React.Component<Record<string, never>, AppState> {
   private readonly header: RefObject<Header>;
+  private refreshInterval?: ReturnType<typeof setInterval>;
+  private events?: EventSource;
+  private sessionInterval?: ReturnType<typeof setInterval>;
+  private sessionId?: string;
 
   constructor(props: Record<string, never>) {
     super(props);
@@ -30,13 +44,21 @@ class App extends React.Component<Record<string, never>, AppState> {
     this.onBackwards = this.onBackwards.bind(this);
     this.onEnded = this.onEnded.bind(this);
     this.onFilterChanged = this.onFilterChanged.bind(this);
+    this.refreshLibrary = this.refreshLibrary.bind(this);
+    this.updateSession = this.updateSession.bind(this);
+    this.onSessionCommand = this.onSessionCommand.bind(this);
+    this.onLoggedIn = this.onLoggedIn.bind(this);
+    this.onPlaylistChanged = this.onPlaylistChanged.bind(this);
 
     const songQueue = new SongQueue();
 
     this.header = React.createRef();
 
     this.state = {
+      user: undefined,
+      loggedOut: false,
       library: undefined,
+      savedPlaylists: [],
       songQueue,
       filteredSongIds: undefined,
       currentSongId: undefined,
@@ -47,17 +69,142 @@ class App extends React.Component<Record<string, never>, AppState> {
   }
 
   public async componentDidMount() {
-    const library = await Library.new();
+    let user: CurrentUser;
+    try {
+      user = await Api.auth.me();
+    } catch (error) {
+      this.setState({ loggedOut: true });
+      return;
+    }
+    await this.onLoggedIn(user);
+  }
+
+  private async onLoggedIn(user: CurrentUser) {
+    this.setState({ user, loggedOut: false });
+
+    const [library, songQueue, savedPlaylists] = await Promise.all([
+      Library.new(),
+      SongQueue.load(),
+      Api.playlists.getAll(),
+    ]);
     const filteredSongIds = library.applyFilter(() => true);
     this.setState({
       library,
+      songQueue,
+      savedPlaylists,
       filteredSongIds,
     });
+
+    this.refreshInterval = setInterval(
+      this.refreshLibrary,
+      LIBRARY_REFRESH_INTERVAL_MS,
+    );
+
+    // Library events carry the changed songs, but fetching the changes
+    // keeps the library's revision consistent with its contents.
+    this.events = new EventSource("/api/events");
+    for (const eventName of ["song-updated", "songs-added", "songs-removed"]) {
+      this.events.addEventListener(eventName, this.refreshLibrary);
+    }
+    this.events.addEventListener("session-command", this.onSessionCommand);
+    this.events.addEventListener("playlist-changed", this.onPlaylistChanged);
+
+    this.updateSession();
+    this.sessionInterval = setInterval(
+      this.updateSession,
+      SESSION_UPDATE_INTERVAL_MS,
+    );
+  }
+
+  public componentWillUnmount() {
+    if (this.refreshInterval !== undefined) {
+      clearInterval(this.refreshInterval);
+    }
+    if (this.events !== undefined) {
+      this.events.close();
+    }
+    if (this.sessionInterval !== undefined) {
+      clearInterval(this.sessionInterval);
+    }
+  }
+
+  // Tells the server what this player is doing, so that other devices can
+  // see it and take over playback from it.
+  private async updateSession() {
+    try {
+      const session = await Api.sessions.update({
+        id: this.sessionId,
+        name: SESSION_NAME,
+        song_id: this.state.currentSongId,
+        position: this.header.current?.getPosition() || 0,
+        playing: this.state.playing,
+        queue: this.state.songQueue.songIdQueue,
+      });
+      this.sessionId = session.id;
+    } catch (error) {
+      console.error("Unable to update session: ", error);
+    }
+  }
+
+  private onSessionCommand(event: MessageEvent) {
+    const sessionCommand: SessionCommandEvent = JSON.parse(event.data);
+    if (sessionCommand.session_id !== this.sessionId) {
+      return;
+    }
+
+    const command = sessionCommand.command;
+    if (command.type === "play") {
+      this.header.current?.play();
+    } else if (command.type === "pause") {
+      this.header.current?.pause();
+    } else if (command.type === "skip") {
+      this.onEnded();
+    } else if (command.type === "resume") {
+      this.setState({ songQueue: new SongQueue(command.queue) });
+      if (command.song_id !== null) {
+        this.header.current?.seekOnLoad(command.position);
+        this.onSongSelected(command.song_id);
+      }
+    }
+  }
+
+  private onPlaylistChanged(event: MessageEvent) {
+    const savedPlaylists: SavedPlaylist[] = JSON.parse(event.data);
+    this.setState({ savedPlaylists });
+  }
+
+  private async refreshLibrary() {
+    const library = this.state.library;
+    if (library === undefined) {
+      return;
+    }
+
+    try {
+      const newLibrary = await library.refresh();
+      if (newLibrary !== library) {
+        this.setState((state) => {
+          return {
+            library: newLibrary,
+            filteredSongIds: newLibrary.applyFilter(
+              state.currentFilter.predicate,
+            ),
+          };
+        });
+      }
+    } catch (error) {
+      console.error("Unable to refresh library: ", error);
+    }
   }
 
   private onSongSelected(songId: string) {
     if (songId !== this.state.currentSongId) {
       this.setState({ currentSongId: songId });
+      const song = this.state.library?.getSong(songId);
+      if (song !== undefined) {
+        Api.nowPlaying(song).catch((error) => {
+          console.error("Unable to send now playing: ", error);
+        });
+      }
     } else {
       if (this.header.current) {
         this.header.current.restartSong();
@@ -90,7 +237,7 @@ class App extends React.Component<Record<string, never>, AppState> {
     }
   }
 
-  private onEnded() {
+  private async onEnded() {
     this.setState((state) => {
       if (
         state.currentSongId === undefined ||
@@ -105,10 +252,19 @@ class App extends React.Component<Record<string, never>, AppState> {
       };
     });
 
-    const [nextSongId, newSongQueue] = this.state.songQueue.getNextSongId(this.state.filteredSongIds || []);
-    this.setState({
-      songQueue: newSongQueue,
-    });
+    let nextSongId: string | undefined;
+    try {
+      let newSongQueue: SongQueue;
+      [nextSongId, newSongQueue] = await this.state.songQueue.getNextSongId(
+        this.state.filteredSongIds || [],
+      );
+      this.setState({
+        songQueue: newSongQueue,
+      });
+    } catch (error) {
+      console.error("Unable to get next song: ", error);
+      return;
+    }
 
     if (nextSongId) {
       this.onSongSelected(nextSongId);
@@ -131,6 +287,10 @@ class App extends React.Component<Record<string, never>, AppState> {
   }
 
   public render() {
+    if (this.state.loggedOut) {
+      return <Login onLoggedIn={this.onLoggedIn} />;
+    }
+
     if (
       this.state.library === undefined ||
       this.state.filteredSongIds === undefined
@@ -152,7 +312,10 @@ class App extends React.Component<Record<string, never>, AppState> {
           />
         </div>
         <div className="playlists-container">
-          <Filters onFilterChanged={this.onFilterChanged} />
+          <Filters
+            savedPlaylists={this.state.savedPlaylists}
+            onFilterChanged={this.onFilterChanged}
+          />
         </div>
         <div className="song-queue-container">
           <UpcomingSongs
@@ -33,7 +33,38 @@ function doGet<T>(path: string): Promise<T> {
   return fromAxiosPromise(axios.get(url));
 }
 
+function doPost<T>(path: string, data: unknown): Promise<T> {
+  const url = "/api/" + path;
+  return fromAxiosPromise(axios.post(url, data));
+}
+
+function doDelete<T>(path: string): Promise<T> {
+  const url = "/api/" + path;
+  return fromAxiosPromise(axios.delete(url));
+}
+
+// Requests that change anything are rejected unless they carry the token
+// the server handed out with the session.
+function setCsrfToken(user: CurrentUser) {
+  axios.defaults.headers.common["X-CSRF-Token"] = user.csrf_token;
+}
+
 const Api = {
+  auth: {
+    me: async (): Promise<CurrentUser> => {
+      const user: CurrentUser = await doGet("me");
+      setCsrfToken(user);
+      return user;
+    },
+    login: async (name: string, password: string): Promise<CurrentUser> => {
+      const user: CurrentUser = await doPost("login", { name, password });
+      setCsrfToken(user);
+      return user;
+    },
+    logout: (): Promise<void> => {
+      return doPost("logout", {});
+    },
+  },
   songs: {
     getAll: (): Promise<Song[]> => {
       return doGet("songs");
@@ -41,6 +72,71 @@ const Api = {
     getSrc: (song: Song): Promise<string> => {
       return doGet("songs/" + song.id + "/contents");
     },
+    setRating: (song: Song, rating: number): Promise<Song> => {
+      return doPost("songs/" + song.id + "/rating", { rating });
+    },
+    recordPlay: (
+      song: Song,
+      startedAt: number,
+      fraction: number,
+    ): Promise<Song> => {
+      return doPost("songs/" + song.id + "/plays", {
+        started_at: startedAt,
+        fraction,
+      });
+    },
+  },
+  queue: {
+    get: (): Promise<Queue> => {
+      return doGet("queue");
+    },
+    update: (update: QueueUpdate): Promise<Queue> => {
+      return doPost("queue", update);
+    },
+  },
+  sessions: {
+    getAll: (): Promise<PlaybackSession[]> => {
+      return doGet("sessions");
+    },
+    update: (update: SessionUpdate): Promise<PlaybackSession> => {
+      return doPost("sessions", update);
+    },
+    transfer: (
+      session: PlaybackSession,
+      target: PlaybackSession,
+    ): Promise<void> => {
+      return doPost("sessions/" + session.id + "/transfer", {
+        target: target.id,
+      });
+    },
+    sendCommand: (
+      session: PlaybackSession,
+      command: SessionCommand,
+    ): Promise<void> => {
+      return doPost("sessions/" + session.id + "/commands", command);
+    },
+  },
+  playlists: {
+    getAll: (): Promise<SavedPlaylist[]> => {
+      return doGet("playlists");
+    },
+    create: (name: string, songIds: string[]): Promise<SavedPlaylist> => {
+      return doPost("playlists", { name, song_ids: songIds });
+    },
+    update: (playlist: SavedPlaylist): Promise<SavedPlaylist> => {
+      return doPost("playlists/" + playlist.id, playlist);
+    },
+    delete: (playlist: SavedPlaylist): Promise<void> => {
+      return doDelete("playlists/" + playlist.id);
+    },
+  },
+  nowPlaying: (song: Song): Promise<void> => {
+    return doPost("now-playing", { song_id: song.id });
+  },
+  library: {
+    getChanges: (since: number): Promise<LibraryChanges> => {
+      return doGet("changes?since=" + since);
+    },
   },
 };
 
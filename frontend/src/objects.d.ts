@@ -3,9 +3,122 @@ interface Song {
   title: string;
   genre: string;
   artist: string;
+  artist_id: string;
   album: string;
+  album_id: string | null;
+  album_artist: string;
+  disc_number: number;
+  track_number: number;
   duration: number;
   rating: number;
+  play_count: number;
+  last_played: number | null;
+  replay_gain: ReplayGain | null;
+}
+
+// Gains are in dB, and peaks are fractions of full scale
+interface ReplayGain {
+  track_gain: number;
+  album_gain: number;
+  track_peak: number;
+  album_peak: number;
+}
+
+interface Album {
+  id: string;
+  title: string;
+  artist_id: string;
+  artist: string;
+  song_count: number;
+  duration: number;
+  has_art: boolean;
+}
+
+interface AlbumDetails extends Album {
+  songs: Song[];
+}
+
+interface Artist {
+  id: string;
+  name: string;
+  album_count: number;
+  song_count: number;
+}
+
+interface ArtistDetails extends Artist {
+  albums: Album[];
+  songs: Song[];
+}
+
+interface LibraryChanges {
+  revision: number;
+  added: Song[];
+  updated: Song[];
+  removed: string[];
+}
+
+type ShuffleStrategy = "uniform" | "rating" | "least-recently-played";
+
+interface Queue {
+  song_ids: string[];
+  strategy: ShuffleStrategy;
+  spread_artists: boolean;
+  updated_at: number;
+}
+
+interface QueueUpdate {
+  song_ids?: string[];
+  candidates?: string[];
+  strategy?: ShuffleStrategy;
+  spread_artists?: boolean;
+  length?: number;
+}
+
+interface PlaybackSession {
+  id: string;
+  name: string;
+  song_id: string | null;
+  position: number;
+  playing: boolean;
+  queue: string[];
+  updated_at: number;
+}
+
+interface SessionUpdate {
+  id?: string;
+  name: string;
+  song_id?: string;
+  position: number;
+  playing: boolean;
+  queue: string[];
+}
+
+type SessionCommand =
+  | { type: "play" }
+  | { type: "pause" }
+  | { type: "skip" }
+  | {
+      type: "resume";
+      song_id: string | null;
+      position: number;
+      queue: string[];
+    };
+
+interface SessionCommandEvent {
+  session_id: string;
+  command: SessionCommand;
+}
+
+interface CurrentUser {
+  name: string;
+  admin: boolean;
+  csrf_token: string;
+}
+
+interface SavedPlaylist {
+  id: string;
+  name: string;
+  song_ids: string[];
 }
 
 interface SongFilter {
@@ -3,20 +3,44 @@ import Api from "../api";
 // Represents an immutable list of songs.
 export class Library {
   private songs: Readonly<Map<string, Song>>;
+  public readonly revision: number;
 
-  private constructor(songs: Map<string, Song>) {
+  private constructor(songs: Map<string, Song>, revision: number) {
     this.songs = songs;
+    this.revision = revision;
   }
 
   static async new(): Promise<Library> {
-    const songs = await Api.songs.getAll();
+    const changes = await Api.library.getChanges(0);
 
     const songsMap = new Map();
-    for (const song of songs) {
+    for (const song of changes.added) {
       songsMap.set(song.id, song);
     }
 
-    return new Library(songsMap);
+    return new Library(songsMap, changes.revision);
+  }
+
+  // Fetches any changes made since this library was loaded. Returns this
+  // same library if nothing has changed, or otherwise a new library.
+  async refresh(): Promise<Library> {
+    const changes = await Api.library.getChanges(this.revision);
+    if (changes.revision === this.revision) {
+      return this;
+    }
+
+    const songsMap = new Map(this.songs);
+    for (const song of changes.added) {
+      songsMap.set(song.id, song);
+    }
+    for (const song of changes.updated) {
+      songsMap.set(song.id, song);
+    }
+    for (const id of changes.removed) {
+      songsMap.delete(id);
+    }
+
+    return new Library(songsMap, changes.revision);
   }
 
   getSong(id: string | undefined): Song | undefined {
@@ -1,34 +1,51 @@
-const QUEUE_LENGTH = 50;
+import Api from "../api";
 
-/**
- * Randomly selects n elements from the given array.
- */
-function pickN<T>(xs: T[], n: number): T[] {
-  const selected: T[] = [];
-  const remaining = xs.slice();
-  while (selected.length < n && remaining.length > 0) {
-    const index = Math.floor(Math.random() * remaining.length);
-    selected.push(remaining[index]);
-    remaining.splice(index, 1);
-  }
-  return selected;
-}
+const QUEUE_LENGTH = 50;
 
+// The queue itself is kept on the server, which picks songs using a
+// weighted shuffle and keeps the queue across reloads and devices.
 export class SongQueue {
-  constructor(public readonly songIdQueue: string[] = []) {}
+  // The server remembers the songs it picks from, so they are only sent
+  // again when the filter changes
+  constructor(
+    public readonly songIdQueue: string[] = [],
+    private readonly sentCandidates?: string[],
+  ) {}
 
-  public getNextSongId(availableSongIds: string[]): [string | undefined, SongQueue] {
-    const newSongIdQueue = this.songIdQueue.filter((s) => availableSongIds.includes(s));
+  static async load(): Promise<SongQueue> {
+    const queue = await Api.queue.get();
+    return new SongQueue(queue.song_ids);
+  }
+
+  public async getNextSongId(
+    availableSongIds: string[],
+  ): Promise<[string | undefined, SongQueue]> {
+    let newSongIdQueue = this.songIdQueue.filter((s) =>
+      availableSongIds.includes(s),
+    );
 
-    if (newSongIdQueue.length <= QUEUE_LENGTH) {
-      newSongIdQueue.push(
-        ...pickN(
-          availableSongIds.filter((x) => !newSongIdQueue.includes(x)),
-          QUEUE_LENGTH + 1 - newSongIdQueue.length),
-      );
+    const candidatesChanged = !sameSongIds(
+      this.sentCandidates,
+      availableSongIds,
+    );
+    if (newSongIdQueue.length <= QUEUE_LENGTH || candidatesChanged) {
+      const queue = await Api.queue.update({
+        candidates: candidatesChanged ? availableSongIds : undefined,
+        length: QUEUE_LENGTH + 1,
+      });
+      newSongIdQueue = queue.song_ids;
     }
 
     const nextSong = newSongIdQueue.shift();
-    return [nextSong, new SongQueue(newSongIdQueue)];
+    const queue = await Api.queue.update({ song_ids: newSongIdQueue });
+    return [nextSong, new SongQueue(queue.song_ids, availableSongIds)];
   }
 }
+
+function sameSongIds(a: string[] | undefined, b: string[]): boolean {
+  return (
+    a !== undefined &&
+    a.length === b.length &&
+    a.every((songId, i) => songId === b[i])
+  );
+}
@@ -7,7 +7,7 @@ export interface Playlist {
   predicate: (s: Song) => boolean;
 }
 
-const allPlaylists: Playlist[] = [
+const ratingPlaylists: Playlist[] = [
   {
     name: "All",
     predicate: () => true,
@@ -48,14 +48,29 @@ function makeFilter(playlist: Playlist, searchString: string): SongFilter {
   };
 }
 
+function fromSavedPlaylist(savedPlaylist: SavedPlaylist): Playlist {
+  const songIds = new Set(savedPlaylist.song_ids);
+  return {
+    name: savedPlaylist.name,
+    predicate: (s: Song) => songIds.has(s.id),
+  };
+}
+
 interface PlaylistsProps {
+  savedPlaylists: SavedPlaylist[];
   onFilterChanged: (filter: SongFilter) => void;
 }
 
 export function Filters(props: PlaylistsProps) {
-  const { onFilterChanged } = props;
+  const { savedPlaylists, onFilterChanged } = props;
+
+  const allPlaylists: Playlist[] = React.useMemo(() => {
+    return ratingPlaylists.concat(savedPlaylists.map(fromSavedPlaylist));
+  }, [savedPlaylists]);
 
-  const [currentPlaylist, setCurrentPlaylist] = React.useState(allPlaylists[0]);
+  const [currentPlaylist, setCurrentPlaylist] = React.useState(
+    ratingPlaylists[0],
+  );
   const [searchString, setSearchString] = React.useState("");
 
   React.useEffect(() => {
@@ -64,7 +79,7 @@ export function Filters(props: PlaylistsProps) {
 
   const onPlaylistSelectedCallbacks: Array<() => void> = React.useMemo(() => {
     return allPlaylists.map((playlist) => () => setCurrentPlaylist(playlist));
-  }, []);
+  }, [allPlaylists]);
 
   const onSearchBoxChange = React.useCallback(
     (e: ChangeEvent<HTMLInputElement>) => {
@@ -30,6 +30,10 @@ interface HeaderState {
 
 export class Header extends React.PureComponent<HeaderProps, HeaderState> {
   private readonly audio: RefObject<HTMLAudioElement>;
+  // Unix timestamp, in seconds, of when the current song started playing
+  private playStartedAt?: number;
+  // Position to jump to once the next song has loaded
+  private pendingSeek?: number;
 
   constructor(props: HeaderProps) {
     super(props);
@@ -54,6 +58,10 @@ export class Header extends React.PureComponent<HeaderProps, HeaderState> {
   public componentDidUpdate(prevProps: HeaderProps) {
     const oldSongId = prevProps.currentSongId;
     const nextSongId = this.props.currentSongId;
+    if (oldSongId && nextSongId !== oldSongId) {
+      this.recordPlay(oldSongId);
+    }
+
     if (!nextSongId && oldSongId) {
       this.setState(
         {
@@ -76,6 +84,7 @@ export class Header extends React.PureComponent<HeaderProps, HeaderState> {
         },
         () => {
           document.title = nextSong.artist + " - " + nextSong.title;
+          this.playStartedAt = Math.floor(Date.now() / 1000);
 
           if (this.audio.current) {
             this.audio.current.pause();
@@ -91,6 +100,10 @@ export class Header extends React.PureComponent<HeaderProps, HeaderState> {
                 () => {
                   if (this.audio.current) {
                     this.audio.current.load();
+                    if (this.pendingSeek !== undefined) {
+                      this.audio.current.currentTime = this.pendingSeek;
+                      this.pendingSeek = undefined;
+                    }
                   }
                   if (this.state.playing === "loading") {
                     this.play();
@@ -107,6 +120,33 @@ export class Header extends React.PureComponent<HeaderProps, HeaderState> {
     }
   }
 
+  private recordPlay(songId: string) {
+    const song = this.props.library.getSong(songId);
+    const position = this.state.currentSongPosition;
+    if (
+      song === undefined ||
+      position === undefined ||
+      this.playStartedAt === undefined ||
+      song.duration <= 0
+    ) {
+      return;
+    }
+
+    const fraction = Math.min(1, position / song.duration);
+    Api.songs.recordPlay(song, this.playStartedAt, fraction).catch((error) => {
+      console.error("Unable to record play: ", error);
+    });
+  }
+
+  public getPosition(): number {
+    return this.state.currentSongPosition || 0;
+  }
+
+  // Sets the position to start from when the next song starts playing
+  public seekOnLoad(position: number) {
+    this.pendingSeek = position;
+  }
+
   public restartSong() {
     if (this.audio.current && this.state.currentSongSrc !== undefined) {
       this.audio.current.currentTime = 0;
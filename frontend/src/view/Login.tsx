@@ -0,0 +1,53 @@
+import * as React from "react";
+import { FormEvent } from "react";
+import Api from "../api";
+
+interface Props {
+  onLoggedIn: (user: CurrentUser) => void;
+}
+
+export function Login(props: Props) {
+  const { onLoggedIn } = props;
+
+  const [name, setName] = React.useState("");
+  const [password, setPassword] = React.useState("");
+  const [error, setError] = React.useState<string | undefined>(undefined);
+
+  const onSubmit = React.useCallback(
+    async (e: FormEvent<HTMLFormElement>) => {
+      e.preventDefault();
+      try {
+        onLoggedIn(await Api.auth.login(name, password));
+      } catch (error) {
+        setError("Incorrect user name or password");
+      }
+    },
+    [name, password, onLoggedIn],
+  );
+
+  return (
+    <form className="login" onSubmit={onSubmit}>
+      <input
+        className="form-control"
+        placeholder="User name"
+        autoComplete="username"
+        value={name}
+        onChange={(e) => setName(e.target.value)}
+      />
+      <input
+        className="form-control"
+        type="password"
+        placeholder="Password"
+        autoComplete="current-password"
+        value={password}
+        onChange={(e) => setPassword(e.target.value)}
+      />
+      {error !== undefined ? (
+        <div className="login-error">{error}</div>
+      ) : null}
+      <button className="btn btn-primary" type="submit">
+        Log in
+      </button>
+    </form>
+  );
+}
@@ -4,56 +4,850 @@ extern crate serde_derive;
 extern crate serde_json;
 
 use regex::Regex;
+use rouille::input::json_input;
 use rouille::{Request, Response};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+use time::OffsetDateTime;
 
-use crate::library::Library;
+use crate::art;
+use crate::auth::AuthenticatedUser;
+use crate::catalog::{Album, Artist, Catalog};
+use crate::config::{config, StorageBackend};
+use crate::events::{Event, EventBus, NowPlaying};
+use crate::library::{random_id, Library, ReplayGain, Song};
+use crate::metrics;
+use crate::play_log::Play;
+use crate::queue::{QueueStore, ShuffleStrategy};
+use crate::sessions::{Command, SessionCommand, SessionStore, SessionUpdate};
+use crate::stats::compute_stats;
+use crate::signed_urls::{self, UrlKind};
 use crate::storage;
+use crate::streaming;
+use crate::transcoding::{self, Format};
+use crate::user_data::{Playlist, UserData, UserDataStore};
 
-#[derive(Serialize)]
-struct ApiSong {
+const DEFAULT_QUEUE_LENGTH: usize = 50;
+
+// Number of entries returned in each ranking by default
+const DEFAULT_STATS_LIMIT: i64 = 10;
+
+#[derive(Serialize, Clone)]
+pub struct ApiSong {
     id: String,
     title: String,
     genre: String,
     artist: String,
+    artist_id: String,
     album: String,
+    album_id: Option<String>,
+    album_artist: String,
+    disc_number: u32,
+    track_number: u32,
     duration: u32,
     rating: u32,
+    play_count: u32,
+    last_played: Option<i64>,
+    replay_gain: Option<ReplayGain>,
+}
+
+impl ApiSong {
+    pub fn new(song: &Song, user_data: &UserData) -> ApiSong {
+        let stats = user_data.play_log.stats(&song.id);
+        return ApiSong {
+            id: song.id.clone(),
+            title: song.title.clone(),
+            genre: song.genre.clone(),
+            artist: song.artist.clone(),
+            artist_id: song.artist_id(),
+            album: song.album.clone(),
+            album_id: song.album_id(),
+            album_artist: song.album_artist.clone(),
+            disc_number: song.disc_number,
+            track_number: song.track_number,
+            duration: song.duration,
+            rating: user_data.rating(song),
+            play_count: stats.play_count,
+            last_played: stats.last_played,
+            replay_gain: song.replay_gain,
+        };
+    }
 }
 
+#[derive(Serialize)]
+struct ApiAlbum {
+    id: String,
+    title: String,
+    artist_id: String,
+    artist: String,
+    song_count: usize,
+    duration: u32,
+    has_art: bool,
+}
+
+impl ApiAlbum {
+    fn new(album: &Album) -> ApiAlbum {
+        return ApiAlbum {
+            id: album.id.clone(),
+            title: album.title.clone(),
+            artist_id: album.artist_id.clone(),
+            artist: album.artist.clone(),
+            song_count: album.song_ids.len(),
+            duration: album.duration,
+            has_art: album.has_art,
+        };
+    }
+}
+
+#[derive(Serialize)]
+struct ApiAlbumDetails {
+    #[serde(flatten)]
+    album: ApiAlbum,
+    songs: Vec<ApiSong>,
+}
+
+#[derive(Serialize)]
+struct ApiArtist {
+    id: String,
+    name: String,
+    album_count: usize,
+    song_count: usize,
+}
+
+impl ApiArtist {
+    fn new(artist: &Artist) -> ApiArtist {
+        return ApiArtist {
+            id: artist.id.clone(),
+            name: artist.name.clone(),
+            album_count: artist.album_ids.len(),
+            song_count: artist.song_ids.len(),
+        };
+    }
+}
+
+#[derive(Serialize)]
+struct ApiArtistDetails {
+    #[serde(flatten)]
+    artist: ApiArtist,
+    albums: Vec<ApiAlbum>,
+    songs: Vec<ApiSong>,
+}
+
+#[derive(Serialize)]
+struct ApiChanges {
+    revision: u64,
+    added: Vec<ApiSong>,
+    updated: Vec<ApiSong>,
+    removed: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiRating {
+    rating: u32,
+}
+
+#[derive(Deserialize)]
+struct ApiNowPlaying {
+    song_id: String,
+}
+
+#[derive(Deserialize)]
+struct ApiNewPlay {
+    // Defaults to the time the play is recorded
+    started_at: Option<i64>,
+    fraction: f64,
+}
+
+#[derive(Deserialize)]
+struct ApiQueueUpdate {
+    // Replaces the queue with exactly these songs
+    song_ids: Option<Vec<String>>,
+    // Otherwise tops the queue up. Candidates are the songs to pick from,
+    // which are remembered for later updates that leave them out. Until
+    // any are sent, songs are picked from the whole library.
+    candidates: Option<Vec<String>>,
+    strategy: Option<ShuffleStrategy>,
+    spread_artists: Option<bool>,
+    length: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ApiTransfer {
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct ApiPlaylist {
+    name: String,
+    song_ids: Vec<String>,
+}
+
+// Parses an optional integer query parameter, returning an error response
+// if it is present but not an integer.
+fn get_integer_param(request: &Request, name: &str) -> std::result::Result<Option<i64>, Response> {
+    return match request.get_param(name) {
+        Some(value) => match value.parse::<i64>() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(
+                Response::text(format!("Parameter {} is not an integer", name))
+                    .with_status_code(400),
+            ),
+        },
+        None => Ok(None),
+    };
+}
+
+// Ratings and plays belong to the user, so aren't in the library's
+// version and are added to it for responses that include them
+fn user_etag(version: &str, user_data: &UserData) -> String {
+    return format!(
+        "\"{}-{}-{}-{}\"",
+        version,
+        user_data.name,
+        user_data.ratings_version,
+        user_data.play_log.plays.len()
+    );
+}
+
+// Songs are streamed through the server, rather than from signed URLs,
+// when configured to or when storage can't sign URLs
+fn proxies_songs() -> bool {
+    return config().server.proxy_songs || config().storage.backend == StorageBackend::Local;
+}
+
+// Locks are always taken in the order that fields are declared here so that
+// handlers needing more than one can't deadlock.
 pub struct Api {
-    library: Library,
+    library: RwLock<Library>,
+    // Worked out from the library whenever it is loaded, and swapped with it
+    catalog: RwLock<Arc<Catalog>>,
+    // Version of library.json in storage that the library matches
+    library_version: Mutex<Option<String>>,
+    user_data: UserDataStore,
+    events: EventBus,
+    queues: QueueStore,
+    sessions: SessionStore,
     songs_contents_regex: Regex,
+    songs_stream_regex: Regex,
+    songs_download_regex: Regex,
+    songs_rating_regex: Regex,
+    songs_plays_regex: Regex,
+    session_transfer_regex: Regex,
+    session_commands_regex: Regex,
+    playlist_regex: Regex,
+    album_art_regex: Regex,
+    album_regex: Regex,
+    artist_regex: Regex,
 }
 
 impl Api {
+    // The library is empty until reload_library succeeds
     pub fn new() -> Api {
         return Api {
-            library: Library::new(),
+            library: RwLock::new(Library::default()),
+            catalog: RwLock::new(Arc::new(Catalog::default())),
+            library_version: Mutex::new(None),
+            user_data: UserDataStore::new(),
+            events: EventBus::new(),
+            queues: QueueStore::new(),
+            sessions: SessionStore::new(),
             songs_contents_regex: Regex::new(r"/api/songs/([a-zA-Z0-9]+)/contents").unwrap(),
+            songs_stream_regex: Regex::new(r"^/api/songs/([a-zA-Z0-9]+)/stream$").unwrap(),
+            songs_download_regex: Regex::new(r"^/api/songs/([a-zA-Z0-9]+)/download$").unwrap(),
+            songs_rating_regex: Regex::new(r"^/api/songs/([a-zA-Z0-9]+)/rating$").unwrap(),
+            songs_plays_regex: Regex::new(r"^/api/songs/([a-zA-Z0-9]+)/plays$").unwrap(),
+            session_transfer_regex: Regex::new(r"^/api/sessions/([a-zA-Z0-9]+)/transfer$").unwrap(),
+            session_commands_regex: Regex::new(r"^/api/sessions/([a-zA-Z0-9]+)/commands$").unwrap(),
+            playlist_regex: Regex::new(r"^/api/playlists/([a-zA-Z0-9]+)$").unwrap(),
+            album_art_regex: Regex::new(r"^/api/albums/([a-zA-Z0-9]+)/art$").unwrap(),
+            album_regex: Regex::new(r"^/api/albums/([a-zA-Z0-9]+)$").unwrap(),
+            artist_regex: Regex::new(r"^/api/artists/([a-zA-Z0-9]+)$").unwrap(),
         };
     }
 
-    fn songs(&self) -> Response {
+    fn user_data(
+        &self,
+        user: &AuthenticatedUser,
+    ) -> std::result::Result<Arc<RwLock<UserData>>, Response> {
+        return self.user_data.get(&user.name).map_err(|error| {
+            Response::text(format!("Unable to load user data: {}", error)).with_status_code(500)
+        });
+    }
+
+    fn songs(&self, request: &Request, user: &AuthenticatedUser) -> Response {
+        let library = self.library.read().unwrap();
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let user_data = user_data.read().unwrap();
         let mut songs: Vec<ApiSong> = Vec::new();
-        for song in self.library.songs.values() {
-            songs.push(ApiSong {
-                id: song.id.clone(),
-                title: song.title.clone(),
-                genre: song.genre.clone(),
-                artist: song.artist.clone(),
-                album: song.album.clone(),
-                duration: song.duration,
-                rating: song.rating,
-            });
-        }
-        return Response::json(&songs);
-    }
-
-    fn song_contents(&self, id: String) -> Response {
-        return match self.library.songs.get(&id) {
+        for song in library.songs.values() {
+            songs.push(ApiSong::new(song, &user_data));
+        }
+        let etag = user_etag(&format!("{:016x}", library.content_hash()), &user_data);
+        return Response::json(&songs)
+            .with_unique_header("Cache-Control", "no-cache")
+            .with_etag(request, etag);
+    }
+
+    fn changes(&self, request: &Request, user: &AuthenticatedUser) -> Response {
+        let since = match request.get_param("since") {
+            Some(since) => match since.parse::<u64>() {
+                Ok(since) => since,
+                Err(_) => {
+                    return Response::text("Revision is not an integer").with_status_code(400)
+                }
+            },
+            None => 0,
+        };
+
+        let library = self.library.read().unwrap();
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let user_data = user_data.read().unwrap();
+        let mut changes = library.changes_since(since);
+        if since != 0 {
+            let changed: HashSet<&String> = changes
+                .added
+                .iter()
+                .chain(changes.updated.iter())
+                .map(|song| &song.id)
+                .collect();
+            for id in user_data.ratings_changed_since(since) {
+                if let Some(song) = library.songs.get(id) {
+                    if !changed.contains(id) {
+                        changes.updated.push(song);
+                    }
+                }
+            }
+        }
+        let etag = user_etag(&format!("{}-{}", changes.revision, since), &user_data);
+        let changes = ApiChanges {
+            revision: changes.revision,
+            added: changes
+                .added
+                .into_iter()
+                .map(|song| ApiSong::new(song, &user_data))
+                .collect(),
+            updated: changes
+                .updated
+                .into_iter()
+                .map(|song| ApiSong::new(song, &user_data))
+                .collect(),
+            removed: changes.removed,
+        };
+        return Response::json(&changes)
+            .with_unique_header("Cache-Control", "no-cache")
+            .with_etag(request, etag);
+    }
+
+    fn albums(&self, request: &Request) -> Response {
+        let catalog = self.catalog.read().unwrap().clone();
+        let mut albums: Vec<ApiAlbum> = catalog.albums.values().map(ApiAlbum::new).collect();
+        albums.sort_by(|a, b| (&a.artist, &a.title, &a.id).cmp(&(&b.artist, &b.title, &b.id)));
+        return Response::json(&albums)
+            .with_unique_header("Cache-Control", "no-cache")
+            .with_etag(request, format!("\"{}\"", catalog.version));
+    }
+
+    fn album(&self, id: String, request: &Request, user: &AuthenticatedUser) -> Response {
+        let library = self.library.read().unwrap();
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let user_data = user_data.read().unwrap();
+        let catalog = self.catalog.read().unwrap().clone();
+        let album = match catalog.albums.get(&id) {
+            Some(album) => album,
+            None => {
+                return Response::text(format!("Album with id {} not found", id))
+                    .with_status_code(404)
+            }
+        };
+        let details = ApiAlbumDetails {
+            album: ApiAlbum::new(album),
+            songs: album
+                .song_ids
+                .iter()
+                .map(|id| ApiSong::new(&library.songs[id], &user_data))
+                .collect(),
+        };
+        let etag = user_etag(&catalog.version, &user_data);
+        return Response::json(&details)
+            .with_unique_header("Cache-Control", "no-cache")
+            .with_etag(request, etag);
+    }
+
+    fn artists(&self, request: &Request) -> Response {
+        let catalog = self.catalog.read().unwrap().clone();
+        let mut artists: Vec<ApiArtist> = catalog.artists.values().map(ApiArtist::new).collect();
+        artists.sort_by(|a, b| (&a.name, &a.id).cmp(&(&b.name, &b.id)));
+        return Response::json(&artists)
+            .with_unique_header("Cache-Control", "no-cache")
+            .with_etag(request, format!("\"{}\"", catalog.version));
+    }
+
+    fn artist(&self, id: String, request: &Request, user: &AuthenticatedUser) -> Response {
+        let library = self.library.read().unwrap();
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let user_data = user_data.read().unwrap();
+        let catalog = self.catalog.read().unwrap().clone();
+        let artist = match catalog.artists.get(&id) {
+            Some(artist) => artist,
+            None => {
+                return Response::text(format!("Artist with id {} not found", id))
+                    .with_status_code(404)
+            }
+        };
+        let details = ApiArtistDetails {
+            artist: ApiArtist::new(artist),
+            albums: artist
+                .album_ids
+                .iter()
+                .map(|id| ApiAlbum::new(&catalog.albums[id]))
+                .collect(),
+            songs: artist
+                .song_ids
+                .iter()
+                .map(|id| ApiSong::new(&library.songs[id], &user_data))
+                .collect(),
+        };
+        let etag = user_etag(&catalog.version, &user_data);
+        return Response::json(&details)
+            .with_unique_header("Cache-Control", "no-cache")
+            .with_etag(request, etag);
+    }
+
+    fn set_song_rating(&self, id: String, request: &Request, user: &AuthenticatedUser) -> Response {
+        let rating: ApiRating = match json_input(request) {
+            Ok(rating) => rating,
+            Err(error) => {
+                return Response::text(format!("Invalid rating: {}", error)).with_status_code(400)
+            }
+        };
+        if rating.rating > 5 {
+            return Response::text("Rating must be between 0 and 5").with_status_code(400);
+        }
+
+        let library = self.library.read().unwrap();
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let mut user_data = user_data.write().unwrap();
+        let song = match library.songs.get(&id) {
+            Some(song) => song,
+            None => {
+                return Response::text(format!("Song with id {} not found", id))
+                    .with_status_code(404)
+            }
+        };
+        if user_data.rating(song) == rating.rating {
+            return Response::json(&ApiSong::new(song, &user_data));
+        }
+
+        // Ratings only change the user's own data, never the shared library.
+        // The change is reported against the next revision, so the user's
+        // other clients find it in the changes until the library moves on.
+        let original_rating = user_data.ratings.insert(id.clone(), rating.rating);
+        let original_revision = user_data
+            .rating_revisions
+            .insert(id.clone(), library.revision + 1);
+        user_data.ratings_version += 1;
+        if let Err(error) = user_data.save() {
+            match original_rating {
+                Some(original_rating) => user_data.ratings.insert(id.clone(), original_rating),
+                None => user_data.ratings.remove(&id),
+            };
+            match original_revision {
+                Some(original_revision) => user_data.rating_revisions.insert(id, original_revision),
+                None => user_data.rating_revisions.remove(&id),
+            };
+            user_data.ratings_version -= 1;
+            return Response::text(format!("Unable to save rating: {}", error))
+                .with_status_code(500);
+        }
+
+        let updated = ApiSong::new(song, &user_data);
+        self.events
+            .publish(&user.name, Event::SongUpdated(updated.clone()));
+        return Response::json(&updated);
+    }
+
+    fn record_play(&self, id: String, request: &Request, user: &AuthenticatedUser) -> Response {
+        let new_play: ApiNewPlay = match json_input(request) {
+            Ok(new_play) => new_play,
+            Err(error) => {
+                return Response::text(format!("Invalid play: {}", error)).with_status_code(400)
+            }
+        };
+        if !(0.0..=1.0).contains(&new_play.fraction) {
+            return Response::text("Fraction must be between 0 and 1").with_status_code(400);
+        }
+
+        let library = self.library.read().unwrap();
+        let song = match library.songs.get(&id) {
+            Some(song) => song,
+            None => {
+                return Response::text(format!("Song with id {} not found", id))
+                    .with_status_code(404)
+            }
+        };
+
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let mut user_data = user_data.write().unwrap();
+        let play = Play {
+            song_id: id,
+            started_at: new_play
+                .started_at
+                .unwrap_or_else(|| OffsetDateTime::now_utc().unix_timestamp()),
+            fraction: new_play.fraction,
+        };
+        if let Err(error) = user_data.play_log.record(play) {
+            return Response::text(format!("Unable to record play: {}", error))
+                .with_status_code(500);
+        }
+
+        let api_song = ApiSong::new(song, &user_data);
+        self.events
+            .publish(&user.name, Event::SongUpdated(api_song.clone()));
+        return Response::json(&api_song);
+    }
+
+    fn history(&self, request: &Request, user: &AuthenticatedUser) -> Response {
+        let from = match get_integer_param(request, "from") {
+            Ok(from) => from,
+            Err(response) => return response,
+        };
+        let to = match get_integer_param(request, "to") {
+            Ok(to) => to,
+            Err(response) => return response,
+        };
+
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let user_data = user_data.read().unwrap();
+        return Response::json(&user_data.play_log.between(from, to));
+    }
+
+    fn stats(&self, request: &Request, user: &AuthenticatedUser) -> Response {
+        let mut from = match get_integer_param(request, "from") {
+            Ok(from) => from,
+            Err(response) => return response,
+        };
+        let to = match get_integer_param(request, "to") {
+            Ok(to) => to,
+            Err(response) => return response,
+        };
+        // A number of days is a shorthand for a window ending now
+        match get_integer_param(request, "days") {
+            Ok(Some(days)) if days < 0 => {
+                return Response::text("Days must not be negative").with_status_code(400);
+            }
+            Ok(Some(days)) => {
+                let seconds = days.saturating_mul(24 * 60 * 60);
+                from = Some(OffsetDateTime::now_utc().unix_timestamp().saturating_sub(seconds));
+            }
+            Ok(None) => {}
+            Err(response) => return response,
+        };
+        let limit = match get_integer_param(request, "limit") {
+            Ok(limit) => limit.unwrap_or(DEFAULT_STATS_LIMIT).max(0) as usize,
+            Err(response) => return response,
+        };
+
+        let library = self.library.read().unwrap();
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let user_data = user_data.read().unwrap();
+        return Response::json(&compute_stats(&library, &user_data, from, to, limit));
+    }
+
+    fn now_playing(&self, request: &Request, user: &AuthenticatedUser) -> Response {
+        let now_playing: ApiNowPlaying = match json_input(request) {
+            Ok(now_playing) => now_playing,
+            Err(error) => {
+                return Response::text(format!("Invalid request: {}", error)).with_status_code(400)
+            }
+        };
+        if !self
+            .library
+            .read()
+            .unwrap()
+            .songs
+            .contains_key(&now_playing.song_id)
+        {
+            return Response::text(format!("Song with id {} not found", now_playing.song_id))
+                .with_status_code(404);
+        }
+
+        self.events.publish(
+            &user.name,
+            Event::NowPlaying(NowPlaying {
+                song_id: now_playing.song_id,
+            }),
+        );
+        return Response::empty_204();
+    }
+
+    fn queue(&self, user: &AuthenticatedUser) -> Response {
+        return match self.queues.get(&user.name) {
+            Ok(queue) => Response::json(&queue),
+            Err(error) => {
+                Response::text(format!("Unable to load queue: {}", error)).with_status_code(500)
+            }
+        };
+    }
+
+    fn update_queue(&self, request: &Request, user: &AuthenticatedUser) -> Response {
+        let update: ApiQueueUpdate = match json_input(request) {
+            Ok(update) => update,
+            Err(error) => {
+                return Response::text(format!("Invalid queue: {}", error)).with_status_code(400)
+            }
+        };
+
+        let library = self.library.read().unwrap();
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let user_data = user_data.read().unwrap();
+        let mut queue = match self.queues.get(&user.name) {
+            Ok(queue) => queue,
+            Err(error) => {
+                return Response::text(format!("Unable to load queue: {}", error))
+                    .with_status_code(500)
+            }
+        };
+        if let Some(strategy) = update.strategy {
+            queue.strategy = strategy;
+        }
+        if let Some(spread_artists) = update.spread_artists {
+            queue.spread_artists = spread_artists;
+        }
+
+        match update.song_ids {
+            Some(song_ids) => {
+                queue.song_ids = song_ids
+                    .into_iter()
+                    .filter(|id| library.songs.contains_key(id))
+                    .collect();
+                queue.updated_at = OffsetDateTime::now_utc().unix_timestamp();
+            }
+            None => {
+                let candidates = match update.candidates {
+                    Some(candidates) => {
+                        if let Err(error) =
+                            self.queues.set_candidates(&user.name, candidates.clone())
+                        {
+                            return Response::text(format!("Unable to save queue: {}", error))
+                                .with_status_code(500);
+                        }
+                        candidates
+                    }
+                    None => match self.queues.candidates(&user.name) {
+                        Ok(Some(candidates)) => candidates,
+                        Ok(None) => library.songs.keys().cloned().collect(),
+                        Err(error) => {
+                            return Response::text(format!("Unable to load queue: {}", error))
+                                .with_status_code(500)
+                        }
+                    },
+                };
+                let length = update.length.unwrap_or(DEFAULT_QUEUE_LENGTH);
+                queue.fill(&library, &user_data, &candidates, length);
+            }
+        }
+
+        if let Err(error) = self.queues.set(&user.name, queue.clone()) {
+            return Response::text(format!("Unable to save queue: {}", error))
+                .with_status_code(500);
+        }
+        return Response::json(&queue);
+    }
+
+    fn update_session(&self, request: &Request, user: &AuthenticatedUser) -> Response {
+        let update: SessionUpdate = match json_input(request) {
+            Ok(update) => update,
+            Err(error) => {
+                return Response::text(format!("Invalid session: {}", error)).with_status_code(400)
+            }
+        };
+        return match self.sessions.update(&user.name, update) {
+            Some(session) => Response::json(&session),
+            None => Response::text("Session not found").with_status_code(404),
+        };
+    }
+
+    fn transfer_session(
+        &self,
+        id: String,
+        request: &Request,
+        user: &AuthenticatedUser,
+    ) -> Response {
+        let transfer: ApiTransfer = match json_input(request) {
+            Ok(transfer) => transfer,
+            Err(error) => {
+                return Response::text(format!("Invalid transfer: {}", error)).with_status_code(400)
+            }
+        };
+
+        return match self.sessions.transfer(&user.name, &id, &transfer.target) {
+            Some(commands) => {
+                for command in commands {
+                    self.events
+                        .publish(&user.name, Event::SessionCommand(command));
+                }
+                Response::empty_204()
+            }
+            None => Response::text("Session not found").with_status_code(404),
+        };
+    }
+
+    fn send_session_command(
+        &self,
+        id: String,
+        request: &Request,
+        user: &AuthenticatedUser,
+    ) -> Response {
+        let command: Command = match json_input(request) {
+            Ok(command) => command,
+            Err(error) => {
+                return Response::text(format!("Invalid command: {}", error)).with_status_code(400)
+            }
+        };
+        if self.sessions.get(&user.name, &id).is_none() {
+            return Response::text(format!("Session with id {} not found", id))
+                .with_status_code(404);
+        }
+
+        self.events.publish(
+            &user.name,
+            Event::SessionCommand(SessionCommand {
+                session_id: id,
+                command,
+            }),
+        );
+        return Response::empty_204();
+    }
+
+    fn playlists(&self, user: &AuthenticatedUser) -> Response {
+        return match self.user_data(user) {
+            Ok(user_data) => Response::json(&user_data.read().unwrap().playlists),
+            Err(response) => response,
+        };
+    }
+
+    // Creates a new playlist, or replaces an existing one if an id is given
+    fn save_playlist(
+        &self,
+        id: Option<String>,
+        request: &Request,
+        user: &AuthenticatedUser,
+    ) -> Response {
+        let playlist: ApiPlaylist = match json_input(request) {
+            Ok(playlist) => playlist,
+            Err(error) => {
+                return Response::text(format!("Invalid playlist: {}", error)).with_status_code(400)
+            }
+        };
+
+        let library = self.library.read().unwrap();
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let mut user_data = user_data.write().unwrap();
+        let original_playlists = user_data.playlists.clone();
+        let playlist = Playlist {
+            id: id.clone().unwrap_or_else(random_id),
+            name: playlist.name,
+            song_ids: playlist
+                .song_ids
+                .into_iter()
+                .filter(|id| library.songs.contains_key(id))
+                .collect(),
+        };
+        match user_data.playlists.iter_mut().find(|p| p.id == playlist.id) {
+            Some(existing) => *existing = playlist.clone(),
+            None => {
+                if id.is_some() {
+                    return Response::text("Playlist not found").with_status_code(404);
+                }
+                user_data.playlists.push(playlist.clone());
+            }
+        }
+
+        if let Err(error) = user_data.save() {
+            user_data.playlists = original_playlists;
+            return Response::text(format!("Unable to save playlist: {}", error))
+                .with_status_code(500);
+        }
+        self.events.publish(
+            &user.name,
+            Event::PlaylistChanged(user_data.playlists.clone()),
+        );
+        return Response::json(&playlist);
+    }
+
+    fn delete_playlist(&self, id: String, user: &AuthenticatedUser) -> Response {
+        let user_data = match self.user_data(user) {
+            Ok(user_data) => user_data,
+            Err(response) => return response,
+        };
+        let mut user_data = user_data.write().unwrap();
+        let original_playlists = user_data.playlists.clone();
+        user_data.playlists.retain(|playlist| playlist.id != id);
+        if user_data.playlists.len() == original_playlists.len() {
+            return Response::text("Playlist not found").with_status_code(404);
+        }
+
+        if let Err(error) = user_data.save() {
+            user_data.playlists = original_playlists;
+            return Response::text(format!("Unable to save playlists: {}", error))
+                .with_status_code(500);
+        }
+        self.events.publish(
+            &user.name,
+            Event::PlaylistChanged(user_data.playlists.clone()),
+        );
+        return Response::empty_204();
+    }
+
+    // Returns the URL to play a song from, which is either signed for
+    // storage or points back at the server
+    fn song_contents(&self, id: String, request: &Request) -> Response {
+        if proxies_songs() {
+            return match self.library.read().unwrap().songs.get(&id) {
+                Some(_) => Response::text(format!("/api/songs/{}/stream", id)),
+                None => {
+                    Response::text(format!("Song with id {} not found", id)).with_status_code(404)
+                }
+            };
+        }
+        return match self.library.read().unwrap().songs.get(&id) {
             Some(song) => {
-                return match storage::sign(&format!("Music{}", song.file_location)) {
-                    Ok(signature) => Response::text(signature),
+                return match signed_urls::sign_song(song, UrlKind::Play, request.remote_addr().ip())
+                {
+                    Ok(signature) => {
+                        metrics::record_signed_url();
+                        Response::text(signature)
+                    }
                     Err(error) => Response::text(format!("Unable to compute signature: {}", error)),
                 }
             }
@@ -61,18 +855,287 @@ impl Api {
         };
     }
 
-    pub fn route_api(&self, request: &Request) -> Response {
+    // Streams the song as it is stored, or transcoded if a format is given
+    fn stream_song(&self, id: String, request: &Request) -> Response {
+        let path = match self.library.read().unwrap().songs.get(&id) {
+            Some(song) => format!("Music{}", song.file_location),
+            None => {
+                return Response::text(format!("Song with id {} not found", id))
+                    .with_status_code(404)
+            }
+        };
+
+        let format = match request.get_param("format") {
+            Some(name) => match Format::parse(&name) {
+                Some(format) => format,
+                None => {
+                    return Response::text(format!("Unknown format {}", name)).with_status_code(400)
+                }
+            },
+            None => return streaming::serve(request, &path),
+        };
+        let bitrate = match get_integer_param(request, "bitrate") {
+            Ok(bitrate) => bitrate.unwrap_or(format.default_bitrate() as i64),
+            Err(response) => return response,
+        };
+        if bitrate < transcoding::MIN_BITRATE as i64 || bitrate > transcoding::MAX_BITRATE as i64 {
+            return Response::text(format!(
+                "Bitrate must be between {} and {}",
+                transcoding::MIN_BITRATE,
+                transcoding::MAX_BITRATE
+            ))
+            .with_status_code(400);
+        }
+        return transcoding::serve(request, &id, &path, format, bitrate as u32);
+    }
+
+    // Sends the browser to a URL that saves the song as a file
+    fn download_song(&self, id: String, request: &Request) -> Response {
+        let song = match self.library.read().unwrap().songs.get(&id) {
+            Some(song) => song.clone(),
+            None => {
+                return Response::text(format!("Song with id {} not found", id))
+                    .with_status_code(404)
+            }
+        };
+
+        if proxies_songs() {
+            let response = streaming::serve(request, &format!("Music{}", song.file_location));
+            return match signed_urls::content_disposition(&song, UrlKind::Download) {
+                Some(disposition) => {
+                    response.with_unique_header("Content-Disposition", disposition)
+                }
+                None => response,
+            };
+        }
+        return match signed_urls::sign_song(&song, UrlKind::Download, request.remote_addr().ip()) {
+            Ok(url) => {
+                metrics::record_signed_url();
+                Response::redirect_302(url).with_unique_header("Cache-Control", "no-store")
+            }
+            Err(error) => Response::text(format!("Unable to compute signature: {}", error))
+                .with_status_code(500),
+        };
+    }
+
+    // Sends the album's art, at about the size asked for if there is one
+    fn album_art(&self, id: String, request: &Request) -> Response {
+        let size = match get_integer_param(request, "size") {
+            Ok(Some(size)) if size <= 0 => {
+                return Response::text("Size must be positive").with_status_code(400)
+            }
+            Ok(size) => size.map(|size| size.min(u32::MAX as i64) as u32),
+            Err(response) => return response,
+        };
+        let name = match self.library.read().unwrap().album_art.get(&id) {
+            Some(name) if !name.is_empty() => name.clone(),
+            _ => {
+                return Response::text(format!("Album with id {} has no art", id))
+                    .with_status_code(404)
+            }
+        };
+        return art::serve(request, &name, size);
+    }
+
+    // Replaces the library with the one in storage if it has changed since
+    // it was last loaded or saved, returning whether it did. Requests
+    // already using the old library finish with it before it is swapped.
+    pub fn reload_library(&self, force: bool) -> std::io::Result<bool> {
+        let version = storage::version("library.json")?;
+        if !force && self.library_version.lock().unwrap().as_ref() == Some(&version) {
+            return Ok(false);
+        }
+        let new_library = Library::load()?;
+        let catalog = Catalog::new(&new_library);
+
+        // Users' data may need loading from storage, which shouldn't happen
+        // while the library is locked
+        let users: Vec<_> = self
+            .events
+            .users()
+            .iter()
+            .filter_map(|user| self.user_data.get(user).ok())
+            .collect();
+
+        let mut library = self.library.write().unwrap();
+        let previous_revision = library.revision;
+        *library = new_library;
+        drop(library);
+        *self.catalog.write().unwrap() = Arc::new(catalog);
+        *self.library_version.lock().unwrap() = Some(version);
+
+        // Let open clients know about anything that was synced
+        let library = self.library.read().unwrap();
+        for user_data in users {
+            let user_data = user_data.read().unwrap();
+            self.events
+                .publish_library_changes(&library, &user_data, previous_revision);
+        }
+        return Ok(true);
+    }
+
+    pub fn compact_play_logs(&self) -> std::io::Result<usize> {
+        return self.user_data.compact_play_logs();
+    }
+
+    pub fn library_size(&self) -> usize {
+        return self.library.read().unwrap().songs.len();
+    }
+
+    pub fn session_count(&self) -> usize {
+        return self.sessions.count();
+    }
+
+    pub fn route_api(&self, request: &Request, user: &AuthenticatedUser) -> Response {
         if request.url().eq("/api/songs") {
-            return self.songs();
+            return self.songs(request, user);
+        }
+
+        if request.url().eq("/api/changes") {
+            return self.changes(request, user);
+        }
+
+        if request.url().eq("/api/albums") {
+            return self.albums(request);
+        }
+
+        if request.url().eq("/api/artists") {
+            return self.artists(request);
+        }
+
+        if request.url().eq("/api/events") {
+            return self.events.subscribe(&user.name);
+        }
+
+        if request.url().eq("/api/now-playing") && request.method() == "POST" {
+            return self.now_playing(request, user);
+        }
+
+        if request.url().eq("/api/history") {
+            return self.history(request, user);
+        }
+
+        if request.url().eq("/api/stats") {
+            return self.stats(request, user);
+        }
+
+        if request.url().eq("/api/queue") {
+            if request.method() == "POST" {
+                return self.update_queue(request, user);
+            }
+            return self.queue(user);
+        }
+
+        if request.url().eq("/api/sessions") {
+            if request.method() == "POST" {
+                return self.update_session(request, user);
+            }
+            return Response::json(&self.sessions.list(&user.name));
+        }
+
+        if request.url().eq("/api/playlists") {
+            if request.method() == "POST" {
+                return self.save_playlist(None, request, user);
+            }
+            return self.playlists(user);
         }
 
         let url = request.url();
+        if let Some(cap) = self.songs_stream_regex.captures(url.as_str()) {
+            if request.method() != "GET" && request.method() != "HEAD" {
+                return Response::text("Method not allowed").with_status_code(405);
+            }
+            return self.stream_song(cap[1].to_string(), request);
+        }
+
+        if let Some(cap) = self.songs_download_regex.captures(url.as_str()) {
+            return self.download_song(cap[1].to_string(), request);
+        }
+
+        if let Some(cap) = self.album_art_regex.captures(url.as_str()) {
+            if request.method() != "GET" && request.method() != "HEAD" {
+                return Response::text("Method not allowed").with_status_code(405);
+            }
+            return self.album_art(cap[1].to_string(), request);
+        }
+
+        if let Some(cap) = self.album_regex.captures(url.as_str()) {
+            return self.album(cap[1].to_string(), request, user);
+        }
+
+        if let Some(cap) = self.artist_regex.captures(url.as_str()) {
+            return self.artist(cap[1].to_string(), request, user);
+        }
+
+        if let Some(cap) = self.songs_rating_regex.captures(url.as_str()) {
+            if request.method() != "POST" {
+                return Response::text("Method not allowed").with_status_code(405);
+            }
+            return self.set_song_rating(cap[1].to_string(), request, user);
+        }
+
+        if let Some(cap) = self.songs_plays_regex.captures(url.as_str()) {
+            if request.method() != "POST" {
+                return Response::text("Method not allowed").with_status_code(405);
+            }
+            return self.record_play(cap[1].to_string(), request, user);
+        }
+
+        if let Some(cap) = self.session_transfer_regex.captures(url.as_str()) {
+            if request.method() != "POST" {
+                return Response::text("Method not allowed").with_status_code(405);
+            }
+            return self.transfer_session(cap[1].to_string(), request, user);
+        }
+
+        if let Some(cap) = self.session_commands_regex.captures(url.as_str()) {
+            if request.method() != "POST" {
+                return Response::text("Method not allowed").with_status_code(405);
+            }
+            return self.send_session_command(cap[1].to_string(), request, user);
+        }
+
+        if let Some(cap) = self.playlist_regex.captures(url.as_str()) {
+            return match request.method() {
+                "POST" => self.save_playlist(Some(cap[1].to_string()), request, user),
+                "DELETE" => self.delete_playlist(cap[1].to_string(), user),
+                _ => Response::text("Method not allowed").with_status_code(405),
+            };
+        }
+
         return match self.songs_contents_regex.captures(url.as_str()) {
             Some(cap) => match cap[1].parse::<String>() {
-                Ok(id) => self.song_contents(id),
+                Ok(id) => self.song_contents(id, request),
                 Err(_) => Response::text("Song id is not an integer").with_status_code(400),
             },
             None => Response::empty_404(),
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::play_log::PlayLog;
+    use crate::user_data::test_user_data;
+
+    #[test]
+    fn user_etags_change_with_ratings_and_plays() {
+        let mut user_data = test_user_data(Vec::new());
+        let etag = user_etag("1", &user_data);
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(user_etag("1", &user_data), etag);
+        assert_ne!(user_etag("2", &user_data), etag);
+
+        user_data.ratings_version += 1;
+        let rated = user_etag("1", &user_data);
+        assert_ne!(rated, etag);
+
+        user_data.play_log = PlayLog::from_plays(vec![Play {
+            song_id: "a".to_string(),
+            started_at: 10,
+            fraction: 1.0,
+        }]);
+        assert_ne!(user_etag("1", &user_data), rated);
+    }
+}
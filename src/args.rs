@@ -1,129 +1,245 @@
-#[derive(Clone)]
-pub enum Mode {
-    Serve,
-    SyncRhythmdb,
-    ValidateLibrary,
-}
+extern crate clap;
+extern crate clap_complete;
 
-impl Mode {
-    fn parse(val: String) -> Option<Mode> {
-        if val.eq("serve") {
-            return Option::Some(Mode::Serve);
-        }
-        if val.eq("sync-rhythmdb") {
-            return Option::Some(Mode::SyncRhythmdb);
-        }
-        if val.eq("validate-library") {
-            return Option::Some(Mode::ValidateLibrary);
-        }
-        return Option::None;
-    }
-}
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+
+use crate::tokens::Scope;
 
-#[derive(Clone)]
+#[derive(Parser, Clone)]
+#[command(
+    name = "rhythmical",
+    version,
+    about = "A browser and cloud based music player"
+)]
 pub struct Args {
+    /// Config file to use instead of $XDG_CONFIG_HOME/rhythmical/config.toml
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<String>,
+
+    /// Print more detail, such as every change a dry run would make
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print results as JSON instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
     pub mode: Mode,
-    pub sync_rhythmdb: Option<SyncRhythmdbArgs>,
-    pub validate_library: Option<ValidateLibraryArgs>,
 }
 
-#[derive(Clone)]
+#[derive(Subcommand, Clone)]
+pub enum Mode {
+    /// Serve the player and its API
+    Serve(ServeArgs),
+    /// Upload new songs from a rhythmdb file and remove deleted ones
+    SyncRhythmdb(SyncRhythmdbArgs),
+    /// Check that the library and the stored files match, and fix them up
+    ValidateLibrary(ValidateLibraryArgs),
+    /// Measure how loud songs are, so that they can be played at the same volume
+    AnalyzeLoudness(AnalyzeLoudnessArgs),
+    /// Add a user, prompting for their password
+    AddUser(UserArgs),
+    /// Change a user's password
+    ResetPassword(UserArgs),
+    /// Create an API token and print it
+    CreateToken(CreateTokenArgs),
+    /// Revoke an API token
+    RevokeToken(RevokeTokenArgs),
+    /// List all API tokens
+    ListTokens,
+    /// Print a shell completion script
+    Completions(CompletionsArgs),
+}
+
+// Each of these overrides the config file when given
+#[derive(clap::Args, Clone)]
+pub struct ServeArgs {
+    /// Address to listen on [default: localhost]
+    #[arg(short, long)]
+    pub address: Option<String>,
+    /// Port to listen on [default: 8000]
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// PEM certificate, to serve HTTPS instead of HTTP
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<String>,
+    /// PEM private key for the certificate
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<String>,
+    /// Port on which to redirect plain HTTP requests to HTTPS
+    #[arg(long = "redirect-http", value_name = "PORT", requires = "tls_cert")]
+    pub redirect_http_port: Option<u16>,
+}
+
+#[derive(clap::Args, Clone)]
 pub struct SyncRhythmdbArgs {
-    pub rhythmdb_file: String,
-    pub library_location_prefix: String,
+    /// Rhythmbox database [default: from the config]
+    #[arg(requires = "library_location_prefix")]
+    pub rhythmdb_file: Option<String>,
+    /// Directory that the locations in the database are relative to
+    pub library_location_prefix: Option<String>,
+    /// Print what would change without changing anything
+    #[arg(short = 'n', long)]
     pub dry_run: bool,
-    pub verbose: bool,
 }
 
-#[derive(Clone)]
+#[derive(clap::Args, Clone)]
 pub struct ValidateLibraryArgs {
+    /// Also decode every song, to find files that are corrupt or don't
+    /// match the library
+    #[arg(long)]
+    pub deep: bool,
+    /// Print what would change without changing anything
+    #[arg(short = 'n', long)]
     pub dry_run: bool,
+    // Set from the global verbosity rather than parsed here
+    #[arg(skip)]
     pub verbose: bool,
 }
 
-const USAGE_MESSAGE: &str = "Incorrect arguments. Usage:
-  serve
-  sync-rhythmdb rhythmdb-file library-location-prefix [--dry-run] [--verbose]
-  validate-library [--dry-run] [--verbose]";
+#[derive(clap::Args, Clone)]
+pub struct AnalyzeLoudnessArgs {
+    /// Analyze songs that have been analyzed before too
+    #[arg(long)]
+    pub all: bool,
+    /// Read songs from this copy of the container's Music directory where
+    /// they are there, rather than downloading them or finding them through
+    /// the [sync] rhythmdb file
+    #[arg(long, value_name = "DIR")]
+    pub music_dir: Option<String>,
+    /// Print what would change without changing anything
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+#[derive(clap::Args, Clone)]
+pub struct UserArgs {
+    pub name: String,
+    /// Make the user an admin
+    #[arg(long)]
+    pub admin: bool,
+}
+
+#[derive(clap::Args, Clone)]
+pub struct CreateTokenArgs {
+    /// User that the token acts as
+    pub user: String,
+    /// Name to remember the token by
+    pub name: String,
+    /// Scopes to grant, which can be repeated
+    #[arg(short, long = "scope", value_enum, default_value = "read-only")]
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(clap::Args, Clone)]
+pub struct RevokeTokenArgs {
+    pub id: String,
+}
+
+#[derive(clap::Args, Clone)]
+pub struct CompletionsArgs {
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
 
 impl Args {
     pub fn get() -> Args {
-        let args: Vec<String> = std::env::args().collect();
-        if args.len() < 2 {
-            println!("{}", USAGE_MESSAGE);
-            std::process::exit(1);
+        let mut args = Args::parse();
+        if let Mode::ValidateLibrary(validate_library) = &mut args.mode {
+            validate_library.verbose = args.verbose > 0;
         }
+        return args;
+    }
+}
 
-        return match Mode::parse(args[1].clone()) {
-            Some(Mode::Serve) => {
-                if args.len() != 2 {
-                    println!("{}", USAGE_MESSAGE);
-                    std::process::exit(1);
-                }
-                Args {
-                    mode: Mode::Serve,
-                    sync_rhythmdb: Option::None,
-                    validate_library: Option::None,
-                }
-            }
-            Some(Mode::SyncRhythmdb) => {
-                if args.len() < 4 {
-                    println!("{}", USAGE_MESSAGE);
-                    std::process::exit(1);
-                }
-                let mut dry_run = false;
-                let mut verbose = false;
-                for i in 4..args.len() {
-                    if args[i] == "--dry-run" {
-                        dry_run = true;
-                    } else if args[i] == "--verbose" {
-                        verbose = true;
-                    } else {
-                        println!("{}", USAGE_MESSAGE);
-                        std::process::exit(1);
-                    }
-                }
-                Args {
-                    mode: Mode::SyncRhythmdb,
-                    sync_rhythmdb: Option::Some(SyncRhythmdbArgs {
-                        rhythmdb_file: args[2].clone(),
-                        library_location_prefix: args[3].clone(),
-                        dry_run,
-                        verbose,
-                    }),
-                    validate_library: Option::None,
-                }
-            }
-            Some(Mode::ValidateLibrary) => {
-                if args.len() < 2 {
-                    println!("{}", USAGE_MESSAGE);
-                    std::process::exit(1);
-                }
-                let mut dry_run = false;
-                let mut verbose = false;
-                for i in 2..args.len() {
-                    if args[i] == "--dry-run" {
-                        dry_run = true;
-                    } else if args[i] == "--verbose" {
-                        verbose = true;
-                    } else {
-                        println!("{}", USAGE_MESSAGE);
-                        std::process::exit(1);
-                    }
-                }
-                Args {
-                    mode: Mode::ValidateLibrary,
-                    sync_rhythmdb: Option::None,
-                    validate_library: Option::Some(ValidateLibraryArgs {
-                        dry_run,
-                        verbose,
-                    }),
-                }
+pub fn print_completions(args: CompletionsArgs) {
+    let mut command = Args::command();
+    clap_complete::generate(
+        args.shell,
+        &mut command,
+        "rhythmical",
+        &mut std::io::stdout(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> std::result::Result<Args, clap::Error> {
+        return Args::try_parse_from(args);
+    }
+
+    #[test]
+    fn command_is_valid() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn global_options_go_anywhere() {
+        let args = parse(&["rhythmical", "serve", "--config", "a.toml", "-vv"]).unwrap();
+        assert_eq!(args.config, Some("a.toml".to_string()));
+        assert_eq!(args.verbose, 2);
+        assert!(parse(&["rhythmical", "--quiet", "--verbose", "serve"]).is_err());
+    }
+
+    #[test]
+    fn tls_options_go_together() {
+        assert!(parse(&["rhythmical", "serve", "--tls-cert", "cert.pem"]).is_err());
+        assert!(parse(&["rhythmical", "serve", "--redirect-http", "80"]).is_err());
+        let args = parse(&[
+            "rhythmical",
+            "serve",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+        ]);
+        assert!(args.is_ok());
+    }
+
+    #[test]
+    fn sync_paths_go_together() {
+        assert!(parse(&["rhythmical", "sync-rhythmdb", "rhythmdb.xml"]).is_err());
+        let args = parse(&["rhythmical", "sync-rhythmdb", "-n"]).unwrap();
+        match args.mode {
+            Mode::SyncRhythmdb(sync) => {
+                assert!(sync.dry_run);
+                assert_eq!(sync.rhythmdb_file, None);
             }
-            None => {
-                println!("{}", USAGE_MESSAGE);
-                std::process::exit(1);
+            _ => panic!("Expected sync-rhythmdb"),
+        }
+    }
+
+    #[test]
+    fn token_scopes_default_to_read_only() {
+        let args = parse(&["rhythmical", "create-token", "bob", "script"]).unwrap();
+        match args.mode {
+            Mode::CreateToken(create) => assert_eq!(create.scopes, vec![Scope::ReadOnly]),
+            _ => panic!("Expected create-token"),
+        }
+        let args = parse(&[
+            "rhythmical",
+            "create-token",
+            "bob",
+            "script",
+            "--scope",
+            "rate",
+            "-s",
+            "admin",
+        ])
+        .unwrap();
+        match args.mode {
+            Mode::CreateToken(create) => {
+                assert_eq!(create.scopes, vec![Scope::Rate, Scope::Admin])
             }
-        };
+            _ => panic!("Expected create-token"),
+        }
     }
 }
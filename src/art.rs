@@ -0,0 +1,236 @@
+use rouille::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Error, Result, Write};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+use crate::config::config;
+use crate::storage;
+use crate::streaming;
+
+// Sizes that thumbnails are made at, in pixels for the longest side. Any
+// size asked for is served from the next one up.
+pub const THUMBNAIL_SIZES: [u32; 3] = [128, 256, 512];
+
+// Images next to songs that are used when none of them have art embedded,
+// in order of preference
+const COVER_FILES: [&str; 4] = ["cover.jpg", "folder.jpg", "cover.png", "folder.png"];
+
+// Tells images apart by their first bytes, as the names embedded art comes
+// with can't be trusted
+fn extension(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0x89, b'P', b'N', b'G']) {
+        return Some("png");
+    }
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        return Some("jpg");
+    }
+    return None;
+}
+
+fn run_ffmpeg(args: &[&str], input: Option<Vec<u8>>) -> Result<Output> {
+    let mut child = Command::new(&config().transcoding.ffmpeg)
+        .args(["-hide_banner", "-v", "error"])
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Result::Err(Error::other(format!(
+            "ffmpeg failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    return Ok(output);
+}
+
+// The cover picture embedded in a song's tags, if it has one
+fn embedded_art(path: &str) -> Option<Vec<u8>> {
+    let output = run_ffmpeg(
+        &[
+            "-i",
+            path,
+            "-an",
+            "-map",
+            "0:v:0",
+            "-frames:v",
+            "1",
+            "-c",
+            "copy",
+            "-f",
+            "image2pipe",
+            "pipe:1",
+        ],
+        None,
+    )
+    .ok()?;
+    return extension(&output.stdout).map(|_| output.stdout);
+}
+
+fn cover_file(path: &str) -> Option<Vec<u8>> {
+    let dir = Path::new(path).parent()?;
+    let names: Vec<String> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    for cover in COVER_FILES {
+        if let Some(name) = names.iter().find(|name| name.eq_ignore_ascii_case(cover)) {
+            if let Ok(data) = fs::read(dir.join(name)) {
+                if extension(&data).is_some() {
+                    return Some(data);
+                }
+            }
+        }
+    }
+    return None;
+}
+
+// Scales the image down to fit in a square of the given size, as a JPEG
+fn thumbnail(data: Vec<u8>, size: u32) -> Result<Vec<u8>> {
+    let scale = format!(
+        "scale=w='min({0},iw)':h='min({0},ih)':force_original_aspect_ratio=decrease",
+        size
+    );
+    let output = run_ffmpeg(
+        &[
+            "-i",
+            "pipe:0",
+            "-vf",
+            &scale,
+            "-frames:v",
+            "1",
+            "-c:v",
+            "mjpeg",
+            "-q:v",
+            "3",
+            "-f",
+            "image2pipe",
+            "pipe:1",
+        ],
+        Some(data),
+    )?;
+    return Ok(output.stdout);
+}
+
+fn thumbnail_path(name: &str, size: u32) -> String {
+    let stem = name.split('.').next().unwrap_or(name);
+    return format!("Art/{}-{}.jpg", stem, size);
+}
+
+// Finds art for an album from its songs' local files, preferring art
+// embedded in any of them. It is stored under Art/ named after a hash of
+// its contents, so albums sharing art only store it once, along with its
+// thumbnails. Returns the art's name, if there is any.
+pub fn import(local_paths: &[String], dry_run: bool) -> Result<Option<String>> {
+    let data = match local_paths
+        .iter()
+        .find_map(|path| embedded_art(path))
+        .or_else(|| local_paths.iter().find_map(|path| cover_file(path)))
+    {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let hash: String = Sha256::digest(&data)
+        .iter()
+        .take(16)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let name = format!("{}.{}", hash, extension(&data).unwrap());
+
+    let path = format!("Art/{}", name);
+    if dry_run {
+        debug!("Would upload {}", path);
+        return Ok(Some(name));
+    }
+    if storage::exists(&path)? {
+        return Ok(Some(name));
+    }
+
+    // Thumbnails go first, so that art is only there once they all are
+    for size in THUMBNAIL_SIZES {
+        storage::put(&thumbnail_path(&name, size), thumbnail(data.clone(), size)?)?;
+    }
+    info!("Uploading {}", path);
+    storage::put(&path, data)?;
+    return Ok(Some(name));
+}
+
+// Sends the smallest thumbnail at least as big as the size asked for, or
+// the art as it was found if the size is bigger than all of them
+fn sized_path(name: &str, size: Option<u32>) -> String {
+    return match size.and_then(|size| THUMBNAIL_SIZES.iter().find(|&&t| t >= size)) {
+        Some(&size) => thumbnail_path(name, size),
+        None => format!("Art/{}", name),
+    };
+}
+
+pub fn serve(request: &Request, name: &str, size: Option<u32>) -> Response {
+    return streaming::serve(request, &sized_path(name, size));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::random_id;
+
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0d, 0x0a];
+    const JPEG: &[u8] = &[0xff, 0xd8, 0xff, 0xe0];
+
+    #[test]
+    fn images_are_recognised_by_their_contents() {
+        assert_eq!(extension(PNG), Some("png"));
+        assert_eq!(extension(JPEG), Some("jpg"));
+        assert_eq!(extension(b"GIF89a"), None);
+        assert_eq!(extension(&[]), None);
+    }
+
+    #[test]
+    fn thumbnails_are_named_after_the_art() {
+        assert_eq!(thumbnail_path("abc.png", 128), "Art/abc-128.jpg");
+        assert_eq!(thumbnail_path("abc.jpg", 512), "Art/abc-512.jpg");
+    }
+
+    #[test]
+    fn sizes_are_served_from_the_next_thumbnail_up() {
+        assert_eq!(sized_path("abc.png", Some(1)), "Art/abc-128.jpg");
+        assert_eq!(sized_path("abc.png", Some(128)), "Art/abc-128.jpg");
+        assert_eq!(sized_path("abc.png", Some(129)), "Art/abc-256.jpg");
+        assert_eq!(sized_path("abc.png", Some(512)), "Art/abc-512.jpg");
+        assert_eq!(sized_path("abc.png", Some(513)), "Art/abc.png");
+        assert_eq!(sized_path("abc.png", None), "Art/abc.png");
+    }
+
+    #[test]
+    fn cover_files_are_found_next_to_songs() {
+        let dir = std::env::temp_dir().join(format!("rhythmical-art-{}", random_id()));
+        fs::create_dir_all(&dir).unwrap();
+        let song = dir.join("song.mp3").to_string_lossy().to_string();
+        assert_eq!(cover_file(&song), None);
+
+        // Files that aren't images are skipped
+        fs::write(dir.join("Cover.jpg"), b"not an image").unwrap();
+        assert_eq!(cover_file(&song), None);
+
+        fs::write(dir.join("folder.png"), PNG).unwrap();
+        assert_eq!(cover_file(&song).as_deref(), Some(PNG));
+
+        fs::write(dir.join("Cover.jpg"), JPEG).unwrap();
+        assert_eq!(cover_file(&song).as_deref(), Some(JPEG));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
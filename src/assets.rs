@@ -0,0 +1,161 @@
+extern crate lazy_static;
+
+use rouille::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::mime_types;
+
+// Names and contents of the frontend's files, embedded by build.rs
+static FILES: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+// Long enough that browsers never check again, which is safe because the
+// URL changes whenever the contents do
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+struct Asset {
+    data: Vec<u8>,
+    content_type: &'static str,
+    etag: String,
+}
+
+impl Asset {
+    fn new(name: &str, data: Vec<u8>) -> Asset {
+        let etag = format!("\"{}\"", hash(&data));
+        return Asset {
+            data,
+            content_type: mime_types::from_path(name),
+            etag,
+        };
+    }
+}
+
+struct Assets {
+    by_name: HashMap<String, Asset>,
+    // Map from hashed names to names, such as
+    // app.0123456789abcdef0123456789abcdef.js to app.js
+    hashed_names: HashMap<String, String>,
+}
+
+lazy_static! {
+    static ref ASSETS: Assets = Assets::new();
+}
+
+// Stays the same between builds, unlike the standard library's hasher
+fn hash(data: &[u8]) -> String {
+    return Sha256::digest(data)
+        .iter()
+        .take(16)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+}
+
+// Puts the hash before the extension, so that the MIME type still matches
+fn hashed_name(name: &str, hash: &str) -> String {
+    return match name.rfind('.') {
+        Some(dot) => format!("{}.{}{}", &name[..dot], hash, &name[dot..]),
+        None => format!("{}.{}", name, hash),
+    };
+}
+
+// Points the page at the hashed URLs of the files it uses
+fn rewrite_index(index_html: &str, hashed_names: &HashMap<String, String>) -> String {
+    let mut index_html = index_html.to_string();
+    for (hashed, name) in hashed_names {
+        index_html = index_html.replace(
+            &format!("\"/{}\"", name),
+            &format!("\"/static/{}\"", hashed),
+        );
+    }
+    return index_html;
+}
+
+impl Assets {
+    fn new() -> Assets {
+        let mut assets = Assets {
+            by_name: HashMap::new(),
+            hashed_names: HashMap::new(),
+        };
+        let mut index_html = String::new();
+        for (name, data) in FILES {
+            if *name == "index.html" {
+                index_html = String::from_utf8_lossy(data).to_string();
+                continue;
+            }
+            let hashed = hashed_name(name, &hash(data));
+            assets.hashed_names.insert(hashed, name.to_string());
+            assets
+                .by_name
+                .insert(name.to_string(), Asset::new(name, data.to_vec()));
+        }
+
+        // The page itself is always checked for changes, and points at the
+        // hashed URLs so that everything else can be cached for good
+        let index_html = rewrite_index(&index_html, &assets.hashed_names);
+        assets.by_name.insert(
+            "index.html".to_string(),
+            Asset::new("index.html", index_html.into_bytes()),
+        );
+        return assets;
+    }
+}
+
+// Serves the embedded file for the request, if there is one. Files are
+// found at /static/ under both their hashed and plain names, and the
+// plain names are always checked for changes.
+pub fn serve(request: &Request) -> Option<Response> {
+    let url = request.url();
+    let path = match url.as_str() {
+        "/" => "index.html",
+        url => url.strip_prefix("/static/").unwrap_or(&url[1..]),
+    };
+
+    if let Some(name) = ASSETS.hashed_names.get(path) {
+        let asset = &ASSETS.by_name[name];
+        return Some(
+            Response::from_data(asset.content_type, asset.data.clone())
+                .with_unique_header("Cache-Control", IMMUTABLE),
+        );
+    }
+
+    let asset = ASSETS.by_name.get(path)?;
+    return Some(
+        Response::from_data(asset.content_type, asset.data.clone())
+            .with_unique_header("Cache-Control", "no-cache")
+            .with_etag(request, asset.etag.clone()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_goes_before_the_extension() {
+        assert_eq!(hashed_name("app.js", "0abc"), "app.0abc.js");
+        assert_eq!(hashed_name("vendor.min.css", "01"), "vendor.min.01.css");
+        assert_eq!(hashed_name("LICENSE", "02"), "LICENSE.02");
+    }
+
+    #[test]
+    fn hash_changes_with_contents() {
+        assert_eq!(hash(b"one"), "7692c3ad3540bb803c020b3aee66cd88");
+        assert_eq!(hash(b"one"), hash(b"one"));
+        assert_ne!(hash(b"one"), hash(b"two"));
+    }
+
+    #[test]
+    fn index_points_at_hashed_names() {
+        let mut hashed_names = HashMap::new();
+        hashed_names.insert(
+            "app.0123456789abcdef0123456789abcdef.js".to_string(),
+            "app.js".to_string(),
+        );
+        let index_html = "<script src=\"/app.js\"></script><a href=\"/other.js\">app.js</a>";
+        assert_eq!(
+            rewrite_index(index_html, &hashed_names),
+            "<script src=\"/static/app.0123456789abcdef0123456789abcdef.js\"></script>\
+             <a href=\"/other.js\">app.js</a>"
+        );
+    }
+}
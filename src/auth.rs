@@ -0,0 +1,627 @@
+extern crate rouille;
+
+use rouille::input::{cookies, json_input};
+use rouille::{Request, Response};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use time::OffsetDateTime;
+
+use crate::library::random_string;
+use crate::tokens::{Scope, Tokens};
+use crate::users::Users;
+
+const SESSION_COOKIE: &str = "rhythmical_session";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+const LOGIN_SESSION_LIFETIME_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Clone)]
+pub struct AuthenticatedUser {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // Set when the request was made with an API token rather than from a
+    // logged in browser
+    pub token_id: Option<String>,
+}
+
+impl AuthenticatedUser {
+    pub fn has_scope(&self, required: Scope) -> bool {
+        return self.scopes.iter().any(|scope| scope.allows(required));
+    }
+}
+
+struct LoginSession {
+    user: String,
+    // Must be sent back in a header with every request that changes
+    // anything, which a cross-site form or script is unable to do
+    csrf_token: String,
+    expires_at: i64,
+}
+
+#[derive(Deserialize)]
+struct ApiLogin {
+    name: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct ApiNewToken {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+struct ApiCurrentUser {
+    name: String,
+    admin: bool,
+    csrf_token: String,
+}
+
+pub struct Auth {
+    users: RwLock<Users>,
+    tokens: RwLock<Tokens>,
+    // Versions of the users and tokens that were last loaded or saved, to
+    // tell when they change
+    users_version: Mutex<Option<String>>,
+    tokens_version: Mutex<Option<String>>,
+    // Map from session cookie values to the session
+    login_sessions: Mutex<HashMap<String, LoginSession>>,
+    // Whether cookies should only ever be sent over HTTPS
+    secure_cookies: bool,
+}
+
+impl Auth {
+    // Nobody can log in until the users and tokens have been loaded
+    pub fn new(secure_cookies: bool) -> Auth {
+        return Auth {
+            users: RwLock::new(Users {
+                users: HashMap::new(),
+            }),
+            tokens: RwLock::new(Tokens {
+                tokens: HashMap::new(),
+            }),
+            users_version: Mutex::new(None),
+            tokens_version: Mutex::new(None),
+            login_sessions: Mutex::new(HashMap::new()),
+            secure_cookies,
+        };
+    }
+
+    pub fn load(&self) -> std::io::Result<()> {
+        self.reload_users(true)?;
+        self.reload_tokens(true)?;
+        return Ok(());
+    }
+
+    // Replaces the users with the ones in storage if they have changed
+    // since they were last loaded, such as by add-user or reset-password,
+    // returning whether they did
+    pub fn reload_users(&self, force: bool) -> std::io::Result<bool> {
+        let version = Users::version()?;
+        if !force && *self.users_version.lock().unwrap() == version {
+            return Ok(false);
+        }
+        let users = Users::load()?;
+        *self.users.write().unwrap() = users;
+        *self.users_version.lock().unwrap() = version;
+        return Ok(true);
+    }
+
+    // Does the same for tokens, such as ones created or revoked from the
+    // command line
+    pub fn reload_tokens(&self, force: bool) -> std::io::Result<bool> {
+        let version = Tokens::version()?;
+        if !force && *self.tokens_version.lock().unwrap() == version {
+            return Ok(false);
+        }
+        let tokens = Tokens::load()?;
+        *self.tokens.write().unwrap() = tokens;
+        *self.tokens_version.lock().unwrap() = version;
+        return Ok(true);
+    }
+
+    // Saves the tokens, remembering their new version so that the next
+    // check doesn't load them again
+    fn save_tokens(&self, tokens: &Tokens) -> std::io::Result<()> {
+        tokens.save()?;
+        *self.tokens_version.lock().unwrap() = Tokens::version()?;
+        return Ok(());
+    }
+
+    // Handles the requests for logging in and out, which don't need the
+    // user to already be authenticated.
+    pub fn route_auth(&self, request: &Request) -> Option<Response> {
+        if request.url().eq("/api/login") && request.method() == "POST" {
+            return Some(self.login(request));
+        }
+        if request.url().eq("/api/logout") && request.method() == "POST" {
+            return Some(self.logout(request));
+        }
+        if request.url().eq("/api/me") {
+            return Some(self.current_user(request));
+        }
+        return None;
+    }
+
+    fn login(&self, request: &Request) -> Response {
+        let login: ApiLogin = match json_input(request) {
+            Ok(login) => login,
+            Err(error) => {
+                return Response::text(format!("Invalid login: {}", error)).with_status_code(400)
+            }
+        };
+
+        let users = self.users.read().unwrap();
+        let user = match users.verify(&login.name, &login.password) {
+            Some(user) => user,
+            None => return Response::text("Incorrect user name or password").with_status_code(401),
+        };
+
+        let token = random_string(32);
+        let csrf_token = random_string(32);
+        self.login_sessions.lock().unwrap().insert(
+            token.clone(),
+            LoginSession {
+                user: user.name.clone(),
+                csrf_token: csrf_token.clone(),
+                expires_at: OffsetDateTime::now_utc().unix_timestamp()
+                    + LOGIN_SESSION_LIFETIME_SECONDS,
+            },
+        );
+
+        let cookie = self.cookie(&token, LOGIN_SESSION_LIFETIME_SECONDS);
+        return Response::json(&ApiCurrentUser {
+            name: user.name.clone(),
+            admin: user.admin,
+            csrf_token,
+        })
+        .with_additional_header("Set-Cookie", cookie);
+    }
+
+    fn logout(&self, request: &Request) -> Response {
+        if let Some(token) = session_token(request) {
+            self.login_sessions.lock().unwrap().remove(&token);
+        }
+        let cookie = self.cookie("", 0);
+        return Response::empty_204().with_additional_header("Set-Cookie", cookie);
+    }
+
+    fn cookie(&self, value: &str, max_age: i64) -> String {
+        let secure = if self.secure_cookies { "; Secure" } else { "" };
+        return format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}{}",
+            SESSION_COOKIE, value, max_age, secure
+        );
+    }
+
+    fn current_user(&self, request: &Request) -> Response {
+        let (user, csrf_token) = match self.find_session(request) {
+            Some(session) => session,
+            None => return Response::text("Not logged in").with_status_code(401),
+        };
+        let users = self.users.read().unwrap();
+        return match users.users.get(&user) {
+            Some(user) => Response::json(&ApiCurrentUser {
+                name: user.name.clone(),
+                admin: user.admin,
+                csrf_token,
+            }),
+            None => Response::text("Not logged in").with_status_code(401),
+        };
+    }
+
+    pub fn login_session_count(&self) -> usize {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut login_sessions = self.login_sessions.lock().unwrap();
+        login_sessions.retain(|_, session| session.expires_at > now);
+        return login_sessions.len();
+    }
+
+    // Returns the user and CSRF token of the request's session, if it has
+    // a session that hasn't expired
+    fn find_session(&self, request: &Request) -> Option<(String, String)> {
+        let token = session_token(request)?;
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut login_sessions = self.login_sessions.lock().unwrap();
+        login_sessions.retain(|_, session| session.expires_at > now);
+        return login_sessions
+            .get(&token)
+            .map(|session| (session.user.clone(), session.csrf_token.clone()));
+    }
+
+    // Checks that the request comes from a logged in user, and that any
+    // request which can change state carries the session's CSRF token.
+    // Requests with an API token are checked against the token instead.
+    pub fn authenticate(&self, request: &Request) -> Result<AuthenticatedUser, Response> {
+        if let Some(header) = request.header("Authorization") {
+            return match header.strip_prefix("Bearer ") {
+                Some(token) => self.authenticate_token(token.trim()),
+                None => Err(Response::text("Unsupported authorization").with_status_code(401)),
+            };
+        }
+
+        let (user, csrf_token) = match self.find_session(request) {
+            Some(session) => session,
+            None => return Err(Response::text("Not logged in").with_status_code(401)),
+        };
+
+        if request.method() != "GET" && request.method() != "HEAD" {
+            let valid = match request.header(CSRF_HEADER) {
+                Some(header) => constant_time_eq(header.as_bytes(), csrf_token.as_bytes()),
+                None => false,
+            };
+            if !valid {
+                return Err(Response::text("Missing or invalid CSRF token").with_status_code(403));
+            }
+        }
+
+        let users = self.users.read().unwrap();
+        return match users.users.get(&user) {
+            Some(user) => Ok(AuthenticatedUser {
+                name: user.name.clone(),
+                scopes: vec![if user.admin {
+                    Scope::Admin
+                } else {
+                    Scope::Rate
+                }],
+                token_id: None,
+            }),
+            None => Err(Response::text("Not logged in").with_status_code(401)),
+        };
+    }
+
+    fn authenticate_token(&self, token: &str) -> Result<AuthenticatedUser, Response> {
+        let tokens = self.tokens.read().unwrap();
+        let token = match tokens.verify(token) {
+            Some(token) => token,
+            None => return Err(Response::text("Invalid token").with_status_code(401)),
+        };
+        if !self.users.read().unwrap().users.contains_key(&token.user) {
+            return Err(Response::text("Invalid token").with_status_code(401));
+        }
+        return Ok(AuthenticatedUser {
+            name: token.user.clone(),
+            scopes: token.scopes.clone(),
+            token_id: Some(token.id.clone()),
+        });
+    }
+
+    // Checks that the user's scopes allow the request
+    pub fn authorize(&self, request: &Request, user: &AuthenticatedUser) -> Result<(), Response> {
+        // Browsers can always manage their own user's tokens
+        if user.token_id.is_none() && request.url().starts_with("/api/tokens") {
+            return Ok(());
+        }
+
+        let required = required_scope(request);
+        if !user.has_scope(required) {
+            return Err(
+                Response::text(format!("The {} scope is required", required.name()))
+                    .with_status_code(403),
+            );
+        }
+        return Ok(());
+    }
+
+    // Handles listing, creating and revoking API tokens. Admins can see
+    // and revoke everyone's tokens, but other users only their own.
+    pub fn route_tokens(&self, request: &Request, user: &AuthenticatedUser) -> Option<Response> {
+        if request.url().eq("/api/tokens") {
+            if request.method() == "POST" {
+                return Some(self.create_token(request, user));
+            }
+            let tokens = self.tokens.read().unwrap();
+            return Some(if user.has_scope(Scope::Admin) {
+                Response::json(&tokens.list(None))
+            } else {
+                Response::json(&tokens.list(Some(&user.name)))
+            });
+        }
+
+        if let Some(id) = request.url().strip_prefix("/api/tokens/") {
+            if request.method() != "DELETE" {
+                return Some(Response::text("Method not allowed").with_status_code(405));
+            }
+            return Some(self.revoke_token(id, user));
+        }
+
+        return None;
+    }
+
+    fn create_token(&self, request: &Request, user: &AuthenticatedUser) -> Response {
+        let new_token: ApiNewToken = match json_input(request) {
+            Ok(new_token) => new_token,
+            Err(error) => {
+                return Response::text(format!("Invalid token: {}", error)).with_status_code(400)
+            }
+        };
+        if new_token.scopes.is_empty() {
+            return Response::text("A token needs at least one scope").with_status_code(400);
+        }
+        // Tokens can't be given more access than their creator has
+        for scope in &new_token.scopes {
+            if !user.has_scope(*scope) {
+                return Response::text(format!("Unable to grant the {} scope", scope.name()))
+                    .with_status_code(403);
+            }
+        }
+
+        // Saving writes out every token, so any created or revoked from the
+        // command line are picked up first rather than being undone
+        if let Err(error) = self.reload_tokens(false) {
+            return Response::text(format!("Unable to load tokens: {}", error))
+                .with_status_code(500);
+        }
+        let mut tokens = self.tokens.write().unwrap();
+        let created = tokens.create(&user.name, &new_token.name, new_token.scopes);
+        if let Err(error) = self.save_tokens(&tokens) {
+            tokens.tokens.remove(&created.token.id);
+            return Response::text(format!("Unable to save token: {}", error))
+                .with_status_code(500);
+        }
+        return Response::json(&created);
+    }
+
+    fn revoke_token(&self, id: &str, user: &AuthenticatedUser) -> Response {
+        if let Err(error) = self.reload_tokens(false) {
+            return Response::text(format!("Unable to load tokens: {}", error))
+                .with_status_code(500);
+        }
+        let mut tokens = self.tokens.write().unwrap();
+        let token = match tokens.tokens.get(id) {
+            Some(token) => token.clone(),
+            None => return Response::text("Token not found").with_status_code(404),
+        };
+        if token.user != user.name && !user.has_scope(Scope::Admin) {
+            return Response::text("Token not found").with_status_code(404);
+        }
+
+        tokens.tokens.remove(id);
+        if let Err(error) = self.save_tokens(&tokens) {
+            tokens.tokens.insert(token.id.clone(), token);
+            return Response::text(format!("Unable to save tokens: {}", error))
+                .with_status_code(500);
+        }
+        return Response::empty_204();
+    }
+}
+
+// Administrative endpoints need the admin scope, anything else that can
+// change state needs the rate scope, and everything else is read only.
+fn required_scope(request: &Request) -> Scope {
+    if request.url().starts_with("/api/tokens") || request.url().starts_with("/api/admin") {
+        return Scope::Admin;
+    }
+    if request.method() == "GET" || request.method() == "HEAD" {
+        return Scope::ReadOnly;
+    }
+    return Scope::Rate;
+}
+
+fn session_token(request: &Request) -> Option<String> {
+    return cookies(request)
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string());
+}
+
+// Compares secrets without leaking how much of them matched through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut difference: u8 = 0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        difference |= x ^ y;
+    }
+    return difference == 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::User;
+
+    // An Auth with one user, alice, who is logged in with the session
+    // cookie "token" and CSRF token "csrf"
+    fn auth(expires_at: i64) -> Auth {
+        let mut users = Users {
+            users: HashMap::new(),
+        };
+        users.users.insert(
+            "alice".to_string(),
+            User {
+                name: "alice".to_string(),
+                password_hash: String::new(),
+                admin: false,
+            },
+        );
+        let auth = Auth {
+            users: RwLock::new(users),
+            tokens: RwLock::new(Tokens {
+                tokens: HashMap::new(),
+            }),
+            users_version: Mutex::new(None),
+            tokens_version: Mutex::new(None),
+            login_sessions: Mutex::new(HashMap::new()),
+            secure_cookies: false,
+        };
+        auth.login_sessions.lock().unwrap().insert(
+            "token".to_string(),
+            LoginSession {
+                user: "alice".to_string(),
+                csrf_token: "csrf".to_string(),
+                expires_at,
+            },
+        );
+        return auth;
+    }
+
+    fn request(method: &str, cookie: &str, csrf_token: Option<&str>) -> Request {
+        let mut headers = vec![(
+            "Cookie".to_string(),
+            format!("{}={}", SESSION_COOKIE, cookie),
+        )];
+        if let Some(csrf_token) = csrf_token {
+            headers.push((CSRF_HEADER.to_string(), csrf_token.to_string()));
+        }
+        return Request::fake_http(method, "/api/songs", headers, Vec::new());
+    }
+
+    fn with_token(method: &str, url: &str, token: &str) -> Request {
+        let headers = vec![("Authorization".to_string(), format!("Bearer {}", token))];
+        return Request::fake_http(method, url, headers, Vec::new());
+    }
+
+    fn status(result: Result<AuthenticatedUser, Response>) -> u16 {
+        return match result {
+            Ok(_) => 200,
+            Err(response) => response.status_code,
+        };
+    }
+
+    #[test]
+    fn reads_need_a_session() {
+        let auth = auth(i64::MAX);
+        let user = auth
+            .authenticate(&request("GET", "token", None))
+            .ok()
+            .unwrap();
+        assert_eq!(user.name, "alice");
+        assert_eq!(
+            status(auth.authenticate(&request("GET", "other", None))),
+            401
+        );
+    }
+
+    #[test]
+    fn changes_need_the_csrf_token() {
+        let auth = auth(i64::MAX);
+        assert_eq!(
+            status(auth.authenticate(&request("POST", "token", None))),
+            403
+        );
+        let wrong = request("POST", "token", Some("wrong"));
+        assert_eq!(status(auth.authenticate(&wrong)), 403);
+        let right = request("POST", "token", Some("csrf"));
+        assert_eq!(status(auth.authenticate(&right)), 200);
+    }
+
+    #[test]
+    fn expired_sessions_are_rejected() {
+        let auth = auth(0);
+        assert_eq!(
+            status(auth.authenticate(&request("GET", "token", None))),
+            401
+        );
+        assert!(auth.login_sessions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn compares_secrets() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn tokens_authenticate_as_their_user() {
+        let auth = auth(i64::MAX);
+        let created = auth
+            .tokens
+            .write()
+            .unwrap()
+            .create("alice", "script", vec![Scope::ReadOnly]);
+        let secret = created.secret;
+
+        // Tokens don't need a CSRF token, as browsers never send them
+        let request = with_token("POST", "/api/songs/a/rating", &secret);
+        let user = auth.authenticate(&request).ok().unwrap();
+        assert_eq!(user.name, "alice");
+        assert_eq!(user.token_id, Some(created.token.id));
+        assert_eq!(
+            auth.authorize(&request, &user).unwrap_err().status_code,
+            403
+        );
+
+        let request = with_token("GET", "/api/songs", &secret);
+        assert!(auth.authorize(&request, &user).is_ok());
+
+        let basic = vec![("Authorization".to_string(), "Basic abc".to_string())];
+        let request = Request::fake_http("GET", "/api/songs", basic, Vec::new());
+        assert_eq!(status(auth.authenticate(&request)), 401);
+    }
+
+    #[test]
+    fn browsers_can_manage_their_own_tokens() {
+        let auth = auth(i64::MAX);
+        let request = request("GET", "token", None);
+        let user = auth.authenticate(&request).ok().unwrap();
+        assert_eq!(user.scopes, vec![Scope::Rate]);
+        let tokens = Request::fake_http("GET", "/api/tokens", Vec::new(), Vec::new());
+        assert!(auth.authorize(&tokens, &user).is_ok());
+        let admin = Request::fake_http("GET", "/api/admin/jobs", Vec::new(), Vec::new());
+        assert!(auth.authorize(&admin, &user).is_err());
+    }
+
+    #[test]
+    fn scopes_required_by_requests() {
+        let scope = |method: &str, url: &str| -> Scope {
+            return required_scope(&Request::fake_http(method, url, Vec::new(), Vec::new()));
+        };
+        assert_eq!(scope("GET", "/api/songs"), Scope::ReadOnly);
+        assert_eq!(scope("HEAD", "/api/songs"), Scope::ReadOnly);
+        assert_eq!(scope("POST", "/api/plays"), Scope::Rate);
+        assert_eq!(scope("PUT", "/api/queue"), Scope::Rate);
+        assert_eq!(scope("GET", "/api/tokens"), Scope::Admin);
+        assert_eq!(scope("POST", "/api/admin/reload"), Scope::Admin);
+    }
+
+    #[test]
+    fn cookies_are_secure_over_https() {
+        let mut auth = auth(i64::MAX);
+        assert!(!auth.cookie("token", 60).contains("Secure"));
+        auth.secure_cookies = true;
+        assert_eq!(
+            auth.cookie("token", 60),
+            "rhythmical_session=token; Path=/; HttpOnly; SameSite=Strict; Max-Age=60; Secure"
+        );
+    }
+
+    #[test]
+    fn users_are_reloaded_when_they_change() {
+        crate::config::init_for_tests();
+        let auth = Auth::new(false);
+        let mut users = Users {
+            users: HashMap::new(),
+        };
+        for name in ["alice", "bob"] {
+            users.users.insert(
+                name.to_string(),
+                User {
+                    name: name.to_string(),
+                    password_hash: String::new(),
+                    admin: false,
+                },
+            );
+            users.save().unwrap();
+            assert!(auth.reload_users(false).unwrap());
+            assert!(!auth.reload_users(false).unwrap());
+            assert!(auth.users.read().unwrap().users.contains_key(name));
+        }
+        assert!(auth.reload_users(true).unwrap());
+    }
+
+    #[test]
+    fn tokens_created_elsewhere_are_picked_up() {
+        crate::config::init_for_tests();
+        let auth = auth(i64::MAX);
+        let mut tokens = Tokens::load().unwrap();
+        let created = tokens.create("alice", "script", vec![Scope::ReadOnly]);
+        tokens.save().unwrap();
+
+        assert!(auth.reload_tokens(false).unwrap());
+        assert!(!auth.reload_tokens(false).unwrap());
+        let tokens = auth.tokens.read().unwrap();
+        assert!(tokens.verify(&created.secret).is_some());
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeSet;
+use std::io::Result;
+use time::OffsetDateTime;
+
+use crate::storage;
+
+const BACKUPS_PATH: &str = "backups/";
+
+// Everything that can't be recreated from the music files
+fn paths_to_back_up() -> Result<Vec<String>> {
+    let mut paths: Vec<String> = Vec::new();
+    for path in ["library.json", "users.json", "tokens.json"].iter() {
+        if storage::exists(path)? {
+            paths.push(path.to_string());
+        }
+    }
+    paths.extend(storage::ls("users/")?);
+    return Result::Ok(paths);
+}
+
+// Copies the library and all user data to backups/<timestamp>/, and then
+// deletes all but the most recent backups. Returns where the backup went.
+pub fn backup(keep: usize) -> Result<String> {
+    let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+    let backup_path = format!("{}{}/", BACKUPS_PATH, timestamp);
+    for path in paths_to_back_up()? {
+        storage::cp(&path, &format!("{}{}", backup_path, path))?;
+    }
+
+    let existing_paths = storage::ls(BACKUPS_PATH)?;
+    let backups: BTreeSet<&str> = existing_paths
+        .iter()
+        .filter_map(|path| path[BACKUPS_PATH.len()..].split('/').next())
+        .collect();
+    // Timestamps have the same number of digits, so sort in time order
+    let old_backups: Vec<&str> = backups
+        .iter()
+        .copied()
+        .take(backups.len().saturating_sub(keep))
+        .collect();
+    for old_backup in old_backups {
+        let prefix = format!("{}{}/", BACKUPS_PATH, old_backup);
+        for path in existing_paths
+            .iter()
+            .filter(|path| path.starts_with(&prefix))
+        {
+            storage::rm(path)?;
+        }
+    }
+
+    return Result::Ok(backup_path);
+}
@@ -0,0 +1,306 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::config::config;
+use crate::library::{artist_id, canonical_album, canonical_artist, Library, Song};
+
+pub struct Album {
+    pub id: String,
+    pub title: String,
+    pub artist_id: String,
+    pub artist: String,
+    // In order of disc and then track number
+    pub song_ids: Vec<String>,
+    pub duration: u32,
+    pub has_art: bool,
+}
+
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    // Albums that the artist is the album artist of
+    pub album_ids: Vec<String>,
+    // Songs by the artist, including those on other artists' albums
+    pub song_ids: Vec<String>,
+}
+
+// Albums and artists, worked out from the songs in the library
+#[derive(Default)]
+pub struct Catalog {
+    pub albums: HashMap<String, Album>,
+    pub artists: HashMap<String, Artist>,
+    // Changes whenever the albums or artists might, for use in ETags
+    pub version: String,
+}
+
+// Counts how often each spelling of a name is used, so that the most
+// common one can be shown
+#[derive(Default)]
+struct Spellings {
+    counts: HashMap<String, usize>,
+}
+
+impl Spellings {
+    fn add(&mut self, name: String) {
+        *self.counts.entry(name).or_default() += 1;
+    }
+
+    // Ties go to the first alphabetically, so that the name shown doesn't
+    // change between requests
+    fn most_common(&self) -> String {
+        return self
+            .counts
+            .iter()
+            .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then(b.cmp(a)))
+            .map(|(name, _)| name.clone())
+            .unwrap_or_default();
+    }
+}
+
+// Aliases change how songs are grouped, and only take effect on restart
+fn aliases_hash() -> u64 {
+    let aliases = &config().aliases;
+    let mut artists: Vec<(&String, &String)> = aliases.artists.iter().collect();
+    let mut albums: Vec<(&String, &String)> = aliases.albums.iter().collect();
+    artists.sort();
+    albums.sort();
+
+    let mut hasher = DefaultHasher::new();
+    artists.hash(&mut hasher);
+    albums.hash(&mut hasher);
+    return hasher.finish();
+}
+
+fn track_order(song: &Song) -> (u32, u32, &str, &str) {
+    return (song.disc_number, song.track_number, &song.title, &song.id);
+}
+
+impl Catalog {
+    pub fn new(library: &Library) -> Catalog {
+        let mut artist_names: HashMap<String, Spellings> = HashMap::new();
+        let mut album_titles: HashMap<String, Spellings> = HashMap::new();
+        let mut artist_songs: HashMap<String, Vec<&Song>> = HashMap::new();
+        let mut album_songs: HashMap<String, Vec<&Song>> = HashMap::new();
+
+        for song in library.songs.values() {
+            let id = song.artist_id();
+            artist_names
+                .entry(id.clone())
+                .or_default()
+                .add(canonical_artist(&song.artist));
+            artist_songs.entry(id).or_default().push(song);
+
+            if let Some(album_id) = song.album_id() {
+                // Album artists without songs of their own are artists too
+                if !song.album_artist.trim().is_empty() {
+                    artist_names
+                        .entry(artist_id(&song.album_artist))
+                        .or_default()
+                        .add(canonical_artist(&song.album_artist));
+                }
+                album_titles
+                    .entry(album_id.clone())
+                    .or_default()
+                    .add(canonical_album(&song.album));
+                album_songs.entry(album_id).or_default().push(song);
+            }
+        }
+
+        let mut artists: HashMap<String, Artist> = HashMap::new();
+        for (id, spellings) in &artist_names {
+            let mut songs = artist_songs.remove(id).unwrap_or_default();
+            songs.sort_by(|a, b| (&a.album, track_order(a)).cmp(&(&b.album, track_order(b))));
+            artists.insert(
+                id.clone(),
+                Artist {
+                    id: id.clone(),
+                    name: spellings.most_common(),
+                    album_ids: Vec::new(),
+                    song_ids: songs.iter().map(|song| song.id.clone()).collect(),
+                },
+            );
+        }
+
+        let mut albums: HashMap<String, Album> = HashMap::new();
+        for (id, mut songs) in album_songs {
+            songs.sort_by(|a, b| track_order(a).cmp(&track_order(b)));
+            let artist_id = artist_id(songs[0].album_artist_name());
+            let album = Album {
+                id: id.clone(),
+                title: album_titles[&id].most_common(),
+                artist: artists[&artist_id].name.clone(),
+                artist_id,
+                song_ids: songs.iter().map(|song| song.id.clone()).collect(),
+                duration: songs.iter().map(|song| song.duration).sum(),
+                has_art: library
+                    .album_art
+                    .get(&id)
+                    .is_some_and(|name| !name.is_empty()),
+            };
+            albums.insert(id, album);
+        }
+
+        for album in albums.values() {
+            let artist = artists.get_mut(&album.artist_id).unwrap();
+            artist.album_ids.push(album.id.clone());
+        }
+        for artist in artists.values_mut() {
+            artist
+                .album_ids
+                .sort_by(|a, b| (&albums[a].title, a).cmp(&(&albums[b].title, b)));
+        }
+        // Art found during a sync always starts a new revision
+        let version = format!(
+            "{}-{:016x}-{:016x}",
+            library.revision,
+            library.content_hash(),
+            aliases_hash()
+        );
+        return Catalog {
+            albums,
+            artists,
+            version,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::init_for_tests;
+    use crate::library::test_song;
+
+    fn library(songs: Vec<Song>) -> Library {
+        init_for_tests();
+        let mut library = Library::default();
+        for song in songs {
+            library.songs.insert(song.id.clone(), song);
+        }
+        return library;
+    }
+
+    fn track(id: &str, artist: &str, album: &str, disc: u32, track: u32) -> Song {
+        let mut song = test_song(id, artist, album);
+        song.disc_number = disc;
+        song.track_number = track;
+        return song;
+    }
+
+    fn artist<'a>(catalog: &'a Catalog, name: &str) -> &'a Artist {
+        return &catalog.artists[&artist_id(name)];
+    }
+
+    #[test]
+    fn spellings_of_a_name_are_one_artist() {
+        let library = library(vec![
+            test_song("a", "The Beatles", "Help!"),
+            test_song("b", "Beatles, The", "Help!"),
+            test_song("c", "the  beatles", "Help!"),
+            test_song("d", "The Beatles", "Help!"),
+        ]);
+        let catalog = Catalog::new(&library);
+        assert_eq!(catalog.artists.len(), 1);
+        assert_eq!(catalog.albums.len(), 1);
+        let artist = artist(&catalog, "Beatles, The");
+        assert_eq!(artist.name, "The Beatles");
+        assert_eq!(artist.song_ids.len(), 4);
+    }
+
+    #[test]
+    fn ties_go_to_the_first_spelling() {
+        let library = library(vec![
+            test_song("a", "beatles, the", "Help!"),
+            test_song("b", "The Beatles", "Help!"),
+        ]);
+        let catalog = Catalog::new(&library);
+        assert_eq!(artist(&catalog, "The Beatles").name, "The Beatles");
+    }
+
+    #[test]
+    fn aliases_join_names() {
+        let library = library(vec![
+            test_song("a", "The Beatles", "Abbey Road"),
+            test_song("b", "Fab Four", "Abbey Road (Remastered)"),
+        ]);
+        let catalog = Catalog::new(&library);
+        assert_eq!(catalog.artists.len(), 1);
+        assert_eq!(catalog.albums.len(), 1);
+        let album = catalog.albums.values().next().unwrap();
+        assert_eq!(album.title, "Abbey Road");
+        assert_eq!(album.artist, "The Beatles");
+        assert_eq!(album.song_ids.len(), 2);
+    }
+
+    #[test]
+    fn albums_are_kept_apart_by_artist() {
+        let library = library(vec![
+            test_song("a", "Queen", "Greatest Hits"),
+            test_song("b", "ABBA", "Greatest Hits"),
+        ]);
+        let catalog = Catalog::new(&library);
+        assert_eq!(catalog.albums.len(), 2);
+        assert_eq!(artist(&catalog, "Queen").album_ids.len(), 1);
+    }
+
+    #[test]
+    fn compilations_belong_to_the_album_artist() {
+        let mut b_side = track("b", "Queen", "Hits", 2, 1);
+        b_side.album_artist = "Various Artists".to_string();
+        let mut a_side = track("a", "ABBA", "Hits", 1, 2);
+        a_side.album_artist = "Various Artists".to_string();
+        let library = library(vec![b_side, a_side, test_song("c", "Queen", "")]);
+        let catalog = Catalog::new(&library);
+
+        let various = artist(&catalog, "Various Artists");
+        assert_eq!(various.album_ids.len(), 1);
+        assert!(various.song_ids.is_empty());
+        let album = &catalog.albums[&various.album_ids[0]];
+        assert_eq!(album.artist, "Various Artists");
+        assert_eq!(album.song_ids, vec!["a", "b"]);
+        assert_eq!(album.duration, 200);
+
+        // Songs on other artists' albums, and without an album, still count
+        let queen = artist(&catalog, "Queen");
+        assert!(queen.album_ids.is_empty());
+        assert_eq!(queen.song_ids.len(), 2);
+    }
+
+    #[test]
+    fn songs_are_in_track_order() {
+        let library = library(vec![
+            track("c", "Queen", "Live", 2, 1),
+            track("b", "Queen", "Live", 1, 2),
+            track("a", "Queen", "Live", 1, 1),
+        ]);
+        let catalog = Catalog::new(&library);
+        let album = catalog.albums.values().next().unwrap();
+        assert_eq!(album.song_ids, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn albums_have_art_when_found() {
+        let mut library = library(vec![
+            test_song("a", "Queen", "Live"),
+            test_song("b", "Queen", "Studio"),
+        ]);
+        let live_id = library.songs["a"].album_id().unwrap();
+        let studio_id = library.songs["b"].album_id().unwrap();
+        library
+            .album_art
+            .insert(live_id.clone(), "0123.jpg".to_string());
+        library.album_art.insert(studio_id.clone(), String::new());
+        let catalog = Catalog::new(&library);
+        assert!(catalog.albums[&live_id].has_art);
+        assert!(!catalog.albums[&studio_id].has_art);
+    }
+
+    #[test]
+    fn version_changes_with_the_library() {
+        let mut library = library(vec![test_song("a", "Queen", "Live")]);
+        let version = Catalog::new(&library).version;
+        assert_eq!(Catalog::new(&library).version, version);
+        library.next_revision();
+        assert_ne!(Catalog::new(&library).version, version);
+    }
+}
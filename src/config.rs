@@ -0,0 +1,669 @@
+extern crate toml;
+
+use log::LevelFilter;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::args::{Args, Mode};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    #[default]
+    Azure,
+    // A directory on the local filesystem, laid out like the container
+    Local,
+}
+
+impl StorageBackend {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            StorageBackend::Azure => "azure",
+            StorageBackend::Local => "local",
+        };
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub account_name: String,
+    pub access_key: String,
+    pub container_name: String,
+    // Root directory for the local backend
+    pub path: String,
+    // How long signed URLs for songs stay valid
+    pub sas_expiry_minutes: i64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        return StorageConfig {
+            backend: StorageBackend::Azure,
+            account_name: String::new(),
+            access_key: String::new(),
+            container_name: String::new(),
+            path: String::new(),
+            sas_expiry_minutes: 60,
+        };
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub redirect_http_port: Option<u16>,
+    // How often to check storage for a new library, or 0 to never check
+    pub library_reload_seconds: u64,
+    // Whether songs are streamed through the server rather than from signed
+    // storage URLs. Always the case with local storage.
+    pub proxy_songs: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        return ServerConfig {
+            address: "localhost".to_string(),
+            port: 8000,
+            tls_cert: None,
+            tls_key: None,
+            redirect_http_port: None,
+            library_reload_seconds: 60,
+            proxy_songs: false,
+        };
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    pub rhythmdb_file: Option<String>,
+    pub library_location_prefix: Option<String>,
+    pub dry_run: bool,
+    pub verbose: bool,
+}
+
+// Stops syncing and validation from deleting more than expected, for
+// example when pointed at the wrong rhythmdb file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeletionConfig {
+    pub max_deleted_songs: Option<usize>,
+    pub max_deleted_files: Option<usize>,
+}
+
+// Periodic jobs run by the server, each of which is turned off by setting
+// its interval to 0
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    pub backup_hours: u64,
+    pub backups_kept: usize,
+    pub compact_play_logs_hours: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> JobsConfig {
+        return JobsConfig {
+            backup_hours: 24,
+            backups_kept: 7,
+            compact_play_logs_hours: 24,
+        };
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscodingConfig {
+    // Program used to decode and encode songs, which must take ffmpeg's
+    // arguments
+    pub ffmpeg: String,
+    // Program used to read songs' formats, which must take ffprobe's
+    // arguments
+    pub ffprobe: String,
+    // Where transcoded songs are kept, or rhythmical-transcodes in the
+    // system's temporary directory if not set
+    pub cache_dir: Option<String>,
+    // The least recently used songs are removed once the cache is bigger
+    // than this, and 0 turns the cache off
+    pub cache_size_mb: u64,
+}
+
+impl Default for TranscodingConfig {
+    fn default() -> TranscodingConfig {
+        return TranscodingConfig {
+            ffmpeg: "ffmpeg".to_string(),
+            ffprobe: "ffprobe".to_string(),
+            cache_dir: None,
+            cache_size_mb: 1024,
+        };
+    }
+}
+
+// Other spellings of artists' and albums' names, mapped to the name that
+// they should be listed under. Differences in case, spacing and a trailing
+// ", The" are always ignored.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AliasesConfig {
+    pub artists: HashMap<String, String>,
+    pub albums: HashMap<String, String>,
+}
+
+// Restrictions on the signed URLs handed out for one kind of request
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignedUrlConfig {
+    // How long the URLs stay valid, or storage.sas_expiry_minutes if not set
+    pub expiry_minutes: Option<i64>,
+    // URLs only work over HTTPS unless this is set
+    pub allow_http: bool,
+    // Whether only the IPv4 address that asked for a URL can use it
+    pub bind_client_ip: bool,
+    // Name that browsers give the file when saving it, made from
+    // {artist}, {album} and {title}. The file's extension is added.
+    pub filename: Option<String>,
+}
+
+impl SignedUrlConfig {
+    pub fn expiry_minutes(&self, storage: &StorageConfig) -> i64 {
+        return self.expiry_minutes.unwrap_or(storage.sas_expiry_minutes);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignedUrlsConfig {
+    pub play: SignedUrlConfig,
+    pub download: SignedUrlConfig,
+    // How long a URL is handed out again for the same song instead of
+    // signing a new one, or 0 to always sign
+    pub cache_seconds: u64,
+}
+
+impl Default for SignedUrlsConfig {
+    fn default() -> SignedUrlsConfig {
+        return SignedUrlsConfig {
+            play: SignedUrlConfig::default(),
+            download: SignedUrlConfig::default(),
+            cache_seconds: 300,
+        };
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    #[default]
+    Human,
+    // One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    // Level for rhythmical's own messages, such as "info" or "debug"
+    pub level: String,
+    pub format: LogFormat,
+    // Extra per-module levels in RUST_LOG syntax, for example
+    // "rhythmical::storage=debug,rouille=warn"
+    pub filter: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        return LoggingConfig {
+            level: "info".to_string(),
+            format: LogFormat::Human,
+            filter: String::new(),
+        };
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub storage: StorageConfig,
+    pub server: ServerConfig,
+    pub sync: SyncConfig,
+    pub deletion: DeletionConfig,
+    pub logging: LoggingConfig,
+    pub jobs: JobsConfig,
+    pub signed_urls: SignedUrlsConfig,
+    pub transcoding: TranscodingConfig,
+    pub aliases: AliasesConfig,
+}
+
+impl Config {
+    // Reads the config file, if there is one, and then applies any
+    // overrides from the environment
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let mut config = match config_path(path)? {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)?;
+                toml::from_str(&contents).map_err(|err| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unable to parse {}: {}", path.display(), err),
+                    )
+                })?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        return Result::Ok(config);
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(backend) = env_var("RHYTHMICAL_STORAGE_BACKEND") {
+            self.storage.backend = match backend.as_str() {
+                "azure" => StorageBackend::Azure,
+                "local" => StorageBackend::Local,
+                _ => return Result::Err(invalid_env("RHYTHMICAL_STORAGE_BACKEND")),
+            };
+        }
+        if let Some(account_name) = env_var("AZURE_ACCOUNT_NAME") {
+            self.storage.account_name = account_name;
+        }
+        if let Some(access_key) = env_var("AZURE_ACCESS_KEY") {
+            self.storage.access_key = access_key;
+        }
+        if let Some(container_name) = env_var("AZURE_CONTAINER_NAME") {
+            self.storage.container_name = container_name;
+        }
+        if let Some(path) = env_var("RHYTHMICAL_STORAGE_PATH") {
+            self.storage.path = path;
+        }
+        if let Some(expiry) = env_var("RHYTHMICAL_SAS_EXPIRY_MINUTES") {
+            self.storage.sas_expiry_minutes = expiry
+                .parse()
+                .map_err(|_| invalid_env("RHYTHMICAL_SAS_EXPIRY_MINUTES"))?;
+        }
+        if let Some(address) = env_var("RHYTHMICAL_ADDRESS") {
+            self.server.address = address;
+        }
+        if let Some(port) = env_var("RHYTHMICAL_PORT") {
+            self.server.port = port.parse().map_err(|_| invalid_env("RHYTHMICAL_PORT"))?;
+        }
+        if let Some(tls_cert) = env_var("RHYTHMICAL_TLS_CERT") {
+            self.server.tls_cert = Some(tls_cert);
+        }
+        if let Some(tls_key) = env_var("RHYTHMICAL_TLS_KEY") {
+            self.server.tls_key = Some(tls_key);
+        }
+        if let Some(level) = env_var("RHYTHMICAL_LOG_LEVEL") {
+            self.logging.level = level;
+        }
+        if let Some(format) = env_var("RHYTHMICAL_LOG_FORMAT") {
+            self.logging.format = match format.as_str() {
+                "human" => LogFormat::Human,
+                "json" => LogFormat::Json,
+                _ => return Result::Err(invalid_env("RHYTHMICAL_LOG_FORMAT")),
+            };
+        }
+        return Result::Ok(());
+    }
+
+    // Command line arguments take precedence over everything else
+    pub fn apply_args(&mut self, args: &Args) {
+        if let Mode::Serve(serve) = &args.mode {
+            if let Some(address) = &serve.address {
+                self.server.address = address.clone();
+            }
+            if let Some(port) = serve.port {
+                self.server.port = port;
+            }
+            if serve.tls_cert.is_some() {
+                self.server.tls_cert = serve.tls_cert.clone();
+            }
+            if serve.tls_key.is_some() {
+                self.server.tls_key = serve.tls_key.clone();
+            }
+            if serve.redirect_http_port.is_some() {
+                self.server.redirect_http_port = serve.redirect_http_port;
+            }
+        }
+        if let Mode::SyncRhythmdb(sync) = &args.mode {
+            if sync.rhythmdb_file.is_some() {
+                self.sync.rhythmdb_file = sync.rhythmdb_file.clone();
+            }
+            if sync.library_location_prefix.is_some() {
+                self.sync.library_location_prefix = sync.library_location_prefix.clone();
+            }
+            self.sync.dry_run |= sync.dry_run;
+            self.sync.verbose |= args.verbose > 0;
+        }
+    }
+
+    // Returns a description of everything wrong with the config for the
+    // given mode, so that problems are found before any work starts
+    pub fn validate(&self, mode: &Mode) -> Vec<String> {
+        let mut errors: Vec<String> = Vec::new();
+
+        match self.storage.backend {
+            StorageBackend::Azure => {
+                if self.storage.account_name.is_empty() {
+                    errors.push("storage.account_name or AZURE_ACCOUNT_NAME is required".into());
+                }
+                if self.storage.access_key.is_empty() {
+                    errors.push("storage.access_key or AZURE_ACCESS_KEY is required".into());
+                }
+                if self.storage.container_name.is_empty() {
+                    errors
+                        .push("storage.container_name or AZURE_CONTAINER_NAME is required".into());
+                }
+            }
+            StorageBackend::Local => {
+                if !Path::new(&self.storage.path).is_dir() {
+                    errors.push(format!(
+                        "storage.path \"{}\" is not a directory",
+                        self.storage.path
+                    ));
+                }
+            }
+        }
+        if self.storage.sas_expiry_minutes <= 0 {
+            errors.push("storage.sas_expiry_minutes must be positive".into());
+        }
+        for (name, signed_url) in [
+            ("play", &self.signed_urls.play),
+            ("download", &self.signed_urls.download),
+        ] {
+            if signed_url.expiry_minutes.is_some_and(|minutes| minutes <= 0) {
+                errors.push(format!(
+                    "signed_urls.{}.expiry_minutes must be positive",
+                    name
+                ));
+            }
+        }
+        if self.logging.level.parse::<LevelFilter>().is_err() {
+            errors.push(format!(
+                "logging.level \"{}\" is not a log level",
+                self.logging.level
+            ));
+        }
+
+        if let Mode::Serve(_) = mode {
+            let server = &self.server;
+            if server.tls_cert.is_some() != server.tls_key.is_some() {
+                errors.push("server.tls_cert and server.tls_key must be given together".into());
+            }
+            for path in [&server.tls_cert, &server.tls_key]
+                .iter()
+                .copied()
+                .flatten()
+            {
+                if !Path::new(path).is_file() {
+                    errors.push(format!("TLS file \"{}\" does not exist", path));
+                }
+            }
+            if server.redirect_http_port.is_some() && server.tls_cert.is_none() {
+                errors.push("server.redirect_http_port can only be used with HTTPS".into());
+            }
+            if self.jobs.backup_hours > 0 && self.jobs.backups_kept == 0 {
+                errors.push("jobs.backups_kept must be at least 1 to make backups".into());
+            }
+        }
+
+        if let Mode::SyncRhythmdb(_) = mode {
+            match &self.sync.rhythmdb_file {
+                Some(path) => {
+                    if !Path::new(path).is_file() {
+                        errors.push(format!("rhythmdb file \"{}\" does not exist", path));
+                    }
+                }
+                None => errors.push("A rhythmdb file must be given".into()),
+            }
+            match &self.sync.library_location_prefix {
+                Some(path) => {
+                    if !Path::new(path).is_dir() {
+                        errors.push(format!("Library location \"{}\" does not exist", path));
+                    }
+                }
+                None => errors.push("A library location prefix must be given".into()),
+            }
+        }
+
+        return errors;
+    }
+}
+
+// Uses the given path, or otherwise the XDG config location if a file
+// exists there. Having no config file at all is fine, as everything can
+// come from the environment.
+fn config_path(path: Option<&str>) -> Result<Option<PathBuf>> {
+    if let Some(path) = path {
+        let path = PathBuf::from(path);
+        if !path.is_file() {
+            return Result::Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Config file {} does not exist", path.display()),
+            ));
+        }
+        return Result::Ok(Some(path));
+    }
+
+    let config_dir = match env_var("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env_var("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => return Result::Ok(None),
+        },
+    };
+    let path = config_dir.join("rhythmical").join("config.toml");
+    return Result::Ok(if path.is_file() { Some(path) } else { None });
+}
+
+fn env_var(name: &str) -> Option<String> {
+    return match env::var(name) {
+        Ok(value) if !value.is_empty() => Some(value),
+        _ => None,
+    };
+}
+
+fn invalid_env(name: &str) -> Error {
+    return Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid value for environment variable {}", name),
+    );
+}
+
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        panic!("Config has already been initialised");
+    }
+}
+
+// Tests all share one config, as it can only be set once. Storage goes to
+// a directory of its own for each test run, and it has the aliases that the
+// catalog tests rely on.
+#[cfg(test)]
+pub fn init_for_tests() {
+    CONFIG.get_or_init(|| {
+        let mut config = Config::default();
+        let path = env::temp_dir().join(format!("rhythmical-tests-{}", std::process::id()));
+        fs::create_dir_all(&path).expect("Unable to create test storage");
+        config.storage.backend = StorageBackend::Local;
+        config.storage.path = path.display().to_string();
+        config
+            .aliases
+            .artists
+            .insert("Fab Four".to_string(), "The Beatles".to_string());
+        config.aliases.albums.insert(
+            "Abbey Road (Remastered)".to_string(),
+            "Abbey Road".to_string(),
+        );
+        return config;
+    });
+}
+
+pub fn config() -> &'static Config {
+    return CONFIG.get().expect("Config has not been initialised");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn parse(contents: &str) -> std::result::Result<Config, toml::de::Error> {
+        return toml::from_str(contents);
+    }
+
+    fn args(args: &[&str]) -> Args {
+        return Args::try_parse_from(args).unwrap();
+    }
+
+    // Validates the config for the given subcommand
+    fn problems(config: &Config, mode: &str) -> Vec<String> {
+        return config.validate(&args(&["rhythmical", mode]).mode);
+    }
+
+    fn azure() -> Config {
+        return parse(
+            r#"
+            [storage]
+            account_name = "account"
+            access_key = "key"
+            container_name = "music"
+            "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn missing_sections_use_defaults() {
+        let config = parse("[server]\nport = 9000\n").unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.address, "localhost");
+        assert_eq!(config.server.library_reload_seconds, 60);
+        assert_eq!(config.storage.backend, StorageBackend::Azure);
+        assert_eq!(config.storage.sas_expiry_minutes, 60);
+        assert_eq!(config.deletion.max_deleted_songs, None);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(parse("[server]\nprot = 9000\n").is_err());
+        assert!(parse("[servers]\nport = 9000\n").is_err());
+        assert!(parse("[storage]\nbackend = \"s3\"\n").is_err());
+    }
+
+    #[test]
+    fn azure_needs_credentials() {
+        assert!(problems(&azure(), "validate-library").is_empty());
+        let errors = problems(&Config::default(), "validate-library");
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].contains("AZURE_ACCOUNT_NAME"));
+    }
+
+    #[test]
+    fn local_storage_needs_a_directory() {
+        let mut config = parse("[storage]\nbackend = \"local\"\n").unwrap();
+        config.storage.path = "/does/not/exist".to_string();
+        assert_eq!(problems(&config, "validate-library").len(), 1);
+        config.storage.path = env::temp_dir().display().to_string();
+        assert!(problems(&config, "validate-library").is_empty());
+    }
+
+    #[test]
+    fn tls_settings_are_checked_when_serving() {
+        let mut config = azure();
+        config.server.tls_cert = Some("/does/not/exist.pem".to_string());
+        config.server.redirect_http_port = Some(80);
+        assert!(problems(&config, "validate-library").is_empty());
+        let errors = problems(&config, "serve");
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("must be given together"));
+        assert!(errors[1].contains("does not exist"));
+
+        config.server.tls_cert = None;
+        let errors = problems(&config, "serve");
+        assert_eq!(
+            errors,
+            vec!["server.redirect_http_port can only be used with HTTPS"]
+        );
+    }
+
+    #[test]
+    fn syncing_needs_paths() {
+        let mut config = azure();
+        assert_eq!(problems(&config, "sync-rhythmdb").len(), 2);
+        config.sync.rhythmdb_file = Some("/does/not/exist.xml".to_string());
+        config.sync.library_location_prefix = Some(env::temp_dir().display().to_string());
+        let errors = problems(&config, "sync-rhythmdb");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("rhythmdb file"));
+    }
+
+    #[test]
+    fn arguments_override_the_file() {
+        let mut config = azure();
+        config.apply_args(&args(&["rhythmical", "serve", "--port", "9000"]));
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.address, "localhost");
+
+        config.apply_args(&args(&[
+            "rhythmical",
+            "-v",
+            "sync-rhythmdb",
+            "a.xml",
+            "/music",
+        ]));
+        assert_eq!(config.sync.rhythmdb_file, Some("a.xml".to_string()));
+        assert!(config.sync.verbose);
+        assert!(!config.sync.dry_run);
+    }
+
+    #[test]
+    fn log_levels_are_checked() {
+        let mut config = parse("[logging]\nlevel = \"debug\"\nformat = \"json\"\n").unwrap();
+        config.storage = azure().storage;
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert!(problems(&config, "validate-library").is_empty());
+        config.logging.level = "loud".to_string();
+        assert_eq!(
+            problems(&config, "validate-library"),
+            vec!["logging.level \"loud\" is not a log level"]
+        );
+    }
+
+    #[test]
+    fn backups_need_somewhere_to_go() {
+        let mut config = azure();
+        config.jobs.backups_kept = 0;
+        assert_eq!(
+            problems(&config, "serve"),
+            vec!["jobs.backups_kept must be at least 1 to make backups"]
+        );
+        config.jobs.backup_hours = 0;
+        assert!(problems(&config, "serve").is_empty());
+    }
+
+    #[test]
+    fn signed_urls_default_to_the_storage_expiry() {
+        let mut config = parse("[signed_urls.download]\nexpiry_minutes = 5\n").unwrap();
+        let urls = &config.signed_urls;
+        assert_eq!(urls.play.expiry_minutes(&config.storage), 60);
+        assert_eq!(urls.download.expiry_minutes(&config.storage), 5);
+
+        config.storage = azure().storage;
+        config.signed_urls.play.expiry_minutes = Some(0);
+        assert_eq!(
+            problems(&config, "serve"),
+            vec!["signed_urls.play.expiry_minutes must be positive"]
+        );
+    }
+}
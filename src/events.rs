@@ -0,0 +1,368 @@
+extern crate rouille;
+extern crate serde_json;
+
+use rouille::{Response, ResponseBody};
+use std::io::{Cursor, Read, Result};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::api::ApiSong;
+use crate::library::Library;
+use crate::sessions::SessionCommand;
+use crate::user_data::{Playlist, UserData};
+
+// How often to send a comment to idle clients so that proxies and browsers
+// don't decide the connection has died. This is also how streams to
+// clients that have gone away find out and are cleaned up.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// tiny_http only sends a chunk of a streamed response once this much has
+// built up, so every message is padded out to the end of a chunk
+const CHUNK_SIZE: u64 = 8192;
+
+#[derive(Serialize, Clone)]
+pub struct NowPlaying {
+    pub song_id: String,
+}
+
+#[derive(Clone)]
+pub enum Event {
+    SongUpdated(ApiSong),
+    SongsAdded(Vec<ApiSong>),
+    SongsRemoved(Vec<String>),
+    NowPlaying(NowPlaying),
+    SessionCommand(SessionCommand),
+    // All of the user's playlists after one of them changed
+    PlaylistChanged(Vec<Playlist>),
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        return match self {
+            Event::SongUpdated(_) => "song-updated",
+            Event::SongsAdded(_) => "songs-added",
+            Event::SongsRemoved(_) => "songs-removed",
+            Event::NowPlaying(_) => "now-playing",
+            Event::SessionCommand(_) => "session-command",
+            Event::PlaylistChanged(_) => "playlist-changed",
+        };
+    }
+
+    fn data(&self) -> String {
+        let data = match self {
+            Event::SongUpdated(song) => serde_json::to_string(song),
+            Event::SongsAdded(songs) => serde_json::to_string(songs),
+            Event::SongsRemoved(ids) => serde_json::to_string(ids),
+            Event::NowPlaying(now_playing) => serde_json::to_string(now_playing),
+            Event::SessionCommand(command) => serde_json::to_string(command),
+            Event::PlaylistChanged(playlists) => serde_json::to_string(playlists),
+        };
+        return data.expect("Unable to serialize event");
+    }
+
+    // Formats the event as described by the Server-Sent Events spec
+    fn to_message(&self, id: u64) -> String {
+        return format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            id,
+            self.name(),
+            self.data()
+        );
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    user: String,
+    sender: Sender<String>,
+}
+
+struct Subscribers {
+    next_event_id: u64,
+    next_subscriber_id: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+pub struct EventBus {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        return EventBus {
+            subscribers: Arc::new(Mutex::new(Subscribers {
+                next_event_id: 1,
+                next_subscriber_id: 1,
+                subscribers: Vec::new(),
+            })),
+        };
+    }
+
+    // Sends the event to every stream opened by the given user. Events
+    // carry ratings and plays, so they are never shared between users.
+    pub fn publish(&self, user: &str, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let message = event.to_message(subscribers.next_event_id);
+        subscribers.next_event_id += 1;
+
+        // Sending only fails once the receiving stream has been dropped,
+        // so this also cleans up after clients that have gone away.
+        subscribers.subscribers.retain(|subscriber| {
+            subscriber.user != user || subscriber.sender.send(message.clone()).is_ok()
+        });
+    }
+
+    // Returns every user with a stream open
+    pub fn users(&self) -> Vec<String> {
+        let subscribers = self.subscribers.lock().unwrap();
+        let mut users: Vec<String> = subscribers
+            .subscribers
+            .iter()
+            .map(|subscriber| subscriber.user.clone())
+            .collect();
+        users.sort();
+        users.dedup();
+        return users;
+    }
+
+    // Publishes events for everything in the library that has changed
+    // since the given revision.
+    pub fn publish_library_changes(&self, library: &Library, user_data: &UserData, since: u64) {
+        let changes = library.changes_since(since);
+        let user = user_data.name.as_str();
+        if !changes.added.is_empty() {
+            self.publish(
+                user,
+                Event::SongsAdded(
+                    changes
+                        .added
+                        .into_iter()
+                        .map(|song| ApiSong::new(song, user_data))
+                        .collect(),
+                ),
+            );
+        }
+        for song in changes.updated {
+            self.publish(user, Event::SongUpdated(ApiSong::new(song, user_data)));
+        }
+        if !changes.removed.is_empty() {
+            self.publish(user, Event::SongsRemoved(changes.removed));
+        }
+    }
+
+    // Returns a response that keeps the connection open and streams all
+    // future events for the user to the client.
+    pub fn subscribe(&self, user: &str) -> Response {
+        let (sender, receiver) = channel();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let id = subscribers.next_subscriber_id;
+        subscribers.next_subscriber_id += 1;
+        subscribers.subscribers.push(Subscriber {
+            id,
+            user: user.to_string(),
+            sender,
+        });
+
+        // Sends a comment straight away so that the client sees the stream
+        // open without waiting for the first event
+        let mut stream = EventStream {
+            id,
+            receiver,
+            subscribers: self.subscribers.clone(),
+            pending: Cursor::new(Vec::new()),
+            written: 0,
+        };
+        stream.queue(": connected\n\n".to_string());
+
+        return Response {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".into(), "text/event-stream".into()),
+                ("Cache-Control".into(), "no-cache".into()),
+            ],
+            data: ResponseBody::from_reader(stream),
+            upgrade: None,
+        };
+    }
+}
+
+// The body of an event stream, which reads messages from the subscriber's
+// channel as the response is sent. The response ends once writing to the
+// client fails, which drops the stream and removes the subscriber.
+struct EventStream {
+    id: u64,
+    receiver: Receiver<String>,
+    subscribers: Arc<Mutex<Subscribers>>,
+    // The rest of the message being sent
+    pending: Cursor<Vec<u8>>,
+    // Bytes sent so far, to know where the current chunk ends
+    written: u64,
+}
+
+impl EventStream {
+    // Pads the message with a comment to the end of the chunk it finishes
+    // in, followed by a blank line, which SSE ignores, that starts the
+    // next chunk and makes the encoder send this one
+    fn queue(&mut self, message: String) {
+        let end = self.written + message.len() as u64;
+        let mut fill = (CHUNK_SIZE - end % CHUNK_SIZE) % CHUNK_SIZE;
+        if fill < 2 {
+            fill += CHUNK_SIZE;
+        }
+        let mut data = message.into_bytes();
+        data.push(b':');
+        data.resize(data.len() + fill as usize - 2, b' ');
+        data.extend(b"\n\n");
+        self.pending = Cursor::new(data);
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pending.position() == self.pending.get_ref().len() as u64 {
+            let message = match self.receiver.recv_timeout(KEEPALIVE_INTERVAL) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.queue(message);
+        }
+        let length = self.pending.read(buf)?;
+        self.written += length as u64;
+        return Ok(length);
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers
+            .subscribers
+            .retain(|subscriber| subscriber.id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now_playing(song_id: &str) -> Event {
+        return Event::NowPlaying(NowPlaying {
+            song_id: song_id.to_string(),
+        });
+    }
+
+    fn subscriber_count(events: &EventBus) -> usize {
+        return events.subscribers.lock().unwrap().subscribers.len();
+    }
+
+    #[test]
+    fn formats_messages() {
+        assert_eq!(
+            now_playing("a").to_message(3),
+            "id: 3\nevent: now-playing\ndata: {\"song_id\":\"a\"}\n\n"
+        );
+        let removed = Event::SongsRemoved(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(
+            removed.to_message(4),
+            "id: 4\nevent: songs-removed\ndata: [\"a\",\"b\"]\n\n"
+        );
+    }
+
+    #[test]
+    fn dropping_a_stream_removes_its_subscriber() {
+        let events = EventBus::new();
+        let connected = events.subscribe("a");
+        drop(events.subscribe("a"));
+        assert_eq!(subscriber_count(&events), 1);
+
+        events.publish("a", now_playing("a"));
+        assert_eq!(subscriber_count(&events), 1);
+        drop(connected);
+        assert_eq!(subscriber_count(&events), 0);
+    }
+
+    #[test]
+    fn publish_drops_subscribers_whose_stream_has_gone() {
+        let events = EventBus::new();
+        let (sender, receiver) = channel();
+        events
+            .subscribers
+            .lock()
+            .unwrap()
+            .subscribers
+            .push(Subscriber {
+                id: 0,
+                user: "a".to_string(),
+                sender,
+            });
+        drop(receiver);
+        events.publish("a", now_playing("a"));
+        assert_eq!(subscriber_count(&events), 0);
+    }
+
+    #[test]
+    fn every_message_ends_a_chunk() {
+        let events = EventBus::new();
+        let (mut body, _) = events.subscribe("a").data.into_reader_and_size();
+        events.publish("a", now_playing("a"));
+        events.publish("a", now_playing(&"b".repeat(CHUNK_SIZE as usize)));
+
+        let mut read = |length: u64| {
+            let mut data = vec![0; length as usize];
+            body.read_exact(&mut data).unwrap();
+            return String::from_utf8(data).unwrap();
+        };
+        // Each message is sent with the blank line that follows it
+        let connected = read(CHUNK_SIZE + 1);
+        assert!(connected.starts_with(": connected\n\n:"));
+        assert!(connected.ends_with(" \n\n"));
+        let first = read(CHUNK_SIZE);
+        assert!(first.starts_with("id: 1\nevent: now-playing\n"));
+        assert!(first.ends_with(" \n\n"));
+        let second = read(CHUNK_SIZE * 2);
+        assert!(second.starts_with("id: 2\n"));
+        assert!(second.ends_with(" \n\n"));
+    }
+
+    #[test]
+    fn events_only_go_to_their_user() {
+        let events = EventBus::new();
+        let (sender, receiver) = channel();
+        events
+            .subscribers
+            .lock()
+            .unwrap()
+            .subscribers
+            .push(Subscriber {
+                id: 0,
+                user: "b".to_string(),
+                sender,
+            });
+        events.publish("a", now_playing("a"));
+        assert!(receiver.try_recv().is_err());
+        events.publish("b", now_playing("b"));
+        assert!(receiver.try_recv().unwrap().contains("\"song_id\":\"b\""));
+    }
+
+    #[test]
+    fn event_ids_increase() {
+        let events = EventBus::new();
+        events.publish("a", now_playing("a"));
+        events.publish("a", now_playing("b"));
+        assert_eq!(events.subscribers.lock().unwrap().next_event_id, 3);
+    }
+
+    #[test]
+    fn users_with_streams_open() {
+        let events = EventBus::new();
+        assert!(events.users().is_empty());
+        let _streams = [
+            events.subscribe("b"),
+            events.subscribe("a"),
+            events.subscribe("b"),
+        ];
+        assert_eq!(events.users(), vec!["a", "b"]);
+    }
+}
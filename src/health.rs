@@ -0,0 +1,130 @@
+use rouille::Response;
+use std::io::Error;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+use crate::storage;
+
+#[derive(Default)]
+struct LoadState {
+    // Unix timestamp of when the library was last loaded successfully
+    loaded_at: Option<i64>,
+    last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    library_loaded: bool,
+    loaded_at: Option<i64>,
+    load_error: Option<String>,
+    storage_reachable: bool,
+    storage_error: Option<String>,
+}
+
+// Keeps track of whether the server has managed to load what it needs
+// from storage, for process supervisors and load balancers to check
+pub struct Health {
+    state: Mutex<LoadState>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        return Health {
+            state: Mutex::new(LoadState::default()),
+        };
+    }
+
+    pub fn record_loaded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.loaded_at = Some(OffsetDateTime::now_utc().unix_timestamp());
+        state.last_error = None;
+    }
+
+    // Called when the library was found to be up to date
+    pub fn record_checked(&self) {
+        self.state.lock().unwrap().last_error = None;
+    }
+
+    pub fn record_error(&self, error: &Error) {
+        self.state.lock().unwrap().last_error = Some(error.to_string());
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        return self.state.lock().unwrap().loaded_at.is_some();
+    }
+
+    // Ready once the library has been loaded and storage can still be
+    // reached, so that the server is taken out of rotation if either fails
+    pub fn readyz(&self) -> Response {
+        let (storage_reachable, storage_error) = match storage::exists("library.json") {
+            Ok(true) => (true, None),
+            Ok(false) => (true, Some("library.json does not exist".to_string())),
+            Err(error) => (false, Some(error.to_string())),
+        };
+
+        let state = self.state.lock().unwrap();
+        let readiness = Readiness {
+            ready: state.loaded_at.is_some() && storage_reachable,
+            library_loaded: state.loaded_at.is_some(),
+            loaded_at: state.loaded_at,
+            load_error: state.last_error.clone(),
+            storage_reachable,
+            storage_error,
+        };
+        let status = if readiness.ready { 200 } else { 503 };
+        return Response::json(&readiness)
+            .with_unique_header("Cache-Control", "no-store")
+            .with_status_code(status);
+    }
+}
+
+// The process is alive if it can answer at all
+pub fn healthz() -> Response {
+    return Response::text("ok").with_unique_header("Cache-Control", "no-store");
+}
+
+pub fn not_ready() -> Response {
+    return Response::text("The library hasn't been loaded yet")
+        .with_status_code(503)
+        .with_unique_header("Retry-After", "5");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn loaded_after_first_success() {
+        let health = Health::new();
+        assert!(!health.is_loaded());
+
+        health.record_error(&Error::new(ErrorKind::NotFound, "no library"));
+        assert!(!health.is_loaded());
+        assert_eq!(
+            health.state.lock().unwrap().last_error.as_deref(),
+            Some("no library")
+        );
+
+        health.record_loaded();
+        assert!(health.is_loaded());
+        assert!(health.state.lock().unwrap().last_error.is_none());
+
+        // A later failure keeps the library that was already loaded
+        health.record_error(&Error::other("timed out"));
+        assert!(health.is_loaded());
+        health.record_checked();
+        assert!(health.state.lock().unwrap().last_error.is_none());
+    }
+
+    #[test]
+    fn not_ready_asks_clients_to_retry() {
+        let response = not_ready();
+        assert_eq!(response.status_code, 503);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(name, value)| name == "Retry-After" && value == "5"));
+    }
+}
@@ -0,0 +1,184 @@
+use std::io::Result;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+
+#[derive(Serialize, Clone)]
+pub struct JobStatus {
+    name: String,
+    interval_seconds: u64,
+    running: bool,
+    runs: u64,
+    failures: u64,
+    // Unix timestamps
+    last_started_at: Option<i64>,
+    next_run_at: Option<i64>,
+    last_duration_ms: Option<u64>,
+    last_error: Option<String>,
+}
+
+struct Stop {
+    stopping: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Stop {
+    // Waits for the given time, returning false straight away if the
+    // runner is stopped in the meantime
+    fn wait(&self, timeout: Duration) -> bool {
+        let stopping = self.stopping.lock().unwrap();
+        let (stopping, _) = self
+            .condvar
+            .wait_timeout_while(stopping, timeout, |stopping| !*stopping)
+            .unwrap();
+        return !*stopping;
+    }
+}
+
+// Runs periodic jobs on their own threads. A job never runs twice at once,
+// and stopping the runner waits for any running jobs to finish.
+pub struct JobRunner {
+    statuses: Arc<Mutex<Vec<JobStatus>>>,
+    stop: Arc<Stop>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl JobRunner {
+    pub fn new() -> JobRunner {
+        return JobRunner {
+            statuses: Arc::new(Mutex::new(Vec::new())),
+            stop: Arc::new(Stop {
+                stopping: Mutex::new(false),
+                condvar: Condvar::new(),
+            }),
+            threads: Mutex::new(Vec::new()),
+        };
+    }
+
+    // Runs the job every interval, starting one interval from now. Jobs
+    // with an interval of zero are turned off and never run.
+    pub fn add<F>(&self, name: &str, interval: Duration, job: F)
+    where
+        F: Fn() -> Result<String> + Send + 'static,
+    {
+        if interval.is_zero() {
+            return;
+        }
+
+        let index = {
+            let mut statuses = self.statuses.lock().unwrap();
+            statuses.push(JobStatus {
+                name: name.to_string(),
+                interval_seconds: interval.as_secs(),
+                running: false,
+                runs: 0,
+                failures: 0,
+                last_started_at: None,
+                next_run_at: Some(unix_time_after(interval)),
+                last_duration_ms: None,
+                last_error: None,
+            });
+            statuses.len() - 1
+        };
+
+        let name = name.to_string();
+        let statuses = self.statuses.clone();
+        let stop = self.stop.clone();
+        let thread = thread::spawn(move || {
+            while stop.wait(interval) {
+                {
+                    let status = &mut statuses.lock().unwrap()[index];
+                    status.running = true;
+                    status.last_started_at = Some(OffsetDateTime::now_utc().unix_timestamp());
+                }
+
+                let start = Instant::now();
+                let result = job();
+                let duration = start.elapsed();
+
+                let status = &mut statuses.lock().unwrap()[index];
+                status.running = false;
+                status.runs += 1;
+                status.last_duration_ms = Some(duration.as_millis() as u64);
+                status.next_run_at = Some(unix_time_after(interval));
+                match result {
+                    Ok(summary) => {
+                        debug!("Job {} finished: {}", name, summary);
+                        status.last_error = None;
+                    }
+                    Err(error) => {
+                        warn!("Job {} failed: {}", name, error);
+                        status.failures += 1;
+                        status.last_error = Some(error.to_string());
+                    }
+                }
+            }
+        });
+        self.threads.lock().unwrap().push(thread);
+    }
+
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        return self.statuses.lock().unwrap().clone();
+    }
+
+    // Stops running jobs, waiting for any that are part way through
+    pub fn stop(&self) {
+        *self.stop.stopping.lock().unwrap() = true;
+        self.stop.condvar.notify_all();
+        for thread in self.threads.lock().unwrap().drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn unix_time_after(duration: Duration) -> i64 {
+    return OffsetDateTime::now_utc().unix_timestamp() + duration.as_secs() as i64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Error;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn jobs_with_no_interval_are_off() {
+        let jobs = JobRunner::new();
+        jobs.add("off", Duration::ZERO, || Ok(String::new()));
+        assert!(jobs.statuses().is_empty());
+        assert!(jobs.threads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn records_runs_and_failures() {
+        let jobs = JobRunner::new();
+        let (sender, receiver) = channel();
+        jobs.add("failing", Duration::from_millis(10), move || {
+            sender.send(()).unwrap();
+            return Err(Error::other("storage is down"));
+        });
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        jobs.stop();
+
+        let status = &jobs.statuses()[0];
+        assert_eq!(status.name, "failing");
+        assert!(!status.running);
+        assert!(status.runs >= 2);
+        assert_eq!(status.failures, status.runs);
+        assert_eq!(status.last_error.as_deref(), Some("storage is down"));
+    }
+
+    #[test]
+    fn stopping_doesnt_wait_for_the_next_run() {
+        let jobs = JobRunner::new();
+        jobs.add("daily", Duration::from_secs(24 * 60 * 60), || {
+            Ok(String::new())
+        });
+        let start = Instant::now();
+        jobs.stop();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(jobs.statuses()[0].runs, 0);
+    }
+}
@@ -2,15 +2,22 @@ extern crate lazy_static;
 extern crate rand;
 extern crate regex;
 extern crate serde_json;
+extern crate sha2;
 
+use std::collections::hash_map::DefaultHasher;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::io::Result;
 use std::path::Path;
 
 use rand::distr::Alphanumeric;
 use rand::{rng, Rng};
 use regex::Regex;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
 
+use crate::config::config;
 use crate::storage;
 
 lazy_static! {
@@ -25,9 +32,91 @@ pub struct Song {
     pub genre: String,
     pub artist: String,
     pub album: String,
+    // Who the album is by, when that isn't the song's artist
+    #[serde(default)]
+    pub album_artist: String,
+    // Position on the album, or 0 if not known
+    #[serde(default)]
+    pub disc_number: u32,
+    #[serde(default)]
+    pub track_number: u32,
     pub duration: u32,
     pub rating: u32,
     pub file_location: String,
+    // The library revisions at which the song was added and last changed
+    #[serde(default)]
+    pub added_revision: u64,
+    #[serde(default)]
+    pub updated_revision: u64,
+    // Unix timestamp of when the song was first added to any library
+    #[serde(default)]
+    pub first_seen: Option<i64>,
+    // Set by analyze-loudness
+    #[serde(default)]
+    pub replay_gain: Option<ReplayGain>,
+    // Set by validate-library --deep, from the file itself
+    #[serde(default)]
+    pub audio: Option<AudioProperties>,
+}
+
+#[derive(PartialOrd, PartialEq, Ord, Eq, Hash, Clone, Serialize, Deserialize, Debug)]
+pub struct AudioProperties {
+    pub codec: String,
+    // In kbit/s, averaged over the song if it varies
+    pub bitrate: u32,
+    // In Hz
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+// How much to change the volume of a song so that songs sound equally
+// loud, following ReplayGain 2.0
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct ReplayGain {
+    // In dB, to bring the track or its album to -18 LUFS
+    pub track_gain: f64,
+    pub album_gain: f64,
+    // Highest true peak, as a fraction of full scale
+    pub track_peak: f64,
+    pub album_peak: f64,
+}
+
+impl ReplayGain {
+    // Lets songs be compared and hashed, which floats can't be
+    fn bits(&self) -> [u64; 4] {
+        return [
+            self.track_gain.to_bits(),
+            self.album_gain.to_bits(),
+            self.track_peak.to_bits(),
+            self.album_peak.to_bits(),
+        ];
+    }
+}
+
+impl PartialEq for ReplayGain {
+    fn eq(&self, other: &ReplayGain) -> bool {
+        return self.bits() == other.bits();
+    }
+}
+
+impl Eq for ReplayGain {}
+
+impl PartialOrd for ReplayGain {
+    fn partial_cmp(&self, other: &ReplayGain) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for ReplayGain {
+    fn cmp(&self, other: &ReplayGain) -> Ordering {
+        return self.bits().cmp(&other.bits());
+    }
+}
+
+impl Hash for ReplayGain {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
 }
 
 impl Song {
@@ -49,24 +138,112 @@ impl Song {
     pub fn has_correct_file_location(&self) -> bool {
         return self.file_location.eq(&self.correct_file_location());
     }
+
+    pub fn album_artist_name(&self) -> &str {
+        if self.album_artist.trim().is_empty() {
+            return &self.artist;
+        }
+        return &self.album_artist;
+    }
+
+    pub fn artist_id(&self) -> String {
+        return artist_id(&self.artist);
+    }
+
+    // Identifies the album the song is on, if any. Albums of the same name
+    // by different artists are different albums.
+    pub fn album_id(&self) -> Option<String> {
+        if self.album.trim().is_empty() {
+            return None;
+        }
+        let key = format!(
+            "{}\n{}",
+            name_key(&canonical_artist(self.album_artist_name())),
+            name_key(&canonical_album(&self.album))
+        );
+        return Some(hash_id(&key));
+    }
+}
+
+// Reduces a name to what is compared when deciding whether two spellings
+// are the same, so that "Beatles, The" is "the beatles"
+fn name_key(name: &str) -> String {
+    let key = name
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase();
+    return match key.strip_suffix(", the") {
+        Some(rest) => format!("the {}", rest),
+        None => key,
+    };
+}
+
+fn alias<'a>(aliases: &'a HashMap<String, String>, name: &'a str) -> &'a str {
+    let key = name_key(name);
+    return aliases
+        .iter()
+        .find(|(variant, _)| name_key(variant) == key)
+        .map(|(_, canonical)| canonical.as_str())
+        .unwrap_or(name);
+}
+
+// The name to list an artist or album under, after applying any aliases
+pub fn canonical_artist(name: &str) -> String {
+    return alias(&config().aliases.artists, name).trim().to_string();
 }
 
-#[derive(Serialize, Deserialize)]
+pub fn canonical_album(name: &str) -> String {
+    return alias(&config().aliases.albums, name).trim().to_string();
+}
+
+fn hash_id(key: &str) -> String {
+    return Sha256::digest(key.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+}
+
+pub fn artist_id(name: &str) -> String {
+    return hash_id(&name_key(&canonical_artist(name)));
+}
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct Library {
+    // Increases by one every time a set of changes is made to the library
+    #[serde(default)]
+    pub revision: u64,
     pub songs: HashMap<String, Song>,
+    // Map from the ids of removed songs to the revision they were removed at
+    #[serde(default)]
+    pub removed_songs: HashMap<String, u64>,
+    // Map from album ids to the name of their art in Art/, or an empty
+    // string if none was found
+    #[serde(default)]
+    pub album_art: HashMap<String, String>,
+}
+
+pub struct LibraryChanges<'a> {
+    pub revision: u64,
+    pub added: Vec<&'a Song>,
+    pub updated: Vec<&'a Song>,
+    pub removed: Vec<String>,
 }
 
 impl Library {
     pub fn new() -> Library {
-        return match storage::cat("library.json") {
-            Ok(data) => match serde_json::from_slice(&data) {
-                Ok(library) => library,
-                Err(error) => panic!("Unable to parse library: {}", error),
-            },
+        return match Library::load() {
+            Ok(library) => library,
             Err(error) => panic!("Unable to load library: {}", error),
         };
     }
 
+    pub fn load() -> Result<Library> {
+        let data = storage::cat("library.json")?;
+        return Result::Ok(serde_json::from_slice(&data)?);
+    }
+
     pub fn save(&self) -> Result<()> {
         let temp_file = "/tmp/new_library.json";
         if Path::new(temp_file).exists() {
@@ -85,37 +262,143 @@ impl Library {
         return Result::Ok(());
     }
 
-    pub fn combine_libraries(matched_songs: &Vec<(Song, Song)>, new_songs: &Vec<Song>) -> Library {
-        let mut songs: HashMap<String, Song> = HashMap::new();
+    pub fn combine_libraries(
+        dest_library: &Library,
+        matched_songs: &Vec<(Song, Song)>,
+        new_songs: &Vec<Song>,
+    ) -> Library {
+        let mut library = Library {
+            revision: dest_library.revision,
+            songs: HashMap::new(),
+            removed_songs: dest_library.removed_songs.clone(),
+            album_art: dest_library.album_art.clone(),
+        };
+        // The revision is only kept if something changed
+        let revision = library.next_revision();
+        let mut changed = !new_songs.is_empty();
 
         // source_song is from the local library being uploaded
         // dest_song is from the existing cloud library
         for (source_song, dest_song) in matched_songs {
-            songs.insert(
-                dest_song.id.clone(),
-                Song {
-                    id: dest_song.id.clone(),
-                    title: dest_song.title.clone(),
-                    genre: source_song.genre.clone(),
-                    artist: dest_song.artist.clone(),
-                    album: dest_song.album.clone(),
-                    duration: dest_song.duration,
-                    rating: source_song.rating,
-                    file_location: dest_song.file_location.clone(),
-                },
-            );
+            let mut song = Song {
+                id: dest_song.id.clone(),
+                title: dest_song.title.clone(),
+                genre: source_song.genre.clone(),
+                artist: dest_song.artist.clone(),
+                album: dest_song.album.clone(),
+                album_artist: source_song.album_artist.clone(),
+                disc_number: source_song.disc_number,
+                track_number: source_song.track_number,
+                duration: dest_song.duration,
+                rating: source_song.rating,
+                file_location: dest_song.file_location.clone(),
+                added_revision: dest_song.added_revision,
+                updated_revision: dest_song.updated_revision,
+                first_seen: dest_song.first_seen.or(source_song.first_seen),
+                replay_gain: dest_song.replay_gain,
+                audio: dest_song.audio.clone(),
+            };
+            if song.genre != dest_song.genre
+                || song.rating != dest_song.rating
+                || song.album_artist != dest_song.album_artist
+                || song.disc_number != dest_song.disc_number
+                || song.track_number != dest_song.track_number
+            {
+                song.updated_revision = revision;
+                changed = true;
+            }
+            library.songs.insert(song.id.clone(), song);
         }
 
         for song in new_songs {
             let mut new_song = song.clone();
-            if songs.contains_key(&song.id) {
-                let new_id = new_song_id(&songs);
+            if library.songs.contains_key(&song.id) {
+                let new_id = library.new_song_id();
                 new_song.id = new_id;
             }
-            songs.insert(new_song.id.clone(), new_song);
+            library.add_song(new_song);
+        }
+
+        for id in dest_library.songs.keys() {
+            if !library.songs.contains_key(id) {
+                library.removed_songs.insert(id.clone(), revision);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            library.revision = dest_library.revision;
+        }
+        return library;
+    }
+
+    // Starts a new revision of the library. Changes made afterwards using
+    // add_song, update_song or remove_song are recorded against it.
+    pub fn next_revision(&mut self) -> u64 {
+        self.revision += 1;
+        return self.revision;
+    }
+
+    pub fn add_song(&mut self, mut song: Song) {
+        song.added_revision = self.revision;
+        song.updated_revision = self.revision;
+        if song.first_seen.is_none() {
+            song.first_seen = Some(OffsetDateTime::now_utc().unix_timestamp());
+        }
+        self.removed_songs.remove(&song.id);
+        self.songs.insert(song.id.clone(), song);
+    }
+
+    pub fn update_song(&mut self, mut song: Song) {
+        song.updated_revision = self.revision;
+        self.songs.insert(song.id.clone(), song);
+    }
+
+    pub fn remove_song(&mut self, id: &str) -> Option<Song> {
+        let song = self.songs.remove(id);
+        if song.is_some() {
+            self.removed_songs.insert(id.to_string(), self.revision);
+        }
+        return song;
+    }
+
+    // Returns all songs that have been added, updated or removed after the
+    // given revision. Songs that were both added and updated count as added.
+    pub fn changes_since(&self, since: u64) -> LibraryChanges<'_> {
+        let mut changes = LibraryChanges {
+            revision: self.revision,
+            added: Vec::new(),
+            updated: Vec::new(),
+            removed: Vec::new(),
+        };
+        for song in self.songs.values() {
+            if since == 0 || song.added_revision > since {
+                changes.added.push(song);
+            } else if song.updated_revision > since {
+                changes.updated.push(song);
+            }
+        }
+        if since != 0 {
+            for (id, revision) in &self.removed_songs {
+                if *revision > since {
+                    changes.removed.push(id.clone());
+                }
+            }
         }
+        return changes;
+    }
+
+    // Computes a value that changes whenever any song in the library changes,
+    // suitable for use in an HTTP entity tag.
+    pub fn content_hash(&self) -> u64 {
+        let mut ids: Vec<&String> = self.songs.keys().collect();
+        ids.sort();
 
-        return Library { songs };
+        let mut hasher = DefaultHasher::new();
+        for id in ids {
+            self.songs.get(id).unwrap().hash(&mut hasher);
+        }
+        return hasher.finish();
     }
 
     pub fn new_song_id(&self) -> String {
@@ -125,13 +408,181 @@ impl Library {
 
 fn new_song_id(songs: &HashMap<String, Song>) -> String {
     loop {
-        let id: String = rng()
-            .sample_iter(&Alphanumeric)
-            .take(16)
-            .map(char::from)
-            .collect();
+        let id = random_id();
         if !songs.contains_key(&id) {
             return id;
         }
     }
 }
+
+pub fn random_id() -> String {
+    return random_string(16);
+}
+
+pub fn random_string(length: usize) -> String {
+    return rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect();
+}
+
+// A song with just enough set to tell it apart, for tests
+#[cfg(test)]
+pub fn test_song(id: &str, artist: &str, album: &str) -> Song {
+    return Song {
+        id: id.to_string(),
+        title: format!("Song {}", id),
+        genre: String::new(),
+        artist: artist.to_string(),
+        album: album.to_string(),
+        album_artist: String::new(),
+        disc_number: 0,
+        track_number: 0,
+        duration: 100,
+        rating: 0,
+        file_location: format!("/{}/{}/{}.mp3", artist, album, id),
+        added_revision: 0,
+        updated_revision: 0,
+        first_seen: None,
+        replay_gain: None,
+        audio: None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_library() -> Library {
+        return Library {
+            revision: 0,
+            songs: HashMap::new(),
+            removed_songs: HashMap::new(),
+            album_art: HashMap::new(),
+        };
+    }
+
+    fn library(songs: Vec<Song>) -> Library {
+        let mut library = empty_library();
+        for song in songs {
+            library.songs.insert(song.id.clone(), song);
+        }
+        return library;
+    }
+
+    fn ids(songs: &[&Song]) -> Vec<String> {
+        let mut ids: Vec<String> = songs.iter().map(|song| song.id.clone()).collect();
+        ids.sort();
+        return ids;
+    }
+
+    // Adds a and b at revision 1, then at revision 2 updates a, removes b
+    // and adds c
+    fn revised_library() -> Library {
+        let mut library = empty_library();
+        library.next_revision();
+        library.add_song(test_song("a", "Artist", "Album"));
+        library.add_song(test_song("b", "Artist", "Album"));
+        library.next_revision();
+        library.update_song(test_song("a", "Artist", "Other album"));
+        library.remove_song("b");
+        library.add_song(test_song("c", "Artist", "Album"));
+        return library;
+    }
+
+    #[test]
+    fn content_hash_ignores_order_songs_were_added_in() {
+        let a = test_song("a", "Artist", "Album");
+        let b = test_song("b", "Artist", "Album");
+        let forwards = library(vec![a.clone(), b.clone()]);
+        let backwards = library(vec![b, a]);
+        assert_eq!(forwards.content_hash(), backwards.content_hash());
+    }
+
+    #[test]
+    fn content_hash_changes_with_songs() {
+        let original = library(vec![test_song("a", "Artist", "Album")]);
+        let mut rated = test_song("a", "Artist", "Album");
+        rated.rating = 5;
+        assert_ne!(original.content_hash(), library(vec![rated]).content_hash());
+        assert_ne!(original.content_hash(), library(Vec::new()).content_hash());
+    }
+
+    #[test]
+    fn changes_since_zero_is_everything() {
+        let library = revised_library();
+        let changes = library.changes_since(0);
+        assert_eq!(changes.revision, 2);
+        assert_eq!(ids(&changes.added), vec!["a", "c"]);
+        assert!(changes.updated.is_empty());
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn changes_since_revision() {
+        let library = revised_library();
+        let changes = library.changes_since(1);
+        assert_eq!(ids(&changes.added), vec!["c"]);
+        assert_eq!(ids(&changes.updated), vec!["a"]);
+        assert_eq!(changes.removed, vec!["b"]);
+    }
+
+    #[test]
+    fn changes_since_current_revision_is_nothing() {
+        let library = revised_library();
+        let changes = library.changes_since(2);
+        assert!(changes.added.is_empty());
+        assert!(changes.updated.is_empty());
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn added_again_is_no_longer_removed() {
+        let mut library = revised_library();
+        library.next_revision();
+        library.add_song(test_song("b", "Artist", "Album"));
+        let changes = library.changes_since(1);
+        assert_eq!(ids(&changes.added), vec!["b", "c"]);
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn combining_records_removed_songs() {
+        let dest = revised_library();
+        let kept = dest.songs.get("a").unwrap().clone();
+        let mut rated = kept.clone();
+        rated.rating = 4;
+        let combined = Library::combine_libraries(&dest, &vec![(rated, kept)], &Vec::new());
+        assert_eq!(combined.revision, 3);
+        let changes = combined.changes_since(2);
+        assert_eq!(ids(&changes.updated), vec!["a"]);
+        assert_eq!(changes.removed, vec!["c"]);
+    }
+
+    #[test]
+    fn combining_without_changes_keeps_the_revision() {
+        let dest = revised_library();
+        let matched: Vec<(Song, Song)> = dest
+            .songs
+            .values()
+            .map(|song| (song.clone(), song.clone()))
+            .collect();
+        let combined = Library::combine_libraries(&dest, &matched, &Vec::new());
+        assert_eq!(combined.revision, 2);
+        let changes = combined.changes_since(2);
+        assert!(changes.updated.is_empty());
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn albums_are_identified_by_artist_and_name() {
+        let album_id = |artist, album| test_song("a", artist, album).album_id();
+        let id = album_id("Artist", "Album").unwrap();
+        assert_eq!(id.len(), 16);
+        assert_eq!(album_id(" artist", "ALBUM "), Some(id.clone()));
+        assert_ne!(album_id("Other", "Album"), Some(id.clone()));
+        assert_ne!(album_id("Artist", "Other"), Some(id));
+        assert_eq!(album_id("Artist", " "), None);
+    }
+}
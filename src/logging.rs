@@ -0,0 +1,90 @@
+extern crate env_logger;
+extern crate log;
+extern crate serde_json;
+
+use env_logger::fmt::Formatter;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Record};
+use serde_json::Map;
+use std::io::{Result, Write};
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::output::Verbosity;
+
+// Adds the key-value pairs of a log record to a JSON object
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> std::result::Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or(serde_json::Value::Null);
+        self.0.insert(key.to_string(), value);
+        return Ok(());
+    }
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> Result<()> {
+    let mut entry = Map::new();
+    entry.insert(
+        "timestamp".into(),
+        buf.timestamp_millis().to_string().into(),
+    );
+    entry.insert("level".into(), record.level().as_str().into());
+    entry.insert("target".into(), record.target().into());
+    entry.insert("message".into(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut JsonFields(&mut entry));
+    return writeln!(buf, "{}", serde_json::Value::Object(entry));
+}
+
+// Sets up logging to stderr, leaving stdout free for command output.
+// The configured level, adjusted by --quiet or --verbose, applies to
+// rhythmical itself while other crates only log warnings. The filter from
+// the config and then RUST_LOG can override this for individual modules.
+pub fn init(config: &LoggingConfig, verbosity: Verbosity) {
+    let level = config.level.parse().unwrap_or(LevelFilter::Info);
+    let level = match verbosity {
+        Verbosity::Quiet => LevelFilter::Error,
+        Verbosity::Normal => level,
+        Verbosity::Verbose => level.max(LevelFilter::Debug),
+    };
+
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_level(LevelFilter::Warn)
+        .filter_module("rhythmical", level)
+        .parse_filters(&config.filter);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if config.format == LogFormat::Json {
+        builder.format(format_json);
+    }
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_fields_as_json() {
+        let fields: [(&str, Value); 3] = [
+            ("status", Value::from(200)),
+            ("path", Value::from("/api/songs")),
+            ("duration_ms", Value::from(1.5)),
+        ];
+        let record = Record::builder().key_values(&fields).build();
+        let mut entry = Map::new();
+        record
+            .key_values()
+            .visit(&mut JsonFields(&mut entry))
+            .unwrap();
+
+        assert_eq!(entry["status"], serde_json::json!(200));
+        assert_eq!(entry["path"], serde_json::json!("/api/songs"));
+        assert_eq!(entry["duration_ms"], serde_json::json!(1.5));
+    }
+}
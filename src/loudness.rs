@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::io::{Error, Result};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::args::AnalyzeLoudnessArgs;
+use crate::config::config;
+use crate::library::{Library, ReplayGain, Song};
+use crate::output;
+use crate::probe::Download;
+use crate::sync_rhythmdb;
+
+// Loudness that ReplayGain 2.0 brings everything to, in LUFS
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+#[derive(Serialize)]
+struct LoudnessSummary {
+    analyzed_songs: usize,
+    failed_songs: usize,
+    // Songs that were read from local files rather than downloaded
+    local_songs: usize,
+    dry_run: bool,
+}
+
+struct Measurement {
+    // Integrated loudness, in LUFS
+    loudness: f64,
+    // True peak, in dBTP
+    peak: f64,
+}
+
+// Finds a value such as "I: -16.7 LUFS" in the summary from ffmpeg's
+// ebur128 filter
+fn summary_value(summary: &str, name: &str) -> Option<f64> {
+    return summary
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(name))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .find_map(|value| value.parse::<f64>().ok());
+}
+
+fn parse_summary(output: &str) -> Option<Measurement> {
+    let summary = &output[output.rfind("Summary:")?..];
+    return Some(Measurement {
+        loudness: summary_value(summary, "I:")?,
+        peak: summary_value(summary, "Peak:")?,
+    });
+}
+
+// Decodes the song with ffmpeg, from the local copy if there is one and
+// from a download otherwise, and measures it following EBU R128. The
+// song is read from disk either way, as some formats need seeking.
+fn measure(song: &Song, local: Option<&str>) -> Result<Measurement> {
+    let download;
+    let input = match local {
+        Some(local) => Path::new(local),
+        None => {
+            download = Download::new(&format!("Music{}", song.file_location))?;
+            download.path.as_path()
+        }
+    };
+
+    let child = Command::new(&config().transcoding.ffmpeg)
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(input)
+        .args([
+            "-vn",
+            "-af",
+            "ebur128=peak=true:framelog=verbose",
+            "-f",
+            "null",
+            "-",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let output = child.wait_with_output()?;
+    let errors = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Result::Err(Error::other(format!(
+            "ffmpeg failed with {}: {}",
+            output.status,
+            errors.trim().lines().last().unwrap_or_default()
+        )));
+    }
+    return parse_summary(&errors).ok_or_else(|| Error::other("ffmpeg didn't report the loudness"));
+}
+
+// Combines the loudness of tracks as if they were played one after the
+// other, weighting each by its length. This is close to measuring the
+// whole album at once without having to decode it again.
+fn album_loudness(tracks: &[(f64, u32)]) -> f64 {
+    let mut energy = 0.0;
+    let mut total_duration = 0.0;
+    for (loudness, duration) in tracks {
+        let duration = (*duration).max(1) as f64;
+        energy += duration * 10f64.powf(loudness / 10.0);
+        total_duration += duration;
+    }
+    return 10.0 * (energy / total_duration).log10();
+}
+
+// Recomputes the album values of every song from its album's tracks,
+// returning the songs that changed. Albums are the same ones the catalog
+// has, so albums with the same title by different artists are kept apart.
+fn with_album_gain(library: &Library) -> Vec<Song> {
+    let mut albums: HashMap<String, Vec<&Song>> = HashMap::new();
+    for song in library.songs.values() {
+        if song.replay_gain.is_none() {
+            continue;
+        }
+        if let Some(album_id) = song.album_id() {
+            albums.entry(album_id).or_default().push(song);
+        }
+    }
+
+    let mut updated_songs: Vec<Song> = Vec::new();
+    for songs in albums.values() {
+        let tracks: Vec<(f64, u32)> = songs
+            .iter()
+            .map(|song| {
+                let replay_gain = song.replay_gain.unwrap();
+                (REFERENCE_LOUDNESS - replay_gain.track_gain, song.duration)
+            })
+            .collect();
+        let album_gain = REFERENCE_LOUDNESS - album_loudness(&tracks);
+        let album_peak = songs
+            .iter()
+            .map(|song| song.replay_gain.unwrap().track_peak)
+            .fold(0.0, f64::max);
+
+        for song in songs {
+            let replay_gain = song.replay_gain.unwrap();
+            let updated = ReplayGain {
+                album_gain,
+                album_peak,
+                ..replay_gain
+            };
+            if updated != replay_gain {
+                let mut song = (*song).clone();
+                song.replay_gain = Some(updated);
+                updated_songs.push(song);
+            }
+        }
+    }
+    return updated_songs;
+}
+
+// Finds local copies of songs, so that they don't have to be downloaded.
+// A music directory mirrors the container's Music directory, while without
+// one the songs are found through the rhythmdb file that syncing uses.
+fn local_paths(library: &Library, music_dir: Option<&str>) -> HashMap<String, String> {
+    let paths = match music_dir {
+        Some(dir) => library
+            .songs
+            .values()
+            .map(|song| {
+                let path = format!("{}{}", dir.trim_end_matches('/'), song.file_location);
+                (song.id.clone(), path)
+            })
+            .collect(),
+        None => sync_rhythmdb::local_paths(library),
+    };
+    return paths
+        .into_iter()
+        .filter(|(_, path)| Path::new(path).is_file())
+        .collect();
+}
+
+pub fn analyze_loudness(args: AnalyzeLoudnessArgs) {
+    let mut library = Library::new();
+    library.next_revision();
+
+    let mut songs: Vec<Song> = library
+        .songs
+        .values()
+        .filter(|song| args.all || song.replay_gain.is_none())
+        .cloned()
+        .collect();
+    songs.sort_by(|a, b| (&a.album, &a.id).cmp(&(&b.album, &b.id)));
+    info!("Found {} songs to analyze", songs.len());
+
+    let local_paths = local_paths(&library, args.music_dir.as_deref());
+    let local_songs = songs
+        .iter()
+        .filter(|song| local_paths.contains_key(&song.id))
+        .count();
+    info!(
+        "Reading {} songs from local files and downloading {}",
+        local_songs,
+        songs.len() - local_songs
+    );
+
+    let mut failed_songs: usize = 0;
+    let mut changed_ids: Vec<String> = Vec::new();
+    for (i, song) in songs.iter().enumerate() {
+        info!(
+            "Analyzing {} ({} / {})",
+            song.file_location,
+            i + 1,
+            songs.len()
+        );
+        let local = local_paths.get(&song.id).map(String::as_str);
+        let measurement = match measure(song, local) {
+            Ok(measurement) => measurement,
+            Err(err) => {
+                error!("Unable to analyze {}: {}", song.file_location, err);
+                failed_songs += 1;
+                continue;
+            }
+        };
+        debug!(
+            "{} is {} LUFS with a peak of {} dBTP",
+            song.file_location, measurement.loudness, measurement.peak
+        );
+
+        // Albums are filled in once all of their songs are known
+        let track_gain = REFERENCE_LOUDNESS - measurement.loudness;
+        let track_peak = 10f64.powf(measurement.peak / 20.0);
+        let mut song = song.clone();
+        song.replay_gain = Some(ReplayGain {
+            track_gain,
+            album_gain: track_gain,
+            track_peak,
+            album_peak: track_peak,
+        });
+        changed_ids.push(song.id.clone());
+        library.update_song(song);
+
+        // Do a checkpoint of our progress so far
+        if (i + 1) % 100 == 0 && !args.dry_run {
+            info!("Uploading library");
+            library.save().unwrap();
+        }
+    }
+
+    // Every album is redone, so that it also covers songs analyzed by
+    // earlier runs that were interrupted
+    for song in with_album_gain(&library) {
+        changed_ids.push(song.id.clone());
+        library.update_song(song);
+    }
+
+    if !args.dry_run {
+        info!("Uploading library");
+        library.save().unwrap();
+    } else {
+        let mut changed_songs: Vec<&Song> = changed_ids
+            .iter()
+            .filter_map(|id| library.songs.get(id))
+            .collect();
+        changed_songs.sort_by(|a, b| (&a.file_location, &a.id).cmp(&(&b.file_location, &b.id)));
+        changed_songs.dedup_by(|a, b| a.id == b.id);
+        for song in changed_songs {
+            let replay_gain = song.replay_gain.unwrap();
+            info!(
+                "Would store a track gain of {:+.2} dB and an album gain of {:+.2} dB for {}",
+                replay_gain.track_gain, replay_gain.album_gain, song.file_location
+            );
+        }
+        info!("Would upload new library");
+    }
+
+    let summary = LoudnessSummary {
+        analyzed_songs: songs.len() - failed_songs,
+        failed_songs,
+        local_songs,
+        dry_run: args.dry_run,
+    };
+    if output::json() {
+        println!("{}", serde_json::to_string(&summary).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::{random_id, test_song};
+
+    const OUTPUT: &str =
+        "[Parsed_ebur128_0 @ 0x1] t: 0.1 TARGET:-23 LUFS M: -120.7 S:-120.7 I: -70.0 LUFS
+[Parsed_ebur128_0 @ 0x1] Summary:
+
+  Integrated loudness:
+    I:         -14.2 LUFS
+    Threshold: -24.5 LUFS
+
+  Loudness range:
+    LRA:         6.1 LU
+
+  True peak:
+    Peak:        0.4 dBFS
+";
+
+    fn song_with_gain(id: &str, album: &str, track_gain: f64, duration: u32) -> Song {
+        let mut song = test_song(id, "Artist", album);
+        song.duration = duration;
+        song.replay_gain = Some(ReplayGain {
+            track_gain,
+            album_gain: track_gain,
+            track_peak: 0.5,
+            album_peak: 0.5,
+        });
+        return song;
+    }
+
+    #[test]
+    fn summary_is_parsed() {
+        let measurement = parse_summary(OUTPUT).unwrap();
+        assert_eq!(measurement.loudness, -14.2);
+        assert_eq!(measurement.peak, 0.4);
+    }
+
+    #[test]
+    fn output_without_a_summary_is_rejected() {
+        assert!(parse_summary("").is_none());
+        assert!(parse_summary(OUTPUT.lines().next().unwrap()).is_none());
+        assert!(parse_summary("Summary:\n  I: -14.2 LUFS\n").is_none());
+    }
+
+    #[test]
+    fn album_loudness_weights_tracks_by_duration() {
+        assert!((album_loudness(&[(-14.0, 100), (-14.0, 300)]) + 14.0).abs() < 1e-9);
+
+        let short_quiet = album_loudness(&[(-10.0, 300), (-20.0, 100)]);
+        let long_quiet = album_loudness(&[(-10.0, 100), (-20.0, 300)]);
+        assert!(short_quiet > long_quiet);
+        assert!(short_quiet < -10.0 && long_quiet > -20.0);
+    }
+
+    #[test]
+    fn album_gain_is_shared_by_the_album() {
+        let mut library = Library {
+            revision: 0,
+            songs: HashMap::new(),
+            removed_songs: HashMap::new(),
+            album_art: HashMap::new(),
+        };
+        for song in [
+            song_with_gain("a", "First", -4.0, 200),
+            song_with_gain("b", "First", -6.0, 200),
+            song_with_gain("c", "Second", -3.0, 200),
+            test_song("d", "Artist", "First"),
+        ] {
+            library.songs.insert(song.id.clone(), song);
+        }
+
+        let mut updated = with_album_gain(&library);
+        updated.sort();
+        assert_eq!(updated.len(), 2);
+        let first = updated[0].replay_gain.unwrap();
+        let second = updated[1].replay_gain.unwrap();
+        assert_eq!(first.album_gain, second.album_gain);
+        assert!(first.album_gain < -4.0 && first.album_gain > -6.0);
+        assert_eq!(first.track_gain, -4.0);
+        assert_eq!(second.track_gain, -6.0);
+
+        // Nothing changes once the album values are filled in
+        for song in updated {
+            library.songs.insert(song.id.clone(), song);
+        }
+        assert!(with_album_gain(&library).is_empty());
+    }
+
+    #[test]
+    fn albums_with_the_same_title_are_kept_apart() {
+        let mut library = Library::default();
+        let mut other = song_with_gain("b", "Greatest Hits", -8.0, 200);
+        other.artist = "Other".to_string();
+        for song in [song_with_gain("a", "Greatest Hits", -4.0, 200), other] {
+            library.songs.insert(song.id.clone(), song);
+        }
+        assert!(with_album_gain(&library).is_empty());
+    }
+
+    #[test]
+    fn local_copies_are_found_in_the_music_directory() {
+        crate::config::init_for_tests();
+        let mut library = Library::default();
+        for song in [
+            test_song("a", "Artist", "Here"),
+            test_song("b", "Artist", "Gone"),
+        ] {
+            library.songs.insert(song.id.clone(), song);
+        }
+        let dir = std::env::temp_dir().join(format!("rhythmical-music-{}", random_id()));
+        std::fs::create_dir_all(dir.join("Artist/Here")).unwrap();
+        std::fs::write(dir.join("Artist/Here/a.mp3"), b"").unwrap();
+
+        let music_dir = format!("{}/", dir.display());
+        let paths = local_paths(&library, Some(&music_dir));
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths["a"], format!("{}/Artist/Here/a.mp3", dir.display()));
+
+        // Without a [sync] section there is nowhere else to look
+        assert!(local_paths(&library, None).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
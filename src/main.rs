@@ -15,31 +15,127 @@ extern crate regex;
 extern crate serde_json;
 extern crate tokio;
 extern crate time;
+extern crate toml;
+extern crate argon2;
+extern crate clap;
+extern crate clap_complete;
+extern crate rpassword;
+extern crate sha2;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+#[macro_use]
+mod output;
 
 mod api;
 mod args;
+mod art;
+mod assets;
+mod auth;
+mod backup;
+mod catalog;
+mod config;
+mod events;
+mod health;
+mod jobs;
 mod library;
+mod logging;
+mod loudness;
+mod metrics;
+mod mime_types;
+mod play_log;
+mod probe;
+mod queue;
 mod server;
+mod sessions;
+mod shutdown;
+mod signed_urls;
+mod stats;
 mod storage;
+mod streaming;
 mod sync_rhythmdb;
+mod tokens;
+mod transcoding;
+mod user_data;
+mod users;
 mod validate_library;
 
-use args::{Args, Mode};
+use args::{print_completions, Args, Mode};
+use config::Config;
+use loudness::analyze_loudness;
+use output::Verbosity;
 use server::start_server;
 use sync_rhythmdb::sync_rhythmdb;
+use tokens::{create_token, list_tokens, revoke_token};
+use users::{add_user, reset_password};
 use validate_library::validate_library;
 
 fn main() {
     let args = Args::get();
+
+    // Completions don't need any config, so they work before setting it up
+    if let Mode::Completions(completions) = args.mode {
+        print_completions(completions);
+        return;
+    }
+
+    let verbosity = if args.quiet {
+        Verbosity::Quiet
+    } else if args.verbose > 0 {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+    output::init(verbosity, args.json);
+
+    let mut config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Unable to load config: {}", error);
+            std::process::exit(1);
+        }
+    };
+    config.apply_args(&args);
+    let errors = config.validate(&args.mode);
+    if !errors.is_empty() {
+        eprintln!("Invalid config:");
+        for error in errors {
+            eprintln!("  {}", error);
+        }
+        std::process::exit(1);
+    }
+    logging::init(&config.logging, verbosity);
+    config::init(config);
+
     match args.mode {
-        Mode::Serve => {
+        Mode::Serve(_) => {
             start_server();
         }
-        Mode::SyncRhythmdb => {
-            sync_rhythmdb(args.sync_rhythmdb.unwrap());
+        Mode::SyncRhythmdb(_) => {
+            sync_rhythmdb();
+        }
+        Mode::ValidateLibrary(validate_library_args) => {
+            validate_library(validate_library_args);
+        }
+        Mode::AnalyzeLoudness(analyze_loudness_args) => {
+            analyze_loudness(analyze_loudness_args);
+        }
+        Mode::AddUser(user_args) => {
+            add_user(user_args);
+        }
+        Mode::ResetPassword(user_args) => {
+            reset_password(user_args);
+        }
+        Mode::CreateToken(create_token_args) => {
+            create_token(create_token_args);
+        }
+        Mode::RevokeToken(revoke_token_args) => {
+            revoke_token(revoke_token_args);
         }
-        Mode::ValidateLibrary => {
-            validate_library(args.validate_library.unwrap());
+        Mode::ListTokens => {
+            list_tokens();
         }
+        Mode::Completions(_) => {}
     }
 }
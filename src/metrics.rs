@@ -0,0 +1,322 @@
+extern crate lazy_static;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds in seconds, the same as the Prometheus client defaults
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Histogram {
+    // Cumulative, so each count includes every smaller bucket
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+lazy_static! {
+    static ref REQUESTS: Mutex<BTreeMap<Labels, Histogram>> = Mutex::new(BTreeMap::new());
+    static ref STORAGE_OPERATIONS: Mutex<BTreeMap<Labels, Histogram>> = Mutex::new(BTreeMap::new());
+    static ref STORAGE_ERRORS: Mutex<BTreeMap<Labels, u64>> = Mutex::new(BTreeMap::new());
+}
+
+static SIGNED_URLS: AtomicU64 = AtomicU64::new(0);
+
+// Values that are read from the server's state when metrics are requested
+pub struct Gauges {
+    pub library_songs: usize,
+    pub playback_sessions: usize,
+    pub login_sessions: usize,
+}
+
+pub fn record_request(route: &str, method: &str, status: u16, duration: Duration) {
+    let labels = vec![
+        ("route", route.to_string()),
+        ("method", method.to_string()),
+        ("status", status.to_string()),
+    ];
+    REQUESTS
+        .lock()
+        .unwrap()
+        .entry(labels)
+        .or_default()
+        .observe(duration);
+}
+
+pub fn record_storage_operation(backend: &str, operation: &str, duration: Duration, ok: bool) {
+    let labels = vec![
+        ("backend", backend.to_string()),
+        ("operation", operation.to_string()),
+    ];
+    if !ok {
+        *STORAGE_ERRORS
+            .lock()
+            .unwrap()
+            .entry(labels.clone())
+            .or_default() += 1;
+    }
+    STORAGE_OPERATIONS
+        .lock()
+        .unwrap()
+        .entry(labels)
+        .or_default()
+        .observe(duration);
+}
+
+pub fn record_signed_url() {
+    SIGNED_URLS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn escape(value: &str) -> String {
+    return value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+}
+
+fn format_labels(labels: &[(&str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    return format!("{{{}}}", labels.join(","));
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_histograms(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &BTreeMap<Labels, Histogram>,
+) {
+    write_header(out, name, "histogram", help);
+    for (labels, histogram) in histograms {
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+            let mut bucket_labels = labels.clone();
+            bucket_labels.push(("le", bound.to_string()));
+            writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                format_labels(&bucket_labels),
+                count
+            )
+            .unwrap();
+        }
+        let mut bucket_labels = labels.clone();
+        bucket_labels.push(("le", "+Inf".to_string()));
+        writeln!(
+            out,
+            "{}_bucket{} {}",
+            name,
+            format_labels(&bucket_labels),
+            histogram.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{}_sum{} {}",
+            name,
+            format_labels(labels),
+            histogram.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{}_count{} {}",
+            name,
+            format_labels(labels),
+            histogram.count
+        )
+        .unwrap();
+    }
+}
+
+// Counters that are also available as a histogram's count are written
+// separately too, as they are easier to query
+fn write_counts(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &BTreeMap<Labels, Histogram>,
+) {
+    write_header(out, name, "counter", help);
+    for (labels, histogram) in histograms {
+        writeln!(out, "{}{} {}", name, format_labels(labels), histogram.count).unwrap();
+    }
+}
+
+fn write_value(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    write_header(out, name, kind, help);
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+// Renders every metric in the Prometheus text format
+pub fn render(gauges: &Gauges) -> String {
+    let mut out = String::new();
+
+    let requests = REQUESTS.lock().unwrap();
+    write_counts(
+        &mut out,
+        "rhythmical_http_requests_total",
+        "HTTP requests handled, by route, method and status.",
+        &requests,
+    );
+    write_histograms(
+        &mut out,
+        "rhythmical_http_request_duration_seconds",
+        "Time taken to handle HTTP requests, by route, method and status.",
+        &requests,
+    );
+    drop(requests);
+
+    let storage_operations = STORAGE_OPERATIONS.lock().unwrap();
+    write_counts(
+        &mut out,
+        "rhythmical_storage_operations_total",
+        "Storage operations, by backend and operation.",
+        &storage_operations,
+    );
+    write_histograms(
+        &mut out,
+        "rhythmical_storage_operation_duration_seconds",
+        "Time taken by storage operations, by backend and operation.",
+        &storage_operations,
+    );
+    drop(storage_operations);
+
+    write_header(
+        &mut out,
+        "rhythmical_storage_operation_errors_total",
+        "counter",
+        "Storage operations that failed, by backend and operation.",
+    );
+    for (labels, count) in STORAGE_ERRORS.lock().unwrap().iter() {
+        writeln!(
+            out,
+            "rhythmical_storage_operation_errors_total{} {}",
+            format_labels(labels),
+            count
+        )
+        .unwrap();
+    }
+
+    write_value(
+        &mut out,
+        "rhythmical_signed_urls_total",
+        "counter",
+        "Signed song URLs handed out to clients.",
+        SIGNED_URLS.load(Ordering::Relaxed),
+    );
+    write_value(
+        &mut out,
+        "rhythmical_library_songs",
+        "gauge",
+        "Songs in the library.",
+        gauges.library_songs as u64,
+    );
+    write_value(
+        &mut out,
+        "rhythmical_playback_sessions",
+        "gauge",
+        "Playback sessions that have reported in recently.",
+        gauges.playback_sessions as u64,
+    );
+    write_value(
+        &mut out,
+        "rhythmical_login_sessions",
+        "gauge",
+        "Logged in browser sessions that haven't expired.",
+        gauges.login_sessions as u64,
+    );
+
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(20));
+        assert_eq!(histogram.buckets[0], 0);
+        assert_eq!(histogram.buckets[2], 1);
+        assert_eq!(histogram.buckets[BUCKETS.len() - 1], 1);
+        assert_eq!(histogram.count, 2);
+        assert!((histogram.sum - 20.02).abs() < 1e-9);
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let labels = vec![
+            ("route", "/a\"b\\c\nd".to_string()),
+            ("method", "GET".to_string()),
+        ];
+        assert_eq!(
+            format_labels(&labels),
+            "{route=\"/a\\\"b\\\\c\\nd\",method=\"GET\"}"
+        );
+        assert_eq!(format_labels(&[]), "");
+    }
+
+    #[test]
+    fn writes_histograms() {
+        let mut histograms: BTreeMap<Labels, Histogram> = BTreeMap::new();
+        histograms
+            .entry(vec![("route", "/api/songs".to_string())])
+            .or_default()
+            .observe(Duration::from_millis(200));
+        let mut out = String::new();
+        write_histograms(&mut out, "test_seconds", "Test.", &histograms);
+
+        assert!(out.starts_with("# HELP test_seconds Test.\n# TYPE test_seconds histogram\n"));
+        assert!(out.contains("test_seconds_bucket{route=\"/api/songs\",le=\"0.1\"} 0\n"));
+        assert!(out.contains("test_seconds_bucket{route=\"/api/songs\",le=\"0.25\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{route=\"/api/songs\",le=\"+Inf\"} 1\n"));
+        assert!(out.contains("test_seconds_count{route=\"/api/songs\"} 1\n"));
+    }
+
+    #[test]
+    fn renders_recorded_metrics() {
+        record_request("/metrics-test", "GET", 200, Duration::from_millis(1));
+        let out = render(&Gauges {
+            library_songs: 12,
+            playback_sessions: 0,
+            login_sessions: 3,
+        });
+        let request = "{route=\"/metrics-test\",method=\"GET\",status=\"200\"}";
+        assert!(out.contains(&format!("rhythmical_http_requests_total{} 1\n", request)));
+        assert!(out.contains("rhythmical_library_songs 12\n"));
+        assert!(out.contains("rhythmical_login_sessions 3\n"));
+    }
+}
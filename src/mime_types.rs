@@ -0,0 +1,32 @@
+use std::path::Path;
+
+// Guesses the MIME type of a file from its extension
+pub fn from_path(path: &str) -> &'static str {
+    let extension = match Path::new(path).extension() {
+        Some(extension) => extension.to_string_lossy().to_lowercase(),
+        None => return "application/octet-stream",
+    };
+    return match extension.as_str() {
+        "html" => "text/html; charset=utf-8",
+        "js" => "application/javascript",
+        "css" => "text/css",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "flac" => "audio/flac",
+        "m4a" | "aac" => "audio/mp4",
+        "wav" => "audio/wav",
+        "webm" => "audio/webm",
+        _ => "application/octet-stream",
+    };
+}
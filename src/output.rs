@@ -0,0 +1,49 @@
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+struct Output {
+    verbosity: Verbosity,
+    json: bool,
+}
+
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+
+pub fn init(verbosity: Verbosity, json: bool) {
+    if OUTPUT.set(Output { verbosity, json }).is_err() {
+        panic!("Output has already been initialised");
+    }
+}
+
+pub fn verbosity() -> Verbosity {
+    return match OUTPUT.get() {
+        Some(output) => output.verbosity,
+        None => Verbosity::Normal,
+    };
+}
+
+pub fn json() -> bool {
+    return match OUTPUT.get() {
+        Some(output) => output.json,
+        None => false,
+    };
+}
+
+// Progress messages are only wanted by a person watching, so they are
+// hidden by --quiet and by --json to keep the output machine readable
+pub fn show_status() -> bool {
+    return verbosity() != Verbosity::Quiet && !json();
+}
+
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::output::show_status() {
+            println!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,267 @@
+extern crate serde_json;
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::storage;
+
+// A play only counts towards a song's play count once at least this much
+// of the song has been listened to.
+const COUNTED_PLAY_FRACTION: f64 = 0.5;
+
+// Logs are compacted once they have this many blocks, well before the
+// 50,000 that Azure allows an append blob
+const COMPACT_AFTER_BLOCKS: u64 = 25_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Play {
+    pub song_id: String,
+    // Unix timestamp, in seconds, of when the song started playing
+    pub started_at: i64,
+    // How much of the song was listened to, between 0 and 1
+    pub fraction: f64,
+}
+
+impl Play {
+    pub fn is_counted(&self) -> bool {
+        return self.fraction >= COUNTED_PLAY_FRACTION;
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PlayStats {
+    pub play_count: u32,
+    pub last_played: Option<i64>,
+}
+
+// Every play that has been recorded, in the order they were recorded.
+// The log is stored separately to the library as one JSON object per line
+// and is only ever appended to.
+pub struct PlayLog {
+    path: String,
+    pub plays: Vec<Play>,
+    stats: HashMap<String, PlayStats>,
+    // How much of the log in storage has been read or appended from here
+    size: u64,
+}
+
+impl PlayLog {
+    pub fn load(path: &str) -> Result<PlayLog> {
+        let mut play_log = PlayLog {
+            path: path.to_string(),
+            plays: Vec::new(),
+            stats: HashMap::new(),
+            size: 0,
+        };
+        if !storage::exists(path)? {
+            return Result::Ok(play_log);
+        }
+
+        let data = storage::cat(path)?;
+        play_log.size = data.len() as u64;
+        let data = String::from_utf8(data).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        for line in data.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let play: Play = serde_json::from_str(line)?;
+            play_log.add(play);
+        }
+        return Result::Ok(play_log);
+    }
+
+    // Appends the play to the log in storage before adding it in memory
+    pub fn record(&mut self, play: Play) -> Result<()> {
+        let mut line = serde_json::to_vec(&play)?;
+        line.push(b'\n');
+        let length = line.len() as u64;
+        storage::append(&self.path, line)?;
+        self.size += length;
+        self.add(play);
+        return Result::Ok(());
+    }
+
+    // Rewrites the log in storage from the plays in memory if it has grown
+    // too many blocks, returning whether it did. Every append adds a block
+    // to the blob, and Azure only allows 50,000 blocks.
+    pub fn compact(&self) -> Result<bool> {
+        if !storage::exists(&self.path)? {
+            return Result::Ok(false);
+        }
+        let metadata = storage::stat(&self.path)?;
+        if metadata.blocks.unwrap_or(0) < COMPACT_AFTER_BLOCKS {
+            return Result::Ok(false);
+        }
+        self.rewrite(&metadata)?;
+        return Result::Ok(true);
+    }
+
+    // Replaces the log in storage with the plays in memory. Nothing is
+    // replaced if the log has plays that aren't in memory, or if any are
+    // appended before the new log is in place, so that none are lost.
+    fn rewrite(&self, metadata: &storage::Metadata) -> Result<()> {
+        if metadata.size != self.size {
+            return Result::Err(Error::other(format!(
+                "{} has plays that haven't been loaded",
+                self.path
+            )));
+        }
+        let mut data: Vec<u8> = Vec::new();
+        for play in &self.plays {
+            data.extend(serde_json::to_vec(play)?);
+            data.push(b'\n');
+        }
+        return storage::put_append(&self.path, data, Some(&metadata.version));
+    }
+
+    fn add(&mut self, play: Play) {
+        if play.is_counted() {
+            let stats = self.stats.entry(play.song_id.clone()).or_default();
+            stats.play_count += 1;
+            stats.last_played = match stats.last_played {
+                Some(last_played) if last_played >= play.started_at => Some(last_played),
+                _ => Some(play.started_at),
+            };
+        }
+        self.plays.push(play);
+    }
+
+    pub fn stats(&self, song_id: &str) -> PlayStats {
+        return self.stats.get(song_id).cloned().unwrap_or_default();
+    }
+
+    // Returns all plays that started within the given range, most recent first.
+    // Both ends of the range are optional and the end is exclusive.
+    pub fn between(&self, from: Option<i64>, to: Option<i64>) -> Vec<&Play> {
+        let mut plays: Vec<&Play> = Vec::new();
+        for play in &self.plays {
+            let after_from = match from {
+                Some(from) => play.started_at >= from,
+                None => true,
+            };
+            let before_to = match to {
+                Some(to) => play.started_at < to,
+                None => true,
+            };
+            if after_from && before_to {
+                plays.push(play);
+            }
+        }
+        plays.sort_by_key(|play| Reverse(play.started_at));
+        return plays;
+    }
+}
+
+#[cfg(test)]
+impl PlayLog {
+    // A log that is only kept in memory
+    pub fn from_plays(plays: Vec<Play>) -> PlayLog {
+        let mut play_log = PlayLog {
+            path: String::new(),
+            plays: Vec::new(),
+            stats: HashMap::new(),
+            size: 0,
+        };
+        for play in plays {
+            play_log.add(play);
+        }
+        return play_log;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(song_id: &str, started_at: i64, fraction: f64) -> Play {
+        return Play {
+            song_id: song_id.to_string(),
+            started_at,
+            fraction,
+        };
+    }
+
+    #[test]
+    fn only_plays_of_half_the_song_count() {
+        assert!(play("a", 0, 0.5).is_counted());
+        assert!(play("a", 0, 1.0).is_counted());
+        assert!(!play("a", 0, 0.49).is_counted());
+    }
+
+    #[test]
+    fn stats_count_counted_plays() {
+        let play_log = PlayLog::from_plays(vec![
+            play("a", 300, 1.0),
+            play("a", 100, 0.9),
+            play("a", 500, 0.1),
+            play("b", 200, 0.2),
+        ]);
+        let stats = play_log.stats("a");
+        assert_eq!(stats.play_count, 2);
+        assert_eq!(stats.last_played, Some(300));
+
+        let stats = play_log.stats("b");
+        assert_eq!(stats.play_count, 0);
+        assert_eq!(stats.last_played, None);
+        assert_eq!(play_log.stats("c").play_count, 0);
+    }
+
+    #[test]
+    fn between_is_most_recent_first() {
+        let play_log = PlayLog::from_plays(vec![
+            play("a", 100, 1.0),
+            play("b", 300, 1.0),
+            play("c", 200, 0.1),
+        ]);
+        let ids = |plays: Vec<&Play>| -> Vec<String> {
+            return plays.iter().map(|play| play.song_id.clone()).collect();
+        };
+        assert_eq!(ids(play_log.between(None, None)), vec!["b", "c", "a"]);
+        assert_eq!(ids(play_log.between(Some(200), None)), vec!["b", "c"]);
+        assert_eq!(ids(play_log.between(None, Some(200))), vec!["a"]);
+        assert_eq!(ids(play_log.between(Some(100), Some(300))), vec!["c", "a"]);
+    }
+
+    #[test]
+    fn compacting_keeps_every_play() {
+        crate::config::init_for_tests();
+        let path = "users/compact-test/plays.log";
+        let mut play_log = PlayLog::load(path).unwrap();
+        play_log.record(play("a", 100, 1.0)).unwrap();
+        play_log.record(play("b", 200, 0.2)).unwrap();
+        // Files on disk don't have blocks, so they never need compacting
+        assert!(!play_log.compact().unwrap());
+        play_log.rewrite(&storage::stat(path).unwrap()).unwrap();
+        assert!(!storage::exists(&format!("{}.partial", path)).unwrap());
+        play_log.record(play("c", 300, 1.0)).unwrap();
+
+        let loaded = PlayLog::load(path).unwrap();
+        let ids: Vec<&str> = loaded.plays.iter().map(|p| p.song_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+        assert_eq!(loaded.stats("a").play_count, 1);
+    }
+
+    #[test]
+    fn compacting_never_drops_plays_it_has_not_seen() {
+        crate::config::init_for_tests();
+        let path = "users/compact-race-test/plays.log";
+        let mut play_log = PlayLog::load(path).unwrap();
+        play_log.record(play("a", 100, 1.0)).unwrap();
+        let metadata = storage::stat(path).unwrap();
+
+        // Recorded by someone else after the log was loaded
+        let mut other = PlayLog::load(path).unwrap();
+        other.record(play("b", 200, 1.0)).unwrap();
+        assert!(play_log.rewrite(&storage::stat(path).unwrap()).is_err());
+
+        // Recorded while the log was being compacted
+        play_log = PlayLog::load(path).unwrap();
+        assert!(play_log.rewrite(&metadata).is_err());
+        assert!(!storage::exists(&format!("{}.partial", path)).unwrap());
+
+        let loaded = PlayLog::load(path).unwrap();
+        let ids: Vec<&str> = loaded.plays.iter().map(|p| p.song_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+    }
+}
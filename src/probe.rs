@@ -0,0 +1,235 @@
+use std::fs::{self, File};
+use std::io::{self, Error, Read, Result};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::config::config;
+use crate::library::{random_string, AudioProperties};
+use crate::storage;
+use crate::streaming::RangeReader;
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    streams: Vec<FfprobeStream>,
+}
+
+// ffprobe gives most numbers as strings, and leaves out what it can't tell
+#[derive(Deserialize)]
+struct FfprobeStream {
+    codec_name: String,
+    sample_rate: Option<String>,
+    channels: Option<u32>,
+    bit_rate: Option<String>,
+}
+
+pub struct Probe {
+    pub properties: AudioProperties,
+    // Length of the decoded audio, in seconds
+    pub duration: f64,
+    // Problems ffmpeg found while decoding, such as corrupt frames
+    pub errors: Vec<String>,
+}
+
+// A song downloaded from storage to a temporary file, which is deleted
+// when dropped. ffprobe and ffmpeg read it from disk rather than a pipe as
+// some formats, such as MP4 with its index at the end, need to seek.
+pub struct Download {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl Download {
+    pub fn new(path: &str) -> Result<Download> {
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+        let local =
+            std::env::temp_dir().join(format!("rhythmical-{}{}", random_string(16), extension));
+        let mut file = File::create(&local)?;
+        let mut download = Download {
+            path: local,
+            size: 0,
+        };
+        let size = storage::stat(path)?.size;
+        io::copy(&mut RangeReader::new(path, 0, size), &mut file)?;
+        download.size = size;
+        return Ok(download);
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read_header(path: &Path) -> Result<FfprobeStream> {
+    let output = Command::new(&config().transcoding.ffprobe)
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "a:0",
+            "-show_entries",
+            "stream=codec_name,sample_rate,channels,bit_rate",
+            "-of",
+            "json",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Result::Err(Error::other(format!(
+            "unreadable header: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let probed: FfprobeOutput = serde_json::from_slice(&output.stdout)?;
+    return probed
+        .streams
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::other("no audio stream"));
+}
+
+// Decodes all of the song, returning how long it is and any errors
+fn decode(path: &Path) -> Result<(f64, Vec<String>)> {
+    let mut child = Command::new(&config().transcoding.ffmpeg)
+        .args(["-v", "error", "-nostats", "-i"])
+        .arg(path)
+        .args(["-map", "0:a:0", "-f", "null", "-", "-progress", "pipe:1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Errors are read as decoding goes, so that ffmpeg never blocks on a
+    // full pipe while progress is being read
+    let mut stderr = child.stderr.take().unwrap();
+    let errors = thread::spawn(move || {
+        let mut errors = String::new();
+        let _ = stderr.read_to_string(&mut errors);
+        return errors;
+    });
+    let mut progress = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut progress)?;
+    let status = child.wait()?;
+    let errors: Vec<String> = errors
+        .join()
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+    if !status.success() {
+        return Result::Err(Error::other(format!(
+            "unable to decode: {}",
+            errors.last().cloned().unwrap_or_else(|| status.to_string())
+        )));
+    }
+
+    let duration = progress_duration(&progress)
+        .ok_or_else(|| Error::other("ffmpeg didn't report the duration"))?;
+    return Ok((duration, errors));
+}
+
+// Progress is reported as key=value lines, ending with the total
+fn progress_duration(progress: &str) -> Option<f64> {
+    let microseconds = progress
+        .lines()
+        .filter_map(|line| line.strip_prefix("out_time_us="))
+        .filter_map(|value| value.trim().parse::<u64>().ok())
+        .next_back()?;
+    return Some(microseconds as f64 / 1_000_000.0);
+}
+
+fn audio_properties(header: FfprobeStream, size: u64, duration: f64) -> AudioProperties {
+    // Files with a variable bitrate don't have one in the header
+    let bitrate = match header.bit_rate.and_then(|rate| rate.parse::<u64>().ok()) {
+        Some(bitrate) => bitrate,
+        None if duration > 0.0 => (size as f64 * 8.0 / duration) as u64,
+        None => 0,
+    };
+    return AudioProperties {
+        codec: header.codec_name,
+        bitrate: (bitrate / 1000) as u32,
+        sample_rate: header
+            .sample_rate
+            .and_then(|rate| rate.parse().ok())
+            .unwrap_or(0),
+        channels: header.channels.unwrap_or(0),
+    };
+}
+
+// Reads the song's format from its header and then decodes all of it,
+// which finds files that are corrupt or cut short
+pub fn probe(path: &str) -> Result<Probe> {
+    let download = Download::new(path)?;
+    let size = download.size;
+    let header = read_header(&download.path)?;
+    let (duration, errors) = decode(&download.path)?;
+
+    return Ok(Probe {
+        properties: audio_properties(header, size, duration),
+        duration,
+        errors,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(json: &str) -> FfprobeStream {
+        let output: FfprobeOutput = serde_json::from_str(json).unwrap();
+        return output.streams.into_iter().next().unwrap();
+    }
+
+    #[test]
+    fn duration_is_the_last_progress_reported() {
+        let progress =
+            "out_time_us=1000000\nprogress=continue\nout_time_us=183500000\nprogress=end\n";
+        assert_eq!(progress_duration(progress), Some(183.5));
+        assert_eq!(progress_duration("out_time_us=N/A\nprogress=end\n"), None);
+        assert_eq!(progress_duration(""), None);
+    }
+
+    #[test]
+    fn properties_are_read_from_the_header() {
+        let header = stream(
+            r#"{"streams": [{"codec_name": "mp3", "sample_rate": "44100", "channels": 2, "bit_rate": "320000"}]}"#,
+        );
+        let properties = audio_properties(header, 1000, 10.0);
+        assert_eq!(properties.codec, "mp3");
+        assert_eq!(properties.bitrate, 320);
+        assert_eq!(properties.sample_rate, 44100);
+        assert_eq!(properties.channels, 2);
+    }
+
+    #[test]
+    fn variable_bitrates_come_from_the_size() {
+        let header = stream(r#"{"streams": [{"codec_name": "vorbis"}]}"#);
+        let properties = audio_properties(header, 2_000_000, 100.0);
+        assert_eq!(properties.bitrate, 160);
+        assert_eq!(properties.sample_rate, 0);
+        assert_eq!(properties.channels, 0);
+
+        let header = stream(r#"{"streams": [{"codec_name": "vorbis"}]}"#);
+        assert_eq!(audio_properties(header, 2_000_000, 0.0).bitrate, 0);
+    }
+
+    #[test]
+    fn downloads_keep_the_extension_until_dropped() {
+        crate::config::init_for_tests();
+        storage::put("Music/probe-test/song.m4a", b"audio".to_vec()).unwrap();
+        let download = Download::new("Music/probe-test/song.m4a").unwrap();
+        let path = download.path.clone();
+        assert_eq!(path.extension().unwrap(), "m4a");
+        assert_eq!(download.size, 5);
+        assert_eq!(fs::read(&path).unwrap(), b"audio");
+        drop(download);
+        assert!(!path.exists());
+    }
+}
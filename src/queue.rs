@@ -0,0 +1,379 @@
+extern crate rand;
+extern crate serde_json;
+
+use rand::{rng, Rng};
+use std::collections::{HashMap, HashSet};
+use std::io::Result;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+use crate::library::{Library, Song};
+use crate::storage;
+use crate::user_data::UserData;
+
+// Songs that have never been played, or not for this long, are treated as
+// equally overdue when weighting by time since last played.
+const MAX_DAYS_SINCE_PLAYED: f64 = 365.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShuffleStrategy {
+    // Every song is equally likely
+    Uniform,
+    // Higher rated songs are more likely, with unrated songs treated as average
+    Rating,
+    // Songs that haven't been played for longer are more likely
+    LeastRecentlyPlayed,
+}
+
+impl ShuffleStrategy {
+    fn weight(&self, song: &Song, user_data: &UserData, now: i64) -> f64 {
+        return match self {
+            ShuffleStrategy::Uniform => 1.0,
+            ShuffleStrategy::Rating => {
+                let rating = match user_data.rating(song) {
+                    0 => 3,
+                    rating => rating,
+                };
+                (rating * rating) as f64
+            }
+            ShuffleStrategy::LeastRecentlyPlayed => {
+                match user_data.play_log.stats(&song.id).last_played {
+                    Some(last_played) => {
+                        let days = (now - last_played).max(0) as f64 / (24.0 * 60.0 * 60.0);
+                        1.0 + days.min(MAX_DAYS_SINCE_PLAYED)
+                    }
+                    None => 1.0 + MAX_DAYS_SINCE_PLAYED,
+                }
+            }
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Queue {
+    pub song_ids: Vec<String>,
+    pub strategy: ShuffleStrategy,
+    pub spread_artists: bool,
+    // Unix timestamp of when the queue was last changed
+    pub updated_at: i64,
+}
+
+impl Queue {
+    fn empty() -> Queue {
+        return Queue {
+            song_ids: Vec::new(),
+            strategy: ShuffleStrategy::Rating,
+            spread_artists: true,
+            updated_at: 0,
+        };
+    }
+
+    // Drops any queued songs that aren't candidates, then tops the queue up
+    // to the given length with a weighted shuffle of the remaining candidates.
+    pub fn fill(
+        &mut self,
+        library: &Library,
+        user_data: &UserData,
+        candidates: &[String],
+        length: usize,
+    ) {
+        let candidate_ids: HashSet<&String> = candidates.iter().collect();
+        self.song_ids.retain(|id| candidate_ids.contains(id));
+
+        let queued: HashSet<String> = self.song_ids.iter().cloned().collect();
+        let remaining: Vec<&Song> = candidates
+            .iter()
+            .filter(|id| !queued.contains(*id))
+            .filter_map(|id| library.songs.get(id))
+            .collect();
+
+        let needed = length.saturating_sub(self.song_ids.len());
+        let mut picked = weighted_shuffle(remaining, self.strategy, user_data, needed);
+        if self.spread_artists {
+            let previous_artist = self
+                .song_ids
+                .last()
+                .and_then(|id| library.songs.get(id))
+                .map(|song| song.artist.clone());
+            picked = spread_artists(picked, previous_artist);
+        }
+        for song in picked {
+            self.song_ids.push(song.id.clone());
+        }
+        self.updated_at = OffsetDateTime::now_utc().unix_timestamp();
+    }
+}
+
+// Picks up to n songs at random, in order, with each song's chance of being
+// picked proportional to its weight. Uses the method of Efraimidis and
+// Spirakis, which gives each song a key of u^(1/weight) and takes the
+// largest keys.
+fn weighted_shuffle<'a>(
+    songs: Vec<&'a Song>,
+    strategy: ShuffleStrategy,
+    user_data: &UserData,
+    n: usize,
+) -> Vec<&'a Song> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let mut rng = rng();
+    let mut keyed: Vec<(f64, &Song)> = songs
+        .into_iter()
+        .map(|song| {
+            let weight = strategy.weight(song, user_data, now).max(f64::MIN_POSITIVE);
+            let u: f64 = rng.random();
+            (u.powf(1.0 / weight), song)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    keyed.truncate(n);
+    return keyed.into_iter().map(|(_, song)| song).collect();
+}
+
+// Reorders the songs so that, where possible, no song is followed by another
+// song by the same artist. Otherwise the original order is kept.
+fn spread_artists(songs: Vec<&Song>, previous_artist: Option<String>) -> Vec<&Song> {
+    let mut remaining = songs;
+    let mut spread: Vec<&Song> = Vec::new();
+    let mut previous_artist = previous_artist;
+    while !remaining.is_empty() {
+        let index = remaining
+            .iter()
+            .position(|song| previous_artist.as_ref() != Some(&song.artist))
+            .unwrap_or(0);
+        let song = remaining.remove(index);
+        previous_artist = Some(song.artist.clone());
+        spread.push(song);
+    }
+    return spread;
+}
+
+// Each user's queue, which is persisted to storage so that playback can be
+// resumed from anywhere. The songs the queue is filled from are kept apart
+// from it, as they are only sent when the client's filter changes and can
+// be the whole library.
+pub struct QueueStore {
+    queues: Mutex<HashMap<String, Queue>>,
+    // None when the queue is filled from the whole library
+    candidates: Mutex<HashMap<String, Option<Vec<String>>>>,
+}
+
+impl QueueStore {
+    pub fn new() -> QueueStore {
+        return QueueStore {
+            queues: Mutex::new(HashMap::new()),
+            candidates: Mutex::new(HashMap::new()),
+        };
+    }
+
+    fn path(user: &str) -> String {
+        return format!("queues/{}.json", user);
+    }
+
+    // User names can't contain dots, so this never clashes with a queue
+    fn candidates_path(user: &str) -> String {
+        return format!("queues/{}.candidates.json", user);
+    }
+
+    pub fn candidates(&self, user: &str) -> Result<Option<Vec<String>>> {
+        let mut candidates = self.candidates.lock().unwrap();
+        if let Some(user_candidates) = candidates.get(user) {
+            return Result::Ok(user_candidates.clone());
+        }
+
+        let path = QueueStore::candidates_path(user);
+        let user_candidates = if storage::exists(&path)? {
+            Some(serde_json::from_slice(&storage::cat(&path)?)?)
+        } else {
+            None
+        };
+        candidates.insert(user.to_string(), user_candidates.clone());
+        return Result::Ok(user_candidates);
+    }
+
+    pub fn set_candidates(&self, user: &str, candidates: Vec<String>) -> Result<()> {
+        let data = serde_json::to_vec(&candidates)?;
+        storage::put(&QueueStore::candidates_path(user), data)?;
+        self.candidates
+            .lock()
+            .unwrap()
+            .insert(user.to_string(), Some(candidates));
+        return Result::Ok(());
+    }
+
+    pub fn get(&self, user: &str) -> Result<Queue> {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get(user) {
+            return Result::Ok(queue.clone());
+        }
+
+        let path = QueueStore::path(user);
+        let queue = if storage::exists(&path)? {
+            serde_json::from_slice(&storage::cat(&path)?)?
+        } else {
+            Queue::empty()
+        };
+        queues.insert(user.to_string(), queue.clone());
+        return Result::Ok(queue);
+    }
+
+    pub fn set(&self, user: &str, queue: Queue) -> Result<()> {
+        let data = serde_json::to_vec(&queue)?;
+        storage::put(&QueueStore::path(user), data)?;
+        self.queues.lock().unwrap().insert(user.to_string(), queue);
+        return Result::Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::test_song;
+    use crate::user_data::test_user_data;
+
+    fn library(songs: Vec<Song>) -> Library {
+        let mut library = Library {
+            revision: 0,
+            songs: HashMap::new(),
+            removed_songs: HashMap::new(),
+            album_art: HashMap::new(),
+        };
+        for song in songs {
+            library.songs.insert(song.id.clone(), song);
+        }
+        return library;
+    }
+
+    fn ids(songs: &[&Song]) -> Vec<String> {
+        return songs.iter().map(|song| song.id.clone()).collect();
+    }
+
+    #[test]
+    fn weighted_shuffle_picks_distinct_songs() {
+        let library = library(vec![
+            test_song("a", "A", "Album"),
+            test_song("b", "B", "Album"),
+            test_song("c", "C", "Album"),
+        ]);
+        let user_data = test_user_data(Vec::new());
+        let songs: Vec<&Song> = library.songs.values().collect();
+
+        let picked = weighted_shuffle(songs.clone(), ShuffleStrategy::Uniform, &user_data, 2);
+        assert_eq!(picked.len(), 2);
+        assert_ne!(picked[0].id, picked[1].id);
+
+        let mut all = ids(&weighted_shuffle(
+            songs,
+            ShuffleStrategy::Uniform,
+            &user_data,
+            10,
+        ));
+        all.sort();
+        assert_eq!(all, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn weighted_shuffle_prefers_heavier_songs() {
+        let library = library(vec![
+            test_song("loved", "A", "Album"),
+            test_song("disliked", "B", "Album"),
+        ]);
+        let mut user_data = test_user_data(Vec::new());
+        user_data.ratings.insert("loved".to_string(), 5);
+        user_data.ratings.insert("disliked".to_string(), 1);
+        let songs: Vec<&Song> = library.songs.values().collect();
+
+        // The loved song weighs 25 times as much, so comes first 25 times
+        // in 26 on average
+        let first_loved = (0..1000)
+            .filter(|_| {
+                let picked =
+                    weighted_shuffle(songs.clone(), ShuffleStrategy::Rating, &user_data, 1);
+                picked[0].id == "loved"
+            })
+            .count();
+        assert!(
+            first_loved > 900,
+            "loved song came first {} times",
+            first_loved
+        );
+    }
+
+    #[test]
+    fn rating_weights_treat_unrated_songs_as_average() {
+        let song = test_song("a", "A", "Album");
+        let user_data = test_user_data(Vec::new());
+        assert_eq!(ShuffleStrategy::Rating.weight(&song, &user_data, 0), 9.0);
+    }
+
+    #[test]
+    fn least_recently_played_weights() {
+        let song = test_song("a", "A", "Album");
+        let now = 400 * 24 * 60 * 60;
+        let never_played = test_user_data(Vec::new());
+        let just_played = test_user_data(vec![crate::play_log::Play {
+            song_id: "a".to_string(),
+            started_at: now,
+            fraction: 1.0,
+        }]);
+        let strategy = ShuffleStrategy::LeastRecentlyPlayed;
+        assert_eq!(strategy.weight(&song, &never_played, now), 366.0);
+        assert_eq!(strategy.weight(&song, &just_played, now), 1.0);
+    }
+
+    #[test]
+    fn spread_artists_avoids_repeats() {
+        let a1 = test_song("a1", "A", "Album");
+        let a2 = test_song("a2", "A", "Album");
+        let b1 = test_song("b1", "B", "Album");
+        assert_eq!(
+            ids(&spread_artists(vec![&a1, &a2, &b1], None)),
+            vec!["a1", "b1", "a2"]
+        );
+        assert_eq!(
+            ids(&spread_artists(vec![&a1, &a2, &b1], Some("A".to_string()))),
+            vec!["b1", "a1", "a2"]
+        );
+    }
+
+    #[test]
+    fn fill_keeps_only_candidates() {
+        let library = library(vec![
+            test_song("a", "A", "Album"),
+            test_song("b", "B", "Album"),
+            test_song("c", "C", "Album"),
+            test_song("d", "D", "Album"),
+        ]);
+        let user_data = test_user_data(Vec::new());
+        let mut queue = Queue::empty();
+        queue.song_ids = vec!["a".to_string(), "b".to_string()];
+
+        let candidates: Vec<String> = vec!["b".to_string(), "c".to_string(), "d".to_string()];
+        queue.fill(&library, &user_data, &candidates, 3);
+        assert_eq!(queue.song_ids[0], "b");
+        let mut queued = queue.song_ids.clone();
+        queued.sort();
+        assert_eq!(queued, vec!["b", "c", "d"]);
+    }
+
+    #[test]
+    fn candidates_are_remembered_per_user() {
+        crate::config::init_for_tests();
+        let store = QueueStore::new();
+        assert_eq!(store.candidates("candidates-test").unwrap(), None);
+
+        let candidates = vec!["a".to_string(), "b".to_string()];
+        store
+            .set_candidates("candidates-test", candidates.clone())
+            .unwrap();
+        assert_eq!(
+            store.candidates("candidates-test").unwrap(),
+            Some(candidates.clone())
+        );
+        assert_eq!(
+            QueueStore::new().candidates("candidates-test").unwrap(),
+            Some(candidates)
+        );
+        assert_eq!(store.candidates("other-test").unwrap(), None);
+    }
+}
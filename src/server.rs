@@ -1,49 +1,480 @@
+extern crate regex;
 extern crate rouille;
 
-use rouille::{Request, Response};
-use std::env;
+use regex::Regex;
+use rouille::{Request, Response, ResponseBody};
 use std::fs::File;
 use std::io::Read;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::api::Api;
+use crate::assets;
+use crate::auth::Auth;
+use crate::backup::backup;
+use crate::config::config;
+use crate::health::{self, Health};
+use crate::jobs::JobRunner;
+use crate::library::random_string;
+use crate::metrics::{self, Gauges};
+use crate::shutdown::watch_for_signals;
 
-fn root() -> Response {
-    let current_dir = env::current_dir().unwrap();
-    let index_html_path = current_dir.join("frontend/public/index.html");
-    let mut index_html = File::open(index_html_path).unwrap();
-    let mut index_html_contents = String::new();
-    index_html.read_to_string(&mut index_html_contents).unwrap();
-    return Response::html(index_html_contents);
+#[derive(Serialize)]
+struct ApiReload {
+    songs: usize,
 }
 
-fn app_js() -> Response {
-    let mut out_js = File::open(concat!(env!("OUT_DIR"), "/app.js")).unwrap();
-    let mut out_js_contents = String::new();
-    out_js.read_to_string(&mut out_js_contents).unwrap();
-    return Response::from_data("application/javascript", out_js_contents);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref ID_SEGMENT_REGEX: Regex =
+        Regex::new(r"^/api/(songs|sessions|playlists|tokens|albums|artists)/[^/]+").unwrap();
 }
 
-pub fn start_server() {
-    let address = "localhost:8000";
-    println!("Serving at {}", address);
+// Compresses the response body if the client accepts it. Responses that
+// aren't a successful 200 are left alone so that a 304 stays empty, as are
+// event streams, which the encoder would hold back.
+fn compress(request: &Request, response: Response) -> Response {
+    let event_stream = response.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("Content-Type") && value == "text/event-stream"
+    });
+    if response.status_code != 200 || event_stream {
+        return response;
+    }
+    let response = response.with_additional_header("Vary", "Accept-Encoding");
+    return rouille::content_encoding::apply(request, response);
+}
 
-    let api = Api::new();
+fn metrics_response(api: &Api, auth: &Auth) -> Response {
+    let gauges = Gauges {
+        library_songs: api.library_size(),
+        playback_sessions: api.session_count(),
+        login_sessions: auth.login_session_count(),
+    };
+    return Response::from_data("text/plain; version=0.0.4", metrics::render(&gauges));
+}
 
-    rouille::start_server(address, move |request: &Request| {
-        println!("Processing request for {}", request.url());
+// Groups requests by what they ask for rather than by their exact path, so
+// that every id doesn't get its own metrics. Paths that weren't found, or
+// were asked for without logging in, are grouped together for the same
+// reason.
+fn route_label(request: &Request, status: u16) -> String {
+    if status == 404 {
+        return "unmatched".to_string();
+    }
+    if status == 401 {
+        return "unauthenticated".to_string();
+    }
+    return ID_SEGMENT_REGEX
+        .replace(&request.url(), "/api/$1/{id}")
+        .to_string();
+}
 
-        if request.url().eq("/") {
-            return root();
+// Reloads the library straight away, rather than waiting for the next check
+fn reload_library(api: &Api, health: &Health) -> Response {
+    return match api.reload_library(true) {
+        Ok(_) => {
+            health.record_loaded();
+            info!("Reloaded library with {} songs", api.library_size());
+            Response::json(&ApiReload {
+                songs: api.library_size(),
+            })
         }
+        Err(error) => {
+            health.record_error(&error);
+            Response::text(format!("Unable to reload library: {}", error)).with_status_code(500)
+        }
+    };
+}
 
-        if request.url().eq("/app.js") {
-            return app_js();
+// Requests that need the admin scope, which has already been checked
+fn route_admin(request: &Request, api: &Api, health: &Health, jobs: &JobRunner) -> Option<Response> {
+    if request.url().eq("/api/admin/reload") && request.method() == "POST" {
+        return Some(reload_library(api, health));
+    }
+    if request.url().eq("/api/admin/jobs") && request.method() == "GET" {
+        return Some(Response::json(&jobs.statuses()));
+    }
+    return None;
+}
+
+// Authenticates and authorizes API requests before passing them on
+fn route_api(
+    request: &Request,
+    auth: &Auth,
+    api: &Api,
+    health: &Health,
+    jobs: &JobRunner,
+) -> Response {
+    if let Some(response) = auth.route_auth(request) {
+        return response;
+    }
+    let user = match auth.authenticate(request) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    if let Err(response) = auth.authorize(request, &user) {
+        return response;
+    }
+    if let Some(response) = auth.route_tokens(request, &user) {
+        return response;
+    }
+    if let Some(response) = route_admin(request, api, health, jobs) {
+        return response;
+    }
+    return api.route_api(request, &user);
+}
+
+// Sends every request on to the same path on the HTTPS server
+fn redirect_to_https(request: &Request, https_port: u16) -> Response {
+    let host = match request.header("Host") {
+        Some(host) => host.split(':').next().unwrap_or(host).to_string(),
+        None => return Response::text("Missing Host header").with_status_code(400),
+    };
+    let port = if https_port == 443 {
+        String::new()
+    } else {
+        format!(":{}", https_port)
+    };
+    return Response::redirect_301(format!("https://{}{}{}", host, port, request.raw_url()));
+}
+
+// Writes one line per request with how it was answered. The request id
+// is also returned to the client so that a report can be matched up with
+// the log.
+fn log_access(request: &Request, response: Response, id: &str, duration: Duration) -> Response {
+    let mut response = response;
+    let (reader, size) = response.data.into_reader_and_size();
+    response.data = match size {
+        Some(size) => ResponseBody::from_reader_and_size(reader, size),
+        None => ResponseBody::from_reader(reader),
+    };
+    // Streamed responses don't know their length up front
+    let bytes = size.map(|size| size as u64);
+    let duration_ms = (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0;
+
+    info!(
+        target: "rhythmical::access",
+        request_id = id,
+        method = request.method(),
+        path = request.raw_url(),
+        status = response.status_code,
+        bytes = bytes,
+        duration_ms = duration_ms,
+        remote_addr = request.remote_addr().to_string().as_str();
+        "{} {} {} {} {} {:.1}ms",
+        request.remote_addr(),
+        request.method(),
+        request.raw_url(),
+        response.status_code,
+        bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+        duration_ms
+    );
+    return response.with_unique_header("X-Request-Id", id.to_string());
+}
+
+// Loads the library, users and tokens, returning whether it worked
+fn load(api: &Api, auth: &Auth, health: &Health) -> bool {
+    return match api.reload_library(true).and_then(|_| auth.load()) {
+        Ok(()) => {
+            health.record_loaded();
+            info!("Loaded library with {} songs", api.library_size());
+            true
+        }
+        Err(error) => {
+            health.record_error(&error);
+            warn!("Unable to load library: {}", error);
+            false
         }
+    };
+}
 
-        if request.url().starts_with("/api") {
-            return api.route_api(request);
+fn retry_load(api: &Api, auth: &Auth, health: &Health) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        info!("Retrying in {}s", delay.as_secs());
+        thread::sleep(delay);
+        if load(api, auth, health) {
+            return;
         }
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
 
-        return Response::empty_404();
-    });
+// Picks up changes made to the library by syncing or validation
+fn check_library(api: &Api, health: &Health) -> std::io::Result<String> {
+    // Until the library first loads, retry_load is in charge of it
+    if !health.is_loaded() {
+        return Ok("The library hasn't been loaded yet".to_string());
+    }
+    return match api.reload_library(false) {
+        Ok(true) => {
+            health.record_loaded();
+            info!("Reloaded library with {} songs", api.library_size());
+            Ok(format!("Reloaded library with {} songs", api.library_size()))
+        }
+        Ok(false) => {
+            health.record_checked();
+            Ok("The library is up to date".to_string())
+        }
+        Err(error) => {
+            health.record_error(&error);
+            Err(error)
+        }
+    };
+}
+
+// Picks up users and tokens changed from the command line
+fn check_auth(auth: &Auth, health: &Health) -> std::io::Result<String> {
+    if !health.is_loaded() {
+        return Ok("The users haven't been loaded yet".to_string());
+    }
+    let mut reloaded = Vec::new();
+    if auth.reload_users(false)? {
+        reloaded.push("users");
+    }
+    if auth.reload_tokens(false)? {
+        reloaded.push("tokens");
+    }
+    if reloaded.is_empty() {
+        return Ok("The users and tokens are up to date".to_string());
+    }
+    let message = format!("Reloaded {}", reloaded.join(" and "));
+    info!("{}", message);
+    return Ok(message);
+}
+
+// Sets off the server's periodic jobs
+fn add_jobs(jobs: &JobRunner, api: &Arc<Api>, auth: &Arc<Auth>, health: &Arc<Health>) {
+    let server_config = &config().server;
+    let jobs_config = &config().jobs;
+
+    let (job_api, job_health) = (api.clone(), health.clone());
+    jobs.add(
+        "reload-library",
+        Duration::from_secs(server_config.library_reload_seconds),
+        move || check_library(&job_api, &job_health),
+    );
+
+    let (job_auth, job_health) = (auth.clone(), health.clone());
+    jobs.add(
+        "reload-auth",
+        Duration::from_secs(server_config.library_reload_seconds),
+        move || check_auth(&job_auth, &job_health),
+    );
+
+    let backups_kept = jobs_config.backups_kept;
+    jobs.add(
+        "backup",
+        Duration::from_secs(jobs_config.backup_hours * 60 * 60),
+        move || backup(backups_kept).map(|path| format!("Backed up to {}", path)),
+    );
+
+    let job_api = api.clone();
+    jobs.add(
+        "compact-play-logs",
+        Duration::from_secs(jobs_config.compact_play_logs_hours * 60 * 60),
+        move || {
+            job_api
+                .compact_play_logs()
+                .map(|count| format!("Compacted {} play logs", count))
+        },
+    );
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .unwrap_or_else(|error| panic!("Unable to read {}: {}", path, error));
+    return contents;
+}
+
+pub fn start_server() {
+    let server_config = &config().server;
+    let address = (server_config.address.clone(), server_config.port);
+    let tls = server_config.tls_cert.is_some();
+
+    let api = Arc::new(Api::new());
+    let auth = Arc::new(Auth::new(tls));
+    let health = Arc::new(Health::new());
+
+    let jobs = Arc::new(JobRunner::new());
+
+    // Serve health checks even when storage is down, and keep trying to
+    // load everything in the background until it works
+    if !load(&api, &auth, &health) {
+        let (api, auth, health) = (api.clone(), auth.clone(), health.clone());
+        thread::spawn(move || retry_load(&api, &auth, &health));
+    }
+    add_jobs(&jobs, &api, &auth, &health);
+    let handler_jobs = jobs.clone();
+
+    let handler = move |request: &Request| {
+        let start = Instant::now();
+        let request_id = random_string(12);
+
+        let response = if request.url().eq("/metrics") {
+            metrics_response(&api, &auth)
+        } else if request.url().eq("/healthz") {
+            health::healthz()
+        } else if request.url().eq("/readyz") {
+            health.readyz()
+        } else if request.url().starts_with("/api") && !health.is_loaded() {
+            health::not_ready()
+        } else if request.url().starts_with("/api") {
+            route_api(request, &auth, &api, &health, &handler_jobs)
+        } else {
+            assets::serve(request).unwrap_or_else(Response::empty_404)
+        };
+
+        let response = compress(request, response);
+        let duration = start.elapsed();
+        metrics::record_request(
+            &route_label(request, response.status_code),
+            request.method(),
+            response.status_code,
+            duration,
+        );
+        return log_access(request, response, &request_id, duration);
+    };
+
+    let server = match (&server_config.tls_cert, &server_config.tls_key) {
+        (Some(cert), Some(key)) => {
+            rouille::Server::new_ssl(address, handler, read_file(cert), read_file(key))
+        }
+        _ => rouille::Server::new(address, handler),
+    };
+    let server = match server {
+        Ok(server) => server,
+        Err(error) => panic!("Unable to start server: {}", error),
+    };
+    let scheme = if tls { "https" } else { "http" };
+    info!("Serving at {}://{}", scheme, server.server_addr());
+
+    if let Some(redirect_port) = server_config.redirect_http_port {
+        let https_port = server_config.port;
+        let redirect_server =
+            rouille::Server::new((server_config.address.clone(), redirect_port), move |request| {
+                redirect_to_https(request, https_port)
+            });
+        match redirect_server {
+            Ok(redirect_server) => {
+                info!(
+                    "Redirecting http://{} to HTTPS",
+                    redirect_server.server_addr()
+                );
+                thread::spawn(move || redirect_server.run());
+            }
+            Err(error) => panic!("Unable to start HTTP redirect server: {}", error),
+        }
+    }
+
+    // Stop accepting connections once asked to, then wait for requests that
+    // have already started, and any running jobs, to finish. Every write
+    // to storage happens within one of those, so nothing is lost.
+    let stopping = watch_for_signals();
+    while !stopping.load(Ordering::SeqCst) {
+        server.poll_timeout(Duration::from_millis(100));
+    }
+    server.poll_timeout(Duration::from_millis(100));
+    server.join();
+    info!("Finished in-flight requests");
+    jobs.stop();
+    info!("Stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept_encoding: &str) -> Request {
+        return Request::fake_http(
+            "GET",
+            "/api/songs",
+            vec![("Accept-Encoding".to_string(), accept_encoding.to_string())],
+            Vec::new(),
+        );
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        return response
+            .headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_ref());
+    }
+
+    #[test]
+    fn compresses_when_accepted() {
+        let response = compress(&request("gzip"), Response::text("a".repeat(1000)));
+        assert_eq!(header(&response, "Content-Encoding"), Some("gzip"));
+        assert_eq!(header(&response, "Vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let not_modified = Response::text("").with_status_code(304);
+        let response = compress(&request("gzip"), not_modified);
+        assert_eq!(header(&response, "Content-Encoding"), None);
+        assert_eq!(header(&response, "Vary"), None);
+
+        let response = compress(&request("identity"), Response::text("a".repeat(1000)));
+        assert_eq!(header(&response, "Content-Encoding"), None);
+
+        let events = crate::events::EventBus::new();
+        let response = compress(&request("gzip"), events.subscribe("alice"));
+        assert_eq!(header(&response, "Content-Encoding"), None);
+    }
+
+    fn redirect(host: &str, url: &str, https_port: u16) -> Option<String> {
+        let headers = vec![("Host".to_string(), host.to_string())];
+        let request = Request::fake_http("GET", url, headers, Vec::new());
+        return header(&redirect_to_https(&request, https_port), "Location").map(String::from);
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        assert_eq!(
+            redirect("music.example.com:8080", "/api/songs?since=3", 443),
+            Some("https://music.example.com/api/songs?since=3".to_string())
+        );
+        assert_eq!(
+            redirect("music.example.com", "/", 8443),
+            Some("https://music.example.com:8443/".to_string())
+        );
+        let request = Request::fake_http("GET", "/", Vec::new(), Vec::new());
+        assert_eq!(redirect_to_https(&request, 443).status_code, 400);
+    }
+
+    #[test]
+    fn access_log_returns_the_request_id() {
+        let request = request("gzip");
+        let response = Response::text("hello");
+        let response = log_access(&request, response, "abc", Duration::from_millis(3));
+        assert_eq!(header(&response, "X-Request-Id"), Some("abc"));
+
+        let mut body = String::new();
+        let (mut reader, size) = response.data.into_reader_and_size();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+        assert_eq!(size, Some(5));
+    }
+
+    #[test]
+    fn routes_are_grouped() {
+        let route = |url: &str, status: u16| -> String {
+            let request = Request::fake_http("GET", url, Vec::new(), Vec::new());
+            return route_label(&request, status);
+        };
+        assert_eq!(route("/api/songs", 200), "/api/songs");
+        assert_eq!(route("/api/songs/a1/rating", 200), "/api/songs/{id}/rating");
+        assert_eq!(route("/api/tokens/abc123", 204), "/api/tokens/{id}");
+        assert_eq!(route("/api/albums/0a1b/art", 200), "/api/albums/{id}/art");
+        assert_eq!(route("/api/artists/0a1b", 200), "/api/artists/{id}");
+        assert_eq!(route("/api/songs/abc123", 401), "unauthenticated");
+        assert_eq!(route("/nothing/here", 404), "unmatched");
+    }
 }
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+use crate::library::random_id;
+
+// Sessions that haven't sent an update for this long are assumed to have
+// been closed.
+const SESSION_TIMEOUT_SECONDS: i64 = 5 * 60;
+
+// A single open player, such as a browser tab, and what it is playing
+#[derive(Serialize, Clone)]
+pub struct PlaybackSession {
+    pub id: String,
+    // Sessions are only visible to, and controllable by, their own user
+    #[serde(skip)]
+    pub user: String,
+    pub name: String,
+    pub song_id: Option<String>,
+    // Position in the current song, in seconds
+    pub position: f64,
+    pub playing: bool,
+    pub queue: Vec<String>,
+    // Unix timestamp of the last update from the session
+    pub updated_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct SessionUpdate {
+    // Omitted when a player starts a new session
+    pub id: Option<String>,
+    pub name: String,
+    pub song_id: Option<String>,
+    pub position: f64,
+    pub playing: bool,
+    #[serde(default)]
+    pub queue: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Command {
+    Play,
+    Pause,
+    Skip,
+    // Start playing the given song, from the given position
+    Resume {
+        song_id: Option<String>,
+        position: f64,
+        queue: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Clone)]
+pub struct SessionCommand {
+    pub session_id: String,
+    pub command: Command,
+}
+
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, PlaybackSession>>,
+}
+
+impl SessionStore {
+    pub fn new() -> SessionStore {
+        return SessionStore {
+            sessions: Mutex::new(HashMap::new()),
+        };
+    }
+
+    // Returns all of the user's sessions that are still open, most recently
+    // updated first
+    pub fn list(&self, user: &str) -> Vec<PlaybackSession> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| now - session.updated_at < SESSION_TIMEOUT_SECONDS);
+
+        let mut list: Vec<PlaybackSession> = sessions
+            .values()
+            .filter(|session| session.user == user)
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then(a.id.cmp(&b.id)));
+        return list;
+    }
+
+    // Counts the open sessions of every user
+    pub fn count(&self) -> usize {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| now - session.updated_at < SESSION_TIMEOUT_SECONDS);
+        return sessions.len();
+    }
+
+    pub fn get(&self, user: &str, id: &str) -> Option<PlaybackSession> {
+        return self.list(user).into_iter().find(|session| session.id == id);
+    }
+
+    pub fn update(&self, user: &str, update: SessionUpdate) -> Option<PlaybackSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        // Don't let one user take over another user's session
+        if let Some(id) = &update.id {
+            if let Some(existing) = sessions.get(id) {
+                if existing.user != user {
+                    return None;
+                }
+            }
+        }
+
+        let session = PlaybackSession {
+            id: update.id.unwrap_or_else(random_id),
+            user: user.to_string(),
+            name: update.name,
+            song_id: update.song_id,
+            position: update.position,
+            playing: update.playing,
+            queue: update.queue,
+            updated_at: OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        sessions.insert(session.id.clone(), session.clone());
+        return Some(session);
+    }
+
+    // Works out the commands needed to move playback from one session to
+    // another: the target picks up where the source was, and the source
+    // is paused.
+    pub fn transfer(
+        &self,
+        user: &str,
+        source_id: &str,
+        target_id: &str,
+    ) -> Option<Vec<SessionCommand>> {
+        let source = self.get(user, source_id)?;
+        self.get(user, target_id)?;
+
+        return Some(vec![
+            SessionCommand {
+                session_id: target_id.to_string(),
+                command: Command::Resume {
+                    song_id: source.song_id,
+                    position: source.position,
+                    queue: source.queue,
+                },
+            },
+            SessionCommand {
+                session_id: source_id.to_string(),
+                command: Command::Pause,
+            },
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(id: Option<&str>, song_id: &str, position: f64) -> SessionUpdate {
+        return SessionUpdate {
+            id: id.map(|id| id.to_string()),
+            name: "Browser".to_string(),
+            song_id: Some(song_id.to_string()),
+            position,
+            playing: true,
+            queue: vec!["next".to_string()],
+        };
+    }
+
+    #[test]
+    fn new_sessions_are_given_ids() {
+        let sessions = SessionStore::new();
+        let first = sessions.update("a", update(None, "a", 0.0)).unwrap();
+        let second = sessions.update("a", update(None, "b", 0.0)).unwrap();
+        assert_ne!(first.id, second.id);
+        let updated = sessions
+            .update("a", update(Some(&first.id), "c", 10.0))
+            .unwrap();
+        assert_eq!(updated.id, first.id);
+        assert_eq!(sessions.list("a").len(), 2);
+        let song_id = sessions.get("a", &first.id).unwrap().song_id;
+        assert_eq!(song_id.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn sessions_time_out() {
+        let sessions = SessionStore::new();
+        let session = sessions.update("a", update(Some("old"), "a", 0.0)).unwrap();
+        sessions
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&session.id)
+            .unwrap()
+            .updated_at -= SESSION_TIMEOUT_SECONDS;
+        assert!(sessions.list("a").is_empty());
+        assert!(sessions.get("a", "old").is_none());
+    }
+
+    #[test]
+    fn transfer_resumes_target_and_pauses_source() {
+        let sessions = SessionStore::new();
+        sessions.update("a", update(Some("source"), "a", 42.0));
+        sessions.update("a", update(Some("target"), "b", 0.0));
+
+        let commands = sessions.transfer("a", "source", "target").unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].session_id, "target");
+        match &commands[0].command {
+            Command::Resume {
+                song_id,
+                position,
+                queue,
+            } => {
+                assert_eq!(song_id.as_deref(), Some("a"));
+                assert_eq!(*position, 42.0);
+                assert_eq!(queue, &vec!["next".to_string()]);
+            }
+            _ => panic!("Expected the target to be resumed"),
+        }
+        assert_eq!(commands[1].session_id, "source");
+        assert!(matches!(commands[1].command, Command::Pause));
+    }
+
+    #[test]
+    fn sessions_belong_to_their_user() {
+        let sessions = SessionStore::new();
+        sessions.update("a", update(Some("mine"), "a", 0.0));
+        assert!(sessions
+            .update("b", update(Some("mine"), "b", 0.0))
+            .is_none());
+        assert!(sessions.list("b").is_empty());
+        assert!(sessions.get("b", "mine").is_none());
+
+        sessions.update("b", update(Some("theirs"), "b", 0.0));
+        assert!(sessions.transfer("a", "mine", "theirs").is_none());
+        assert_eq!(sessions.list("a").len(), 1);
+    }
+
+    #[test]
+    fn transfer_needs_both_sessions() {
+        let sessions = SessionStore::new();
+        sessions.update("a", update(Some("source"), "a", 0.0));
+        assert!(sessions.transfer("a", "source", "missing").is_none());
+        assert!(sessions.transfer("a", "missing", "source").is_none());
+    }
+
+    #[test]
+    fn commands_are_tagged_with_their_type() {
+        let json = serde_json::to_string(&Command::Skip).unwrap();
+        assert_eq!(json, "{\"type\":\"skip\"}");
+    }
+}
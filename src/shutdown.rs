@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tokio::runtime::Runtime;
+
+// Blocks until the process is asked to stop with Ctrl-C or SIGTERM
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Returns a flag that is set once the process has been asked to stop. A
+// second signal stops the process straight away, in case shutting down
+// gracefully gets stuck.
+pub fn watch_for_signals() -> Arc<AtomicBool> {
+    let stopping = Arc::new(AtomicBool::new(false));
+    let flag = stopping.clone();
+    thread::spawn(move || {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(wait_for_signal());
+        info!("Shutting down, signal again to stop immediately");
+        flag.store(true, Ordering::SeqCst);
+        runtime.block_on(wait_for_signal());
+        warn!("Stopping without finishing in-flight requests");
+        std::process::exit(1);
+    });
+    return stopping;
+}
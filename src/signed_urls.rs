@@ -0,0 +1,200 @@
+extern crate lazy_static;
+
+use std::collections::HashMap;
+use std::io::Result;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{config, SignedUrlConfig};
+use crate::library::Song;
+use crate::storage::{self, SignOptions};
+
+// Name for downloads when none is configured
+const DEFAULT_DOWNLOAD_FILENAME: &str = "{artist} - {title}";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum UrlKind {
+    Play,
+    Download,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct CacheKey {
+    path: String,
+    kind: UrlKind,
+    ip: Option<String>,
+}
+
+struct CachedUrl {
+    url: String,
+    reusable_until: Instant,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<HashMap<CacheKey, CachedUrl>> = Mutex::new(HashMap::new());
+}
+
+fn settings(kind: UrlKind) -> &'static SignedUrlConfig {
+    return match kind {
+        UrlKind::Play => &config().signed_urls.play,
+        UrlKind::Download => &config().signed_urls.download,
+    };
+}
+
+// Azure can only restrict signed URLs to IPv4 addresses
+fn ipv4(address: IpAddr) -> Option<String> {
+    return match address {
+        IpAddr::V4(address) => Some(address.to_string()),
+        IpAddr::V6(address) => address.to_ipv4_mapped().map(|address| address.to_string()),
+    };
+}
+
+// Fills in the filename template from the config, keeping the file's
+// extension
+fn filename(template: &str, song: &Song) -> String {
+    let name = template
+        .replace("{artist}", &song.artist)
+        .replace("{album}", &song.album)
+        .replace("{title}", &song.title)
+        .replace(['/', '\\', '"'], "_");
+    return match Path::new(&song.file_location).extension() {
+        Some(extension) => format!("{}.{}", name, extension.to_string_lossy()),
+        None => name,
+    };
+}
+
+// Plain ASCII for old clients, and the exact name for everyone else
+fn disposition_header(disposition: &str, filename: &str) -> String {
+    let ascii: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    return format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        ascii,
+        storage::percent_encode(filename)
+    );
+}
+
+// The Content-Disposition for the kind of request, if the file should be
+// named. Downloads always are.
+pub fn content_disposition(song: &Song, kind: UrlKind) -> Option<String> {
+    let template = match (&settings(kind).filename, kind) {
+        (Some(template), _) => template.as_str(),
+        (None, UrlKind::Download) => DEFAULT_DOWNLOAD_FILENAME,
+        (None, UrlKind::Play) => return None,
+    };
+    let disposition = match kind {
+        UrlKind::Play => "inline",
+        UrlKind::Download => "attachment",
+    };
+    return Some(disposition_header(disposition, &filename(template, song)));
+}
+
+// Signs a URL for the song's file as configured for the kind of request.
+// URLs are handed out again for a while, but never for more than half of
+// their lifetime, so that clients always get time to use them.
+pub fn sign_song(song: &Song, kind: UrlKind, client: IpAddr) -> Result<String> {
+    let settings = settings(kind);
+    let path = format!("Music{}", song.file_location);
+    let ip = if settings.bind_client_ip {
+        let ip = ipv4(client);
+        if ip.is_none() {
+            debug!("Not binding a signed URL to IPv6 address {}", client);
+        }
+        ip
+    } else {
+        None
+    };
+    let key = CacheKey {
+        path: path.clone(),
+        kind,
+        ip: ip.clone(),
+    };
+
+    let now = Instant::now();
+    if let Some(cached) = CACHE.lock().unwrap().get(&key) {
+        if now < cached.reusable_until {
+            return Ok(cached.url.clone());
+        }
+    }
+
+    let expiry_minutes = settings.expiry_minutes(&config().storage);
+    let url = storage::sign(
+        &path,
+        &SignOptions {
+            expiry_minutes,
+            https_only: !settings.allow_http,
+            ip,
+            content_disposition: content_disposition(song, kind),
+        },
+    )?;
+
+    let cache_duration = Duration::from_secs(config().signed_urls.cache_seconds)
+        .min(Duration::from_secs(expiry_minutes as u64 * 60 / 2));
+    if !cache_duration.is_zero() {
+        let mut cache = CACHE.lock().unwrap();
+        cache.retain(|_, cached| now < cached.reusable_until);
+        cache.insert(
+            key,
+            CachedUrl {
+                url: url.clone(),
+                reusable_until: now + cache_duration,
+            },
+        );
+    }
+    return Ok(url);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::test_song;
+
+    #[test]
+    fn filenames_keep_the_extension() {
+        let song = test_song("a", "AC/DC", "Back in Black");
+        assert_eq!(
+            filename("{artist} - {album} - {title}", &song),
+            "AC_DC - Back in Black - Song a.mp3"
+        );
+        let mut song = song;
+        song.file_location = "/no-extension".to_string();
+        assert_eq!(filename("{title}", &song), "Song a");
+    }
+
+    #[test]
+    fn disposition_has_an_ascii_fallback() {
+        assert_eq!(
+            disposition_header("attachment", "Björk - Jóga.mp3"),
+            "attachment; filename=\"Bj_rk - J_ga.mp3\"; \
+             filename*=UTF-8''Bj%C3%B6rk%20-%20J%C3%B3ga.mp3"
+        );
+    }
+
+    #[test]
+    fn only_downloads_are_named_by_default() {
+        crate::config::init_for_tests();
+        let song = test_song("a", "Artist", "Album");
+        assert_eq!(content_disposition(&song, UrlKind::Play), None);
+        let download = content_disposition(&song, UrlKind::Download).unwrap();
+        assert!(download.starts_with("attachment; filename=\"Artist - Song a.mp3\""));
+    }
+
+    #[test]
+    fn only_ipv4_addresses_are_bound() {
+        let ip = |address: &str| ipv4(address.parse().unwrap());
+        assert_eq!(ip("192.0.2.1"), Some("192.0.2.1".to_string()));
+        assert_eq!(ip("::ffff:192.0.2.1"), Some("192.0.2.1".to_string()));
+        assert_eq!(ip("2001:db8::1"), None);
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+use crate::library::Library;
+use crate::play_log::Play;
+use crate::user_data::UserData;
+
+#[derive(Serialize)]
+pub struct RankedItem {
+    pub name: String,
+    pub plays: u32,
+    pub listening_seconds: u64,
+}
+
+#[derive(Serialize)]
+pub struct RankedSong {
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub plays: u32,
+    pub listening_seconds: u64,
+}
+
+#[derive(Serialize)]
+pub struct GrowthPoint {
+    // Month the songs were first seen in, formatted as YYYY-MM
+    pub month: String,
+    pub added: u32,
+    pub total: u32,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    // Plays that count towards play counts, which the rankings are made of
+    pub plays: u32,
+    // Time spent listening, including to songs that were skipped
+    pub listening_seconds: u64,
+    pub top_artists: Vec<RankedItem>,
+    pub top_albums: Vec<RankedItem>,
+    pub top_genres: Vec<RankedItem>,
+    pub top_songs: Vec<RankedSong>,
+    // Number of songs with each rating, indexed by rating
+    pub rating_distribution: Vec<u32>,
+    pub never_played: Vec<String>,
+    pub library_growth: Vec<GrowthPoint>,
+    // Songs that were added before first-seen dates were recorded
+    pub undated_songs: u32,
+}
+
+#[derive(Default)]
+struct Tally {
+    plays: u32,
+    listening_seconds: u64,
+}
+
+impl Tally {
+    fn add(&mut self, seconds: u64) {
+        self.plays += 1;
+        self.listening_seconds += seconds;
+    }
+}
+
+// Computes statistics over the plays that started within the given range.
+// Statistics about the library itself always cover the whole library.
+pub fn compute_stats(
+    library: &Library,
+    user_data: &UserData,
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: usize,
+) -> Stats {
+    let plays = user_data.play_log.between(from, to);
+
+    let mut counted_plays: u32 = 0;
+    let mut listening_seconds: u64 = 0;
+    let mut artists: HashMap<String, Tally> = HashMap::new();
+    let mut albums: HashMap<String, Tally> = HashMap::new();
+    let mut genres: HashMap<String, Tally> = HashMap::new();
+    let mut songs: HashMap<String, Tally> = HashMap::new();
+    for play in &plays {
+        let song = match library.songs.get(&play.song_id) {
+            Some(song) => song,
+            None => continue,
+        };
+        let seconds = listened_seconds(play, song.duration);
+        listening_seconds += seconds;
+        // Skipped songs don't count towards the rankings, as with play counts
+        if !play.is_counted() {
+            continue;
+        }
+        counted_plays += 1;
+        artists.entry(song.artist.clone()).or_default().add(seconds);
+        albums
+            .entry(format!("{} - {}", song.artist, song.album))
+            .or_default()
+            .add(seconds);
+        genres.entry(song.genre.clone()).or_default().add(seconds);
+        songs.entry(song.id.clone()).or_default().add(seconds);
+    }
+
+    let top_songs = top_n(songs, limit)
+        .into_iter()
+        .map(|item| {
+            let song = library.songs.get(&item.name).unwrap();
+            RankedSong {
+                id: song.id.clone(),
+                title: song.title.clone(),
+                artist: song.artist.clone(),
+                plays: item.plays,
+                listening_seconds: item.listening_seconds,
+            }
+        })
+        .collect();
+
+    let mut rating_distribution: Vec<u32> = vec![0; 6];
+    let mut never_played: Vec<String> = Vec::new();
+    for song in library.songs.values() {
+        let rating = (user_data.rating(song) as usize).min(rating_distribution.len() - 1);
+        rating_distribution[rating] += 1;
+        if user_data.play_log.stats(&song.id).play_count == 0 {
+            never_played.push(song.id.clone());
+        }
+    }
+    never_played.sort();
+
+    let (library_growth, undated_songs) = library_growth(library);
+
+    return Stats {
+        from,
+        to,
+        plays: counted_plays,
+        listening_seconds,
+        top_artists: top_n(artists, limit),
+        top_albums: top_n(albums, limit),
+        top_genres: top_n(genres, limit),
+        top_songs,
+        rating_distribution,
+        never_played,
+        library_growth,
+        undated_songs,
+    };
+}
+
+fn listened_seconds(play: &Play, duration: u32) -> u64 {
+    return (play.fraction * duration as f64).round() as u64;
+}
+
+// Ranks by number of plays, breaking ties using the time spent listening
+fn top_n(tallies: HashMap<String, Tally>, limit: usize) -> Vec<RankedItem> {
+    let mut items: Vec<RankedItem> = tallies
+        .into_iter()
+        .map(|(name, tally)| RankedItem {
+            name,
+            plays: tally.plays,
+            listening_seconds: tally.listening_seconds,
+        })
+        .collect();
+    items.sort_by(|a, b| {
+        b.plays
+            .cmp(&a.plays)
+            .then(b.listening_seconds.cmp(&a.listening_seconds))
+            .then(a.name.cmp(&b.name))
+    });
+    items.truncate(limit);
+    return items;
+}
+
+// Counts the songs first seen in each month, along with a running total
+fn library_growth(library: &Library) -> (Vec<GrowthPoint>, u32) {
+    let mut months: HashMap<(i32, u8), u32> = HashMap::new();
+    let mut undated_songs: u32 = 0;
+    for song in library.songs.values() {
+        let first_seen = match song.first_seen {
+            Some(first_seen) => OffsetDateTime::from_unix_timestamp(first_seen).ok(),
+            None => None,
+        };
+        match first_seen {
+            Some(first_seen) => {
+                *months
+                    .entry((first_seen.year(), first_seen.month() as u8))
+                    .or_default() += 1;
+            }
+            None => {
+                undated_songs += 1;
+            }
+        }
+    }
+
+    let mut keys: Vec<(i32, u8)> = months.keys().cloned().collect();
+    keys.sort();
+
+    let mut total = undated_songs;
+    let mut growth: Vec<GrowthPoint> = Vec::new();
+    for (year, month) in keys {
+        let added = *months.get(&(year, month)).unwrap();
+        total += added;
+        growth.push(GrowthPoint {
+            month: format!("{:04}-{:02}", year, month),
+            added,
+            total,
+        });
+    }
+    return (growth, undated_songs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::test_song;
+    use crate::user_data::test_user_data;
+
+    fn play(song_id: &str, started_at: i64, fraction: f64) -> Play {
+        return Play {
+            song_id: song_id.to_string(),
+            started_at,
+            fraction,
+        };
+    }
+
+    fn library() -> Library {
+        let mut library = Library {
+            revision: 0,
+            songs: HashMap::new(),
+            removed_songs: HashMap::new(),
+            album_art: HashMap::new(),
+        };
+        for song in [
+            test_song("a", "First", "One"),
+            test_song("b", "First", "Two"),
+            test_song("c", "Second", "Three"),
+        ] {
+            library.songs.insert(song.id.clone(), song);
+        }
+        return library;
+    }
+
+    #[test]
+    fn ranks_by_plays() {
+        let library = library();
+        let user_data = test_user_data(vec![
+            play("a", 10, 1.0),
+            play("c", 20, 1.0),
+            play("c", 30, 1.0),
+            play("b", 40, 1.0),
+        ]);
+        let stats = compute_stats(&library, &user_data, None, None, 10);
+        assert_eq!(stats.plays, 4);
+        assert_eq!(stats.listening_seconds, 400);
+        let songs: Vec<(&str, u32)> = stats
+            .top_songs
+            .iter()
+            .map(|song| (song.id.as_str(), song.plays))
+            .collect();
+        assert_eq!(songs, vec![("c", 2), ("a", 1), ("b", 1)]);
+        let artists: Vec<(&str, u32)> = stats
+            .top_artists
+            .iter()
+            .map(|artist| (artist.name.as_str(), artist.plays))
+            .collect();
+        assert_eq!(artists, vec![("First", 2), ("Second", 2)]);
+    }
+
+    #[test]
+    fn ties_are_broken_by_listening_time() {
+        let library = library();
+        let user_data = test_user_data(vec![play("a", 10, 0.6), play("b", 20, 0.9)]);
+        let stats = compute_stats(&library, &user_data, None, None, 10);
+        assert_eq!(stats.listening_seconds, 150);
+        assert_eq!(stats.top_songs[0].id, "b");
+        assert_eq!(stats.top_songs[1].id, "a");
+    }
+
+    #[test]
+    fn skipped_plays_only_count_towards_listening_time() {
+        let library = library();
+        let user_data = test_user_data(vec![
+            play("a", 10, 1.0),
+            play("b", 20, 0.1),
+            play("b", 30, 0.1),
+        ]);
+        let stats = compute_stats(&library, &user_data, None, None, 10);
+        assert_eq!(stats.plays, 1);
+        assert_eq!(stats.listening_seconds, 120);
+        let songs: Vec<&str> = stats
+            .top_songs
+            .iter()
+            .map(|song| song.id.as_str())
+            .collect();
+        assert_eq!(songs, vec!["a"]);
+    }
+
+    #[test]
+    fn only_plays_in_range() {
+        let library = library();
+        let user_data = test_user_data(vec![
+            play("a", 10, 1.0),
+            play("b", 20, 1.0),
+            play("c", 30, 1.0),
+        ]);
+        let stats = compute_stats(&library, &user_data, Some(20), Some(30), 10);
+        assert_eq!(stats.plays, 1);
+        assert_eq!(stats.top_songs[0].id, "b");
+    }
+
+    #[test]
+    fn limits_rankings() {
+        let library = library();
+        let user_data = test_user_data(vec![play("a", 10, 1.0), play("c", 20, 1.0)]);
+        let stats = compute_stats(&library, &user_data, None, None, 1);
+        assert_eq!(stats.top_songs.len(), 1);
+        assert_eq!(stats.top_artists.len(), 1);
+    }
+
+    #[test]
+    fn library_statistics() {
+        let mut library = library();
+        library.songs.get_mut("a").unwrap().rating = 5;
+        library.songs.get_mut("b").unwrap().first_seen = Some(0);
+        let user_data = test_user_data(vec![play("a", 10, 1.0)]);
+        let stats = compute_stats(&library, &user_data, None, None, 10);
+        assert_eq!(stats.rating_distribution, vec![2, 0, 0, 0, 0, 1]);
+        assert_eq!(stats.never_played, vec!["b", "c"]);
+        assert_eq!(stats.undated_songs, 2);
+        assert_eq!(stats.library_growth.len(), 1);
+        assert_eq!(stats.library_growth[0].month, "1970-01");
+        assert_eq!(stats.library_growth[0].total, 3);
+    }
+}
@@ -1,33 +1,120 @@
+use azure_core::request_options::IfMatchCondition;
 use azure_storage::prelude::*;
+use azure_storage_blobs::blob::CopyStatus;
 use azure_storage_blobs::container::operations::BlobItem;
 use azure_storage_blobs::prelude::*;
 use futures::stream::StreamExt;
-use std::env;
-use std::io::{Error, ErrorKind, Result};
+use std::fs;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
 use time::{Duration, OffsetDateTime};
 
-fn read_env_var(name: &str) -> String {
-    return env::var(name).expect(&format!("Unable to read environment variable: {}", name));
-}
+use crate::config::{config, StorageBackend};
+use crate::metrics;
 
+// Blobs that aren't there are NotFound, the same as missing local files,
+// so that callers can tell them apart from storage being unavailable
 fn azure_error(err: azure_storage::Error) -> Error {
-    return Error::new(
-        ErrorKind::Other,
-        format!("error accessing azure: {}", err.to_string()),
+    let kind = match err.kind() {
+        azure_core::error::ErrorKind::HttpResponse {
+            status: azure_core::StatusCode::NotFound,
+            ..
+        } => ErrorKind::NotFound,
+        _ => ErrorKind::Other,
+    };
+    return Error::new(kind, format!("error accessing azure: {}", err.to_string()));
+}
+
+// The largest block that can be appended to an append blob at once
+const MAX_APPEND_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+// Logs how long a storage operation took, and why it failed if it did
+async fn traced<T>(
+    operation: &str,
+    path: &str,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let start = Instant::now();
+    let result = future.await;
+    let elapsed = start.elapsed();
+    metrics::record_storage_operation(
+        config().storage.backend.name(),
+        operation,
+        elapsed,
+        result.is_ok(),
     );
+    let duration_ms = elapsed.as_millis() as u64;
+    match &result {
+        Ok(_) => debug!(
+            operation = operation, path = path, duration_ms = duration_ms;
+            "{} {} took {}ms", operation, path, duration_ms
+        ),
+        Err(err) => warn!(
+            operation = operation, path = path, duration_ms = duration_ms;
+            "{} {} failed after {}ms: {}", operation, path, duration_ms, err
+        ),
+    }
+    return result;
 }
 
 fn get_container_client() -> ContainerClient {
-    let account_name = read_env_var("AZURE_ACCOUNT_NAME");
-    let access_key = read_env_var("AZURE_ACCESS_KEY");
-    let container_name = read_env_var("AZURE_CONTAINER_NAME");
+    let storage = &config().storage;
+    let storage_credentials =
+        StorageCredentials::access_key(storage.account_name.clone(), storage.access_key.clone());
+    return ClientBuilder::new(storage.account_name.clone(), storage_credentials)
+        .container_client(storage.container_name.clone());
+}
 
-    let storage_credentials = StorageCredentials::access_key(account_name.clone(), access_key);
-    return ClientBuilder::new(account_name, storage_credentials).container_client(container_name);
+// Returns where the path lives on disk when using the local backend
+fn local_path(path: &str) -> Option<PathBuf> {
+    let storage = &config().storage;
+    return match storage.backend {
+        StorageBackend::Local => Some(Path::new(&storage.path).join(path)),
+        StorageBackend::Azure => None,
+    };
 }
 
-pub async fn ls_async(path: &str) -> Result<Vec<String>> {
+// Lists every file under the directory, relative to the storage root
+fn local_ls(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<()> {
+    if !dir.is_dir() {
+        return Result::Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            local_ls(root, &path, files)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            files.push(relative.to_string_lossy().replace('\\', "/"));
+        }
+    }
+    return Result::Ok(());
+}
+
+fn local_put(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    return fs::write(path, content);
+}
+
+async fn ls_backend(path: &str) -> Result<Vec<String>> {
+    if let Some(local) = local_path(path) {
+        // Like blob prefixes, the path doesn't have to end at a directory
+        let root = PathBuf::from(&config().storage.path);
+        let dir = if path.ends_with('/') {
+            local
+        } else {
+            local.parent().map(Path::to_path_buf).unwrap_or(root.clone())
+        };
+        let mut files: Vec<String> = Vec::new();
+        local_ls(&root, &dir, &mut files)?;
+        files.retain(|file| file.starts_with(path));
+        return Ok(files);
+    }
+
     let mut stream = get_container_client()
         .list_blobs()
         .prefix(path.to_string())
@@ -55,11 +142,96 @@ pub async fn ls_async(path: &str) -> Result<Vec<String>> {
     return Ok(blobs);
 }
 
+pub async fn ls_async(path: &str) -> Result<Vec<String>> {
+    return traced("ls", path, ls_backend(path)).await;
+}
+
 pub fn ls(path: &str) -> Result<Vec<String>> {
     return Runtime::new().unwrap().block_on(ls_async(path));
 }
 
-pub async fn cat_async(path: &str) -> Result<Vec<u8>> {
+async fn exists_backend(path: &str) -> Result<bool> {
+    if let Some(local) = local_path(path) {
+        return Ok(local.is_file());
+    }
+    return get_container_client()
+        .blob_client(path.to_string())
+        .exists()
+        .await
+        .map_err(azure_error);
+}
+
+pub async fn exists_async(path: &str) -> Result<bool> {
+    return traced("exists", path, exists_backend(path)).await;
+}
+
+pub fn exists(path: &str) -> Result<bool> {
+    return Runtime::new().unwrap().block_on(exists_async(path));
+}
+
+pub struct Metadata {
+    pub size: u64,
+    // Changes whenever the contents do. This is the ETag in Azure, and the
+    // size and modification time on disk.
+    pub version: String,
+    pub last_modified: SystemTime,
+    // How many blocks have been appended to an append blob. Files on disk
+    // and other blobs don't have any.
+    pub blocks: Option<u64>,
+}
+
+async fn stat_backend(path: &str) -> Result<Metadata> {
+    if let Some(local) = local_path(path) {
+        let metadata = fs::metadata(local)?;
+        let modified = metadata.modified()?;
+        let nanos = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        return Ok(Metadata {
+            size: metadata.len(),
+            version: format!("{}-{}", metadata.len(), nanos),
+            last_modified: modified,
+            blocks: None,
+        });
+    }
+
+    return match get_container_client()
+        .blob_client(path.to_string())
+        .get_properties()
+        .await
+    {
+        Ok(response) => {
+            let properties = response.blob.properties;
+            Ok(Metadata {
+                size: properties.content_length,
+                version: properties.etag.to_string(),
+                last_modified: properties.last_modified.into(),
+                blocks: properties.blob_committed_block_count,
+            })
+        }
+        Err(err) => Result::Err(azure_error(err)),
+    };
+}
+
+pub async fn stat_async(path: &str) -> Result<Metadata> {
+    return traced("stat", path, stat_backend(path)).await;
+}
+
+pub fn stat(path: &str) -> Result<Metadata> {
+    return Runtime::new().unwrap().block_on(stat_async(path));
+}
+
+// Returns a value that changes whenever the file's contents do
+pub fn version(path: &str) -> Result<String> {
+    return stat(path).map(|metadata| metadata.version);
+}
+
+async fn cat_backend(path: &str) -> Result<Vec<u8>> {
+    if let Some(local) = local_path(path) {
+        return fs::read(local);
+    }
+
     let mut stream = get_container_client()
         .blob_client(path.to_string())
         .get()
@@ -89,44 +261,184 @@ pub async fn cat_async(path: &str) -> Result<Vec<u8>> {
     return Ok(data);
 }
 
+pub async fn cat_async(path: &str) -> Result<Vec<u8>> {
+    return traced("cat", path, cat_backend(path)).await;
+}
+
 pub fn cat(path: &str) -> Result<Vec<u8>> {
     return Runtime::new().unwrap().block_on(cat_async(path));
 }
 
-pub async fn sign_async(path: &str) -> Result<String> {
-    let permissions = BlobSasPermissions {
-        read: true,
-        add: false,
-        create: false,
-        write: false,
-        delete: false,
-        delete_version: false,
-        permanent_delete: false,
-        list: false,
-        tags: false,
-        move_: false,
-        execute: false,
-        ownership: false,
-        permissions: false,
+// Reads the bytes from start up to, but not including, end
+async fn read_range_backend(path: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+    if let Some(local) = local_path(path) {
+        let mut file = fs::File::open(local)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut data: Vec<u8> = Vec::new();
+        file.take(end - start).read_to_end(&mut data)?;
+        return Ok(data);
+    }
+
+    let mut stream = get_container_client()
+        .blob_client(path.to_string())
+        .get()
+        .range(start..end)
+        .into_stream();
+
+    let mut data: Vec<u8> = Vec::new();
+    while let Some(value) = stream.next().await {
+        match value {
+            Ok(mut value) => {
+                while let Some(bytes) = value.data.next().await {
+                    match bytes {
+                        Ok(bytes) => {
+                            data.extend(bytes);
+                        }
+                        Err(err) => {
+                            return Result::Err(azure_error(err));
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                return Result::Err(azure_error(err));
+            }
+        }
+    }
+
+    return Ok(data);
+}
+
+pub async fn read_range_async(path: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+    return traced("read_range", path, read_range_backend(path, start, end)).await;
+}
+
+pub fn read_range(path: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+    return Runtime::new().unwrap().block_on(read_range_async(path, start, end));
+}
+
+// Version of the Azure API that signed URLs are written for
+const SAS_VERSION: &str = "2022-11-02";
+
+// What a signed URL allows. It can only ever be used to read the file.
+pub struct SignOptions {
+    pub expiry_minutes: i64,
+    pub https_only: bool,
+    // The only IPv4 address the URL can be used from
+    pub ip: Option<String>,
+    // Returned as the Content-Disposition header, to name the file
+    pub content_disposition: Option<String>,
+}
+
+// Percent-encodes everything but unreserved characters, which is safe for
+// both query strings and Content-Disposition filenames
+pub fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    return encoded;
+}
+
+// Azure's SDK can't sign response headers, so service SASes are built by
+// hand following
+// https://learn.microsoft.com/rest/api/storageservices/create-service-sas
+async fn sign_backend(path: &str, options: &SignOptions) -> Result<String> {
+    if local_path(path).is_some() {
+        return Result::Err(Error::new(
+            ErrorKind::Unsupported,
+            "the local storage backend is unable to sign URLs",
+        ));
+    }
+
+    let storage = &config().storage;
+    // Azure ignores fractions of a second when checking signatures
+    let expiry = (OffsetDateTime::now_utc() + Duration::minutes(options.expiry_minutes))
+        .replace_nanosecond(0)
+        .unwrap();
+    let expiry = azure_core::date::to_rfc3339(&expiry);
+    let protocol = if options.https_only { "https" } else { "https,http" };
+    let ip = options.ip.clone().unwrap_or_default();
+    let content_disposition = options.content_disposition.clone().unwrap_or_default();
+    let string_to_sign = [
+        "r",
+        "",
+        &expiry,
+        &format!(
+            "/blob/{}/{}/{}",
+            storage.account_name, storage.container_name, path
+        ),
+        "",
+        &ip,
+        protocol,
+        SAS_VERSION,
+        "b",
+        "",
+        "",
+        "",
+        &content_disposition,
+        "",
+        "",
+        "",
+    ]
+    .join("\n");
+    let key = azure_core::auth::Secret::new(storage.access_key.clone());
+    let signature = match azure_core::hmac::hmac_sha256(&string_to_sign, &key) {
+        Ok(signature) => signature,
+        Err(err) => return Result::Err(azure_error(err)),
     };
-    let expiry = OffsetDateTime::now_utc() + Duration::hours(1);
+
+    let mut query: Vec<(&str, &str)> = vec![
+        ("sv", SAS_VERSION),
+        ("sp", "r"),
+        ("sr", "b"),
+        ("se", &expiry),
+        ("spr", protocol),
+    ];
+    if options.ip.is_some() {
+        query.push(("sip", &ip));
+    }
+    if options.content_disposition.is_some() {
+        query.push(("rscd", &content_disposition));
+    }
+    query.push(("sig", &signature));
+    let token: Vec<String> = query
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, percent_encode(value)))
+        .collect();
+    return Ok(format!(
+        "https://{}.blob.core.windows.net/{}/{}?{}",
+        storage.account_name,
+        storage.container_name,
+        path,
+        token.join("&")
+    ));
+}
+
+pub async fn sign_async(path: &str, options: &SignOptions) -> Result<String> {
+    return traced("sign", path, sign_backend(path, options)).await;
+}
+
+pub fn sign(path: &str, options: &SignOptions) -> Result<String> {
+    return Runtime::new().unwrap().block_on(sign_async(path, options));
+}
+
+async fn put_backend(path: &str, content: Vec<u8>) -> Result<()> {
+    if let Some(local) = local_path(path) {
+        return local_put(&local, &content);
+    }
+
     let client = get_container_client()
         .blob_client(path.to_string());
-    let signature = client.shared_access_signature(permissions, expiry).await;
-
-    match signature {
-        Ok(signature) => {
-            match signature.token() {
-                Ok(token) => {
-                    let account_name = read_env_var("AZURE_ACCOUNT_NAME");
-                    let container_name = read_env_var("AZURE_CONTAINER_NAME");
-                    let url = format!("https://{}.blob.core.windows.net/{}/{}?{}", account_name, container_name, path, token);
-                    return Ok(url);
-                }
-                Err(err) => {
-                    return Result::Err(azure_error(err));
-                }
-            }
+
+    match client.put_block_blob(content).await {
+        Ok(_) => {
+            return Result::Ok(());
         }
         Err(err) => {
             return Result::Err(azure_error(err));
@@ -134,15 +446,36 @@ pub async fn sign_async(path: &str) -> Result<String> {
     }
 }
 
-pub fn sign(path: &str) -> Result<String> {
-    return Runtime::new().unwrap().block_on(sign_async(path));
+pub async fn put_async(path: &str, content: Vec<u8>) -> Result<()> {
+    return traced("put", path, put_backend(path, content)).await;
 }
 
-pub async fn put_async(path: &str, content: Vec<u8>) -> Result<()> {
+pub fn put(path: &str, content: Vec<u8>) -> Result<()> {
+    return Runtime::new().unwrap().block_on(put_async(path, content));
+}
+
+// Appends to the end of an append blob, creating it first if necessary.
+// Appending never rewrites existing data, which makes it safe to use for
+// logs that are written to by more than one process.
+async fn append_backend(path: &str, content: Vec<u8>) -> Result<()> {
+    if let Some(local) = local_path(path) {
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(local)?;
+        return file.write_all(&content);
+    }
+
     let client = get_container_client()
         .blob_client(path.to_string());
 
-    match client.put_block_blob(content).await {
+    if !exists_async(path).await? {
+        if let Err(err) = client.put_append_blob().await {
+            return Result::Err(azure_error(err));
+        }
+    }
+
+    match client.append_block(content).await {
         Ok(_) => {
             return Result::Ok(());
         }
@@ -152,6 +485,78 @@ pub async fn put_async(path: &str, content: Vec<u8>) -> Result<()> {
     }
 }
 
+pub async fn append_async(path: &str, content: Vec<u8>) -> Result<()> {
+    return traced("append", path, append_backend(path, content)).await;
+}
+
+pub fn append(path: &str, content: Vec<u8>) -> Result<()> {
+    return Runtime::new().unwrap().block_on(append_async(path, content));
+}
+
+// Replaces an append blob with the given content, so that it starts again
+// with as few blocks as possible. The content is written somewhere else
+// first and then moved over the original, so that the original is left as
+// it was if writing fails part way through. Given a version, the original
+// is also left alone if it no longer has that version when it is replaced.
+async fn put_append_backend(path: &str, content: Vec<u8>, version: Option<&str>) -> Result<()> {
+    let temporary_path = format!("{}.partial", path);
+    if let Some(local) = local_path(path) {
+        let temporary = local_path(&temporary_path).unwrap();
+        local_put(&temporary, &content)?;
+        if version.is_some() && Some(stat_backend(path).await?.version.as_str()) != version {
+            fs::remove_file(temporary)?;
+            return Result::Err(Error::other(format!(
+                "{} changed while it was being replaced",
+                path
+            )));
+        }
+        return fs::rename(temporary, local);
+    }
+
+    let container_client = get_container_client();
+    let temporary = container_client.blob_client(temporary_path);
+    if let Err(err) = temporary.put_append_blob().await {
+        return Result::Err(azure_error(err));
+    }
+    for block in content.chunks(MAX_APPEND_BLOCK_SIZE) {
+        if let Err(err) = temporary.append_block(block.to_vec()).await {
+            return Result::Err(azure_error(err));
+        }
+    }
+
+    // Copies within an account finish straight away, keeping the blob type
+    let source = match temporary.url() {
+        Ok(url) => url,
+        Err(err) => return Result::Err(azure_error(err)),
+    };
+    let mut copy = container_client.blob_client(path.to_string()).copy(source);
+    if let Some(version) = version {
+        copy = copy.if_match(IfMatchCondition::Match(version.to_string()));
+    }
+    match copy.await {
+        Ok(response) if response.copy_status == CopyStatus::Success => {}
+        Ok(response) => {
+            return Result::Err(Error::other(format!(
+                "copying over {} didn't finish: {:?}",
+                path, response.copy_status
+            )));
+        }
+        Err(err) => return Result::Err(azure_error(err)),
+    }
+    if let Err(err) = temporary.delete().await {
+        return Result::Err(azure_error(err));
+    }
+    return Result::Ok(());
+}
+
+pub async fn put_append_async(path: &str, content: Vec<u8>, version: Option<&str>) -> Result<()> {
+    return traced("put_append", path, put_append_backend(path, content, version)).await;
+}
+
+pub fn put_append(path: &str, content: Vec<u8>, version: Option<&str>) -> Result<()> {
+    return Runtime::new().unwrap().block_on(put_append_async(path, content, version));
+}
+
 pub async fn upload_async(local_source_path: &str, remote_dest_path: &str) -> Result<()> {
     let content = std::fs::read(local_source_path)?;
     return put_async(remote_dest_path, content).await;
@@ -170,7 +575,11 @@ pub fn cp(src_path: &str, dest_path: &str) -> Result<()> {
     return Runtime::new().unwrap().block_on(cp_async(src_path, dest_path));
 }
 
-pub async fn rm_async(path: &str) -> Result<()> {
+async fn rm_backend(path: &str) -> Result<()> {
+    if let Some(local) = local_path(path) {
+        return fs::remove_file(local);
+    }
+
     let client = get_container_client()
         .blob_client(path.to_string());
 
@@ -184,6 +593,32 @@ pub async fn rm_async(path: &str) -> Result<()> {
     }
 }
 
+pub async fn rm_async(path: &str) -> Result<()> {
+    return traced("rm", path, rm_backend(path)).await;
+}
+
 pub fn rm(path: &str) -> Result<()> {
     return Runtime::new().unwrap().block_on(rm_async(path));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azure_core::error::ErrorKind as AzureErrorKind;
+    use azure_core::StatusCode;
+
+    fn http_error(status: StatusCode) -> azure_storage::Error {
+        return azure_storage::Error::message(
+            AzureErrorKind::http_response(status, None),
+            "request failed",
+        );
+    }
+
+    #[test]
+    fn missing_blobs_are_not_found() {
+        let missing = azure_error(http_error(StatusCode::NotFound));
+        assert_eq!(missing.kind(), ErrorKind::NotFound);
+        let forbidden = azure_error(http_error(StatusCode::Forbidden));
+        assert_eq!(forbidden.kind(), ErrorKind::Other);
+    }
+}
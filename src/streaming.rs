@@ -0,0 +1,310 @@
+extern crate httpdate;
+
+use rouille::{Request, Response, ResponseBody};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mime_types;
+use crate::storage::{self, Metadata};
+
+// How much is fetched from storage at a time while a response is sent, so
+// that seeking near the end of a song doesn't download all of it first
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Songs don't change once uploaded, and are always revalidated by ETag
+const CACHE_CONTROL: &str = "private, max-age=3600";
+
+// Reads the bytes from start up to end of a file in storage, a chunk at a
+// time as the response is written
+pub struct RangeReader {
+    path: String,
+    position: u64,
+    end: u64,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl RangeReader {
+    pub fn new(path: &str, start: u64, end: u64) -> RangeReader {
+        return RangeReader {
+            path: path.to_string(),
+            position: start,
+            end,
+            chunk: Vec::new(),
+            offset: 0,
+        };
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.offset == self.chunk.len() {
+            if self.position >= self.end {
+                return Ok(0);
+            }
+            let chunk_end = (self.position + CHUNK_SIZE).min(self.end);
+            self.chunk = storage::read_range(&self.path, self.position, chunk_end)?;
+            self.offset = 0;
+            if self.chunk.is_empty() {
+                return Result::Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("{} is shorter than expected", self.path),
+                ));
+            }
+            self.position += self.chunk.len() as u64;
+        }
+
+        let length = (self.chunk.len() - self.offset).min(buf.len());
+        buf[..length].copy_from_slice(&self.chunk[self.offset..self.offset + length]);
+        self.offset += length;
+        return Ok(length);
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum ByteRange {
+    Whole,
+    // From start up to, but not including, end
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+// Parses a Range header for a file of the given size. Headers that can't
+// be parsed, and requests for more than one range, get the whole file,
+// which clients have to accept.
+fn parse_range(header: &str, size: u64) -> ByteRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Whole,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Whole,
+    };
+
+    // A suffix, such as bytes=-500 for the last 500 bytes
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+            Ok(length) => ByteRange::Part(size - length.min(size), size),
+            Err(_) => ByteRange::Whole,
+        };
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Whole,
+    };
+    let end = if end.is_empty() {
+        size
+    } else {
+        match end.parse::<u64>() {
+            // The last byte position is inclusive
+            Ok(last) if last >= start => (last + 1).min(size),
+            _ => return ByteRange::Whole,
+        }
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    return ByteRange::Part(start, end);
+}
+
+// Compares ETags, ignoring whether either is weak
+fn etag_matches(header: &str, etag: &str) -> bool {
+    return header
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+}
+
+// HTTP dates only have whole seconds, so times are compared in seconds
+fn seconds(time: SystemTime) -> u64 {
+    return time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+}
+
+fn unmodified_since(header: &str, last_modified: SystemTime) -> bool {
+    return match httpdate::parse_http_date(header) {
+        Ok(since) => seconds(last_modified) <= seconds(since),
+        Err(_) => false,
+    };
+}
+
+// Whether a Range request should still be honoured. An If-Range header
+// means the client only wants part of the file if it hasn't changed since
+// it got the rest.
+fn range_applies(request: &Request, etag: &str, last_modified: SystemTime) -> bool {
+    return match request.header("If-Range") {
+        Some(if_range) if if_range.starts_with('"') => if_range == etag,
+        // Weak ETags can't be used to combine ranges
+        Some(if_range) if if_range.starts_with("W/") => false,
+        Some(if_range) => match httpdate::parse_http_date(if_range) {
+            Ok(date) => seconds(date) == seconds(last_modified),
+            Err(_) => false,
+        },
+        None => true,
+    };
+}
+
+fn with_headers(response: Response, etag: &str, last_modified: &str) -> Response {
+    return response
+        .with_unique_header("Accept-Ranges", "bytes")
+        .with_unique_header("ETag", etag.to_string())
+        .with_unique_header("Last-Modified", last_modified.to_string())
+        .with_unique_header("Cache-Control", CACHE_CONTROL);
+}
+
+// Looks up a song's file, with the response to send if that fails
+pub fn stat_song(path: &str) -> std::result::Result<Metadata, Response> {
+    return match storage::stat(path) {
+        Ok(metadata) => Ok(metadata),
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            Err(Response::text("Song file not found").with_status_code(404))
+        }
+        Err(error) => {
+            Err(Response::text(format!("Unable to read song: {}", error)).with_status_code(500))
+        }
+    };
+}
+
+// Sends a file from storage, or the part of it asked for with a Range
+// header, so that players can seek without downloading all of it
+pub fn serve(request: &Request, path: &str) -> Response {
+    let metadata = match stat_song(path) {
+        Ok(metadata) => metadata,
+        Err(response) => return response,
+    };
+    let path = path.to_string();
+    return serve_ranges(
+        request,
+        metadata,
+        mime_types::from_path(&path),
+        move |start, end| Ok(Box::new(RangeReader::new(&path, start, end))),
+    );
+}
+
+// Sends the whole of a file, or the part asked for, given a way to read
+// the bytes from start up to end
+pub fn serve_ranges<F>(
+    request: &Request,
+    metadata: Metadata,
+    content_type: &str,
+    open: F,
+) -> Response
+where
+    F: FnOnce(u64, u64) -> Result<Box<dyn Read + Send>>,
+{
+    let Metadata {
+        size,
+        version,
+        last_modified,
+        ..
+    } = metadata;
+    let etag = format!("\"{}\"", version.trim_matches('"'));
+    let last_modified_date = httpdate::fmt_http_date(last_modified);
+
+    // If-Modified-Since is only used by clients that don't send ETags
+    let not_modified = match request.header("If-None-Match") {
+        Some(if_none_match) => etag_matches(if_none_match, &etag),
+        None => match request.header("If-Modified-Since") {
+            Some(since) => unmodified_since(since, last_modified),
+            None => false,
+        },
+    };
+    if not_modified {
+        return with_headers(
+            Response::empty_204().with_status_code(304),
+            &etag,
+            &last_modified_date,
+        );
+    }
+
+    let range = match request.header("Range") {
+        Some(range) if range_applies(request, &etag, last_modified) => parse_range(range, size),
+        _ => ByteRange::Whole,
+    };
+    let (status_code, start, end) = match range {
+        ByteRange::Whole => (200, 0, size),
+        ByteRange::Part(start, end) => (206, start, end),
+        ByteRange::Unsatisfiable => {
+            return with_headers(
+                Response::text("Requested range not satisfiable")
+                    .with_status_code(416)
+                    .with_unique_header("Content-Range", format!("bytes */{}", size)),
+                &etag,
+                &last_modified_date,
+            );
+        }
+    };
+
+    let reader = match open(start, end) {
+        Ok(reader) => reader,
+        Err(error) => {
+            return Response::text(format!("Unable to read song: {}", error)).with_status_code(500);
+        }
+    };
+    let mut response = Response {
+        status_code,
+        headers: vec![("Content-Type".into(), content_type.to_string().into())],
+        data: ResponseBody::from_reader_and_size(reader, (end - start) as usize),
+        upgrade: None,
+    };
+    if status_code == 206 {
+        response = response.with_unique_header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, end - 1, size),
+        );
+    }
+    return with_headers(response, &etag, &last_modified_date);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Part(0, 100));
+        assert_eq!(parse_range("bytes=500-", 1000), ByteRange::Part(500, 1000));
+        assert_eq!(parse_range(" bytes=10-10 ", 1000), ByteRange::Part(10, 11));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_file() {
+        assert_eq!(
+            parse_range("bytes=900-2000", 1000),
+            ByteRange::Part(900, 1000)
+        );
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Part(900, 1000));
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Part(0, 1000));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn other_ranges_get_the_whole_file() {
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), ByteRange::Whole);
+        assert_eq!(parse_range("items=0-1", 1000), ByteRange::Whole);
+        assert_eq!(parse_range("bytes=5", 1000), ByteRange::Whole);
+        assert_eq!(parse_range("bytes=a-b", 1000), ByteRange::Whole);
+        assert_eq!(parse_range("bytes=10-5", 1000), ByteRange::Whole);
+    }
+
+    #[test]
+    fn matches_etags() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"x\", \"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abcd\"", "\"abc\""));
+    }
+}
@@ -8,11 +8,24 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::path::Path;
 
-use crate::args::SyncRhythmdbArgs;
+use crate::art;
+use crate::config::config;
 use crate::library::{Library, Song};
+use crate::output;
 use crate::storage;
 
+#[derive(Serialize)]
+struct SyncSummary {
+    matched: usize,
+    added: usize,
+    failed_uploads: usize,
+    removed: usize,
+    new_album_art: usize,
+    dry_run: bool,
+}
+
 #[derive(PartialEq)]
 enum Element {
     Entry,
@@ -21,19 +34,29 @@ enum Element {
     Genre(String),
     Artist(String),
     Album(String),
+    AlbumArtist(String),
+    TrackNumber(u32),
+    DiscNumber(u32),
     Duration(u32),
     Rating(u32),
+    FirstSeen(i64),
     Location(String),
     Unknown,
     EOF,
 }
 
-pub fn sync_rhythmdb(args: SyncRhythmdbArgs) {
-    let library_location_prefix = sanitise_library_location_prefix(&args.library_location_prefix);
+pub fn sync_rhythmdb() {
+    // Both paths are checked to be present when the config is validated
+    let args = &config().sync;
+    let library_location_prefix =
+        sanitise_library_location_prefix(args.library_location_prefix.as_ref().unwrap());
 
     let dest_library = Library::new();
-    let source_library =
-        read_rhythmdb(&args.rhythmdb_file, &library_location_prefix, &dest_library);
+    let source_library = read_rhythmdb(
+        args.rhythmdb_file.as_ref().unwrap(),
+        &library_location_prefix,
+        &dest_library,
+    );
     let source_songs = LibraryHash::new(&source_library);
     let dest_songs = LibraryHash::new(&dest_library);
 
@@ -59,9 +82,20 @@ pub fn sync_rhythmdb(args: SyncRhythmdbArgs) {
         }
     }
 
-    println!("Matched {} songs", matched_songs.len());
-    println!("Found {} new songs", new_songs.len());
-    println!("Found {} removed songs", removed_songs.len());
+    info!("Matched {} songs", matched_songs.len());
+    info!("Found {} new songs", new_songs.len());
+    info!("Found {} removed songs", removed_songs.len());
+
+    if let Some(max_deleted_songs) = config().deletion.max_deleted_songs {
+        if removed_songs.len() > max_deleted_songs && !args.dry_run {
+            error!(
+                "Refusing to delete {} songs, as the limit is {}",
+                removed_songs.len(),
+                max_deleted_songs
+            );
+            std::process::exit(1);
+        }
+    }
 
     // Upload all new songs
     let mut failed_new_song_ids: Vec<String> = Vec::new();
@@ -69,7 +103,7 @@ pub fn sync_rhythmdb(args: SyncRhythmdbArgs) {
     for (i, song) in &mut new_songs.iter_mut().enumerate() {
         let new_file_location = song.correct_file_location();
         if !args.dry_run {
-            println!(
+            info!(
                 "Uploading {} to {} ({} / {})",
                 song.file_location, new_file_location, i, num_new_songs
             );
@@ -77,12 +111,12 @@ pub fn sync_rhythmdb(args: SyncRhythmdbArgs) {
                 &format!("{}{}", library_location_prefix, song.file_location),
                 &format!("Music{}", new_file_location),
             );
-            if upload_result.is_err() {
-                println!("Failed to upload {}", song.file_location);
+            if let Err(err) = upload_result {
+                error!("Failed to upload {}: {}", song.file_location, err);
                 failed_new_song_ids.push(song.id.clone());
             }
         } else if args.verbose {
-            println!(
+            info!(
                 "Would upload {} to {}",
                 song.file_location, new_file_location
             );
@@ -94,22 +128,69 @@ pub fn sync_rhythmdb(args: SyncRhythmdbArgs) {
     new_songs.retain(|song| !failed_new_song_ids.contains(&song.id));
 
     // Construct the new library and save it
-    let new_library = Library::combine_libraries(&matched_songs, &new_songs);
-    println!(
+    let mut new_library = Library::combine_libraries(&dest_library, &matched_songs, &new_songs);
+    info!(
         "Constructed new library with {} songs",
         new_library.songs.len()
     );
+
+    // Find art for any albums that haven't been looked at yet, and look
+    // again for albums without art when songs are added to them
+    let new_album_ids: Vec<String> = new_songs.iter().filter_map(|song| song.album_id()).collect();
+    let mut album_paths: HashMap<String, Vec<String>> = HashMap::new();
+    for song in source_library.songs.values() {
+        if let Some(album_id) = song.album_id() {
+            album_paths
+                .entry(album_id)
+                .or_default()
+                .push(format!("{}{}", library_location_prefix, song.file_location));
+        }
+    }
+    let mut new_album_art: usize = 0;
+    for (album_id, paths) in &mut album_paths {
+        let looked = match new_library.album_art.get(album_id) {
+            Some(name) => !name.is_empty() || !new_album_ids.contains(album_id),
+            None => false,
+        };
+        if looked {
+            continue;
+        }
+        paths.sort();
+        // Failures are left to be tried again by the next sync
+        match art::import(paths, args.dry_run) {
+            Ok(Some(name)) => {
+                if args.verbose {
+                    info!("Found art {} for album {}", name, album_id);
+                }
+                new_library.album_art.insert(album_id.clone(), name);
+                new_album_art += 1;
+            }
+            Ok(None) => {
+                new_library.album_art.insert(album_id.clone(), String::new());
+            }
+            Err(err) => {
+                error!("Failed to store art for {}: {}", paths[0], err);
+            }
+        }
+    }
+    info!("Found art for {} albums", new_album_art);
+    // Art found for songs that didn't otherwise change still needs a new
+    // revision for clients to see it
+    if new_album_art > 0 && new_library.revision == dest_library.revision {
+        new_library.next_revision();
+    }
+
     if !args.dry_run {
-        println!("Uploading library");
+        info!("Uploading library");
         new_library.save().unwrap();
     } else if args.verbose {
-        println!("Would upload new library");
+        info!("Would upload new library");
     }
 
     // Delete all removed songs
     for (i, song) in removed_songs.iter().enumerate() {
         if !args.dry_run {
-            println!(
+            info!(
                 "Deleting {} ({} / {})",
                 song.file_location,
                 i,
@@ -117,13 +198,52 @@ pub fn sync_rhythmdb(args: SyncRhythmdbArgs) {
             );
             let removal_result =
                 storage::rm(&format!("Music{}", song.file_location));
-            if removal_result.is_err() {
-                println!("Failed to delete {}", song.file_location);
+            if let Err(err) = removal_result {
+                error!("Failed to delete {}: {}", song.file_location, err);
             }
         } else if args.verbose {
-            println!("Would delete {}", song.file_location);
+            info!("Would delete {}", song.file_location);
         }
     }
+
+    if output::json() {
+        let summary = SyncSummary {
+            matched: matched_songs.len(),
+            added: new_songs.len(),
+            failed_uploads: failed_new_song_ids.len(),
+            removed: removed_songs.len(),
+            new_album_art,
+            dry_run: args.dry_run,
+        };
+        println!("{}", serde_json::to_string(&summary).unwrap());
+    }
+}
+
+// Finds where songs in the library are on this computer, by matching them
+// against the rhythmdb file in [sync] the same way syncing does. Returns a
+// map from song ids to local paths, which is empty without a [sync] section.
+pub fn local_paths(library: &Library) -> HashMap<String, String> {
+    let sync = &config().sync;
+    let (rhythmdb_file, prefix) = match (&sync.rhythmdb_file, &sync.library_location_prefix) {
+        (Some(rhythmdb_file), Some(prefix)) => (rhythmdb_file, prefix),
+        _ => return HashMap::new(),
+    };
+    if !Path::new(rhythmdb_file).is_file() || !Path::new(prefix).is_dir() {
+        warn!("Unable to find {} or {}", rhythmdb_file, prefix);
+        return HashMap::new();
+    }
+    let prefix = sanitise_library_location_prefix(prefix);
+
+    let source_library = read_rhythmdb(rhythmdb_file, &prefix, library);
+    let songs = LibraryHash::new(library);
+    return source_library
+        .songs
+        .values()
+        .filter_map(|source_song| {
+            let song = songs.lookup(source_song)?;
+            Some((song.id, format!("{}{}", prefix, source_song.file_location)))
+        })
+        .collect();
 }
 
 fn sanitise_library_location_prefix(prefix: &str) -> String {
@@ -153,7 +273,10 @@ fn read_rhythmdb(
     reader.read_line(&mut line).unwrap();
 
     let mut library = Library {
+        revision: 0,
         songs: HashMap::new(),
+        removed_songs: HashMap::new(),
+        album_art: HashMap::new(),
     };
     loop {
         match read_song(&mut reader, &library_location_prefix) {
@@ -184,9 +307,17 @@ fn read_song(input_file: &mut BufReader<File>, library_location_prefix: &str) ->
         genre: String::new(),
         artist: String::new(),
         album: String::new(),
+        album_artist: String::new(),
+        disc_number: 0,
+        track_number: 0,
         duration: 0,
         rating: 0,
         file_location: String::new(),
+        added_revision: 0,
+        updated_revision: 0,
+        first_seen: None,
+        replay_gain: None,
+        audio: None,
     };
 
     while !element.eq(&Element::CloseEntry) && !element.eq(&Element::EOF) {
@@ -203,10 +334,18 @@ fn read_song(input_file: &mut BufReader<File>, library_location_prefix: &str) ->
             Element::Album(album) => {
                 song.album = decode(&album);
             }
+            Element::AlbumArtist(album_artist) => {
+                song.album_artist = decode(&album_artist);
+            }
+            Element::TrackNumber(track_number) => song.track_number = track_number,
+            Element::DiscNumber(disc_number) => song.disc_number = disc_number,
             Element::Duration(duration) => song.duration = duration,
             Element::Rating(rating) => {
                 song.rating = rating;
             }
+            Element::FirstSeen(first_seen) => {
+                song.first_seen = Some(first_seen);
+            }
             Element::Location(location) => {
                 let prefix = format!("file://{}", library_location_prefix);
                 if !location.starts_with(&prefix) {
@@ -262,6 +401,21 @@ fn read_element(input_file: &mut BufReader<File>) -> Element {
     if line.starts_with("<album>") && line.ends_with("</album>") {
         return Element::Album(line[7..line.len() - 8].to_string());
     }
+    if line.starts_with("<album-artist>") && line.ends_with("</album-artist>") {
+        return Element::AlbumArtist(line[14..line.len() - 15].to_string());
+    }
+    if line.starts_with("<track-number>") && line.ends_with("</track-number>") {
+        return match line[14..line.len() - 15].parse::<u32>() {
+            Ok(track_number) => Element::TrackNumber(track_number),
+            Err(_) => Element::Unknown,
+        };
+    }
+    if line.starts_with("<disc-number>") && line.ends_with("</disc-number>") {
+        return match line[13..line.len() - 14].parse::<u32>() {
+            Ok(disc_number) => Element::DiscNumber(disc_number),
+            Err(_) => Element::Unknown,
+        };
+    }
     if line.starts_with("<duration>") && line.ends_with("</duration>") {
         let contents = &line[10..line.len() - 11];
         if contents.len() == 0 {
@@ -278,6 +432,13 @@ fn read_element(input_file: &mut BufReader<File>) -> Element {
             return Element::Rating(contents.parse::<u32>().unwrap());
         }
     }
+    if line.starts_with("<first-seen>") && line.ends_with("</first-seen>") {
+        let contents = &line[12..line.len() - 13];
+        return match contents.parse::<i64>() {
+            Ok(first_seen) => Element::FirstSeen(first_seen),
+            Err(_) => Element::Unknown,
+        };
+    }
     if line.starts_with("<location>") && line.ends_with("</location>") {
         return Element::Location(line[10..line.len() - 11].to_string());
     }
@@ -0,0 +1,302 @@
+extern crate clap;
+extern crate serde_json;
+extern crate sha2;
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Result;
+use time::OffsetDateTime;
+
+use crate::args::{CreateTokenArgs, RevokeTokenArgs};
+use crate::auth::constant_time_eq;
+use crate::library::random_string;
+use crate::output;
+use crate::storage;
+use crate::users::Users;
+
+const TOKENS_PATH: &str = "tokens.json";
+const TOKEN_PREFIX: &str = "rhy";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    // Anything that doesn't change state
+    ReadOnly,
+    // Rating songs and recording plays, as well as the user's queue,
+    // sessions and playlists
+    Rate,
+    // Managing tokens and other administrative endpoints
+    Admin,
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Scope::ReadOnly => "read-only",
+            Scope::Rate => "rate",
+            Scope::Admin => "admin",
+        };
+    }
+
+    // Each scope includes everything that the scopes before it allow
+    pub fn allows(&self, required: Scope) -> bool {
+        return match self {
+            Scope::ReadOnly => required == Scope::ReadOnly,
+            Scope::Rate => required != Scope::Admin,
+            Scope::Admin => true,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiToken {
+    pub id: String,
+    pub user: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // SHA-256 of the secret part of the token. Tokens are long and random,
+    // so unlike passwords they don't need a slow hash.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub secret_hash: String,
+    pub created_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    pub token: ApiToken,
+    // The only time the full token is ever shown
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Tokens {
+    pub tokens: HashMap<String, ApiToken>,
+}
+
+impl Tokens {
+    pub fn new() -> Tokens {
+        return match Tokens::load() {
+            Ok(tokens) => tokens,
+            Err(error) => panic!("Unable to load tokens: {}", error),
+        };
+    }
+
+    pub fn load() -> Result<Tokens> {
+        if !storage::exists(TOKENS_PATH)? {
+            return Result::Ok(Tokens {
+                tokens: HashMap::new(),
+            });
+        }
+        let data = storage::cat(TOKENS_PATH)?;
+        return Result::Ok(serde_json::from_slice(&data)?);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec(self)?;
+        return storage::put(TOKENS_PATH, data);
+    }
+
+    // The version of the tokens in storage, which is None until one is
+    // created
+    pub fn version() -> Result<Option<String>> {
+        if !storage::exists(TOKENS_PATH)? {
+            return Result::Ok(None);
+        }
+        return storage::version(TOKENS_PATH).map(Some);
+    }
+
+    // Creates a new token, returning it along with the full token string.
+    // The string is never stored, so it can only be shown to the user now.
+    pub fn create(&mut self, user: &str, name: &str, scopes: Vec<Scope>) -> CreatedToken {
+        let id = random_string(8);
+        let secret = random_string(32);
+        let token = ApiToken {
+            id: id.clone(),
+            user: user.to_string(),
+            name: name.to_string(),
+            scopes,
+            secret_hash: hash_secret(&secret),
+            created_at: OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        self.tokens.insert(id.clone(), token.clone());
+
+        let mut token = token;
+        token.secret_hash = String::new();
+        return CreatedToken {
+            token,
+            secret: format!("{}_{}_{}", TOKEN_PREFIX, id, secret),
+        };
+    }
+
+    // Returns the token matching a token string, if there is one
+    pub fn verify(&self, token: &str) -> Option<&ApiToken> {
+        let mut parts = token.splitn(3, '_');
+        if parts.next()? != TOKEN_PREFIX {
+            return None;
+        }
+        let id = parts.next()?;
+        let secret = parts.next()?;
+
+        let stored = self.tokens.get(id)?;
+        if constant_time_eq(
+            hash_secret(secret).as_bytes(),
+            stored.secret_hash.as_bytes(),
+        ) {
+            return Some(stored);
+        }
+        return None;
+    }
+
+    // Lists the user's tokens, or everyone's if no user is given, without
+    // their hashes
+    pub fn list(&self, user: Option<&str>) -> Vec<ApiToken> {
+        let mut tokens: Vec<ApiToken> = Vec::new();
+        for token in self.tokens.values() {
+            let visible = match user {
+                Some(user) => token.user == user,
+                None => true,
+            };
+            if visible {
+                let mut token = token.clone();
+                token.secret_hash = String::new();
+                tokens.push(token);
+            }
+        }
+        tokens.sort_by_key(|token| token.created_at);
+        return tokens;
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    let digest = Sha256::digest(secret.as_bytes());
+    return digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+pub fn create_token(args: CreateTokenArgs) {
+    let users = Users::new();
+    let user = match users.users.get(&args.user) {
+        Some(user) => user,
+        None => {
+            eprintln!("User {} does not exist", args.user);
+            std::process::exit(1);
+        }
+    };
+    if args.scopes.contains(&Scope::Admin) && !user.admin {
+        eprintln!("Only admin users can have tokens with the admin scope");
+        std::process::exit(1);
+    }
+
+    let mut tokens = Tokens::new();
+    let created = tokens.create(&args.user, &args.name, args.scopes);
+    tokens.save().expect("Unable to save tokens");
+    if output::json() {
+        println!("{}", serde_json::to_string(&created).unwrap());
+        return;
+    }
+    status!("Created token {} for user {}", created.token.id, created.token.user);
+    println!("{}", created.secret);
+}
+
+pub fn revoke_token(args: RevokeTokenArgs) {
+    let mut tokens = Tokens::new();
+    if tokens.tokens.remove(&args.id).is_none() {
+        eprintln!("Token {} does not exist", args.id);
+        std::process::exit(1);
+    }
+    tokens.save().expect("Unable to save tokens");
+    status!("Revoked token {}", args.id);
+}
+
+pub fn list_tokens() {
+    let tokens = Tokens::new();
+    if output::json() {
+        println!("{}", serde_json::to_string(&tokens.list(None)).unwrap());
+        return;
+    }
+    for token in tokens.list(None) {
+        let scopes: Vec<&str> = token.scopes.iter().map(|scope| scope.name()).collect();
+        println!(
+            "{} {} {} [{}]",
+            token.id,
+            token.user,
+            token.name,
+            scopes.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> Tokens {
+        return Tokens {
+            tokens: HashMap::new(),
+        };
+    }
+
+    #[test]
+    fn created_tokens_verify() {
+        let mut tokens = tokens();
+        let created = tokens.create("bob", "script", vec![Scope::Rate]);
+        assert!(created.secret.starts_with("rhy_"));
+        assert!(created.token.secret_hash.is_empty());
+
+        let token = tokens.verify(&created.secret).unwrap();
+        assert_eq!(token.id, created.token.id);
+        assert_eq!(token.user, "bob");
+        assert_eq!(token.scopes, vec![Scope::Rate]);
+    }
+
+    #[test]
+    fn wrong_tokens_dont_verify() {
+        let mut tokens = tokens();
+        let created = tokens.create("bob", "script", vec![Scope::ReadOnly]);
+        let id = &created.token.id;
+
+        assert!(tokens.verify("").is_none());
+        assert!(tokens.verify(&format!("rhy_{}", id)).is_none());
+        assert!(tokens.verify(&format!("rhy_{}_wrong", id)).is_none());
+        let unknown = format!("rhy_unknown_{}", "x".repeat(32));
+        assert!(tokens.verify(&unknown).is_none());
+        let secret = created.secret.strip_prefix("rhy_").unwrap();
+        assert!(tokens.verify(&format!("other_{}", secret)).is_none());
+    }
+
+    #[test]
+    fn revoked_tokens_dont_verify() {
+        let mut tokens = tokens();
+        let created = tokens.create("bob", "script", vec![Scope::ReadOnly]);
+        tokens.tokens.remove(&created.token.id);
+        assert!(tokens.verify(&created.secret).is_none());
+    }
+
+    #[test]
+    fn list_hides_hashes_and_other_users() {
+        let mut tokens = tokens();
+        tokens.create("bob", "script", vec![Scope::ReadOnly]);
+        tokens.create("alice", "phone", vec![Scope::Rate]);
+
+        let listed = tokens.list(Some("bob"));
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, "script");
+        assert!(listed[0].secret_hash.is_empty());
+        assert_eq!(tokens.list(None).len(), 2);
+    }
+
+    #[test]
+    fn scopes_include_lesser_scopes() {
+        assert!(Scope::Admin.allows(Scope::Rate));
+        assert!(Scope::Rate.allows(Scope::ReadOnly));
+        assert!(!Scope::Rate.allows(Scope::Admin));
+        assert!(!Scope::ReadOnly.allows(Scope::Rate));
+    }
+
+    #[test]
+    fn scope_names_match_the_command_line() {
+        for scope in Scope::value_variants() {
+            assert_eq!(Scope::from_str(scope.name(), false), Ok(*scope));
+        }
+    }
+}
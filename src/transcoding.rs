@@ -0,0 +1,401 @@
+use rouille::{Request, Response, ResponseBody};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Error, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::config::config;
+use crate::library::random_id;
+use crate::probe::Download;
+use crate::storage::Metadata;
+use crate::streaming;
+
+// Limits on the bitrate clients can ask for, in kbit/s
+pub const MIN_BITRATE: u32 = 16;
+pub const MAX_BITRATE: u32 = 320;
+
+const FIRST_READ_SIZE: usize = 64 * 1024;
+
+// Partial transcodes that haven't been written to for this long were left
+// by a server that stopped part way through, and are never finished
+const STALE_PARTIAL_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Opus,
+    Mp3,
+    Aac,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        return match name {
+            "opus" => Some(Format::Opus),
+            "mp3" => Some(Format::Mp3),
+            "aac" => Some(Format::Aac),
+            _ => None,
+        };
+    }
+
+    pub fn default_bitrate(&self) -> u32 {
+        return match self {
+            Format::Opus => 96,
+            Format::Mp3 | Format::Aac => 128,
+        };
+    }
+
+    fn extension(&self) -> &'static str {
+        return match self {
+            Format::Opus => "opus",
+            Format::Mp3 => "mp3",
+            Format::Aac => "aac",
+        };
+    }
+
+    fn content_type(&self) -> &'static str {
+        return match self {
+            Format::Opus => "audio/ogg; codecs=opus",
+            Format::Mp3 => "audio/mpeg",
+            Format::Aac => "audio/aac",
+        };
+    }
+
+    // Encoder and container for ffmpeg, chosen so that the output can be
+    // played while it is still being written
+    fn ffmpeg_args(&self) -> [&'static str; 4] {
+        return match self {
+            Format::Opus => ["-c:a", "libopus", "-f", "ogg"],
+            Format::Mp3 => ["-c:a", "libmp3lame", "-f", "mp3"],
+            Format::Aac => ["-c:a", "aac", "-f", "adts"],
+        };
+    }
+}
+
+fn cache_dir() -> PathBuf {
+    return match &config().transcoding.cache_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::temp_dir().join("rhythmical-transcodes"),
+    };
+}
+
+// Named after the song and the settings, and the version of the original
+// so that songs replaced in storage are transcoded again. The version is
+// hashed with SHA-256 so that names stay the same between releases.
+fn cache_path(id: &str, version: &str, format: Format, bitrate: u32) -> PathBuf {
+    let hash: String = Sha256::digest(version.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    return cache_dir().join(format!(
+        "{}-{}-{}k.{}",
+        id,
+        hash,
+        bitrate,
+        format.extension()
+    ));
+}
+
+// Deletes the least recently used transcodes until the cache fits.
+// Transcodes that are still being written are left alone, unless they
+// are stale.
+fn prune_cache(dir: &Path, max_bytes: u64) -> Result<()> {
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let partial = entry
+            .path()
+            .extension()
+            .is_some_and(|extension| extension == "partial");
+        if !partial {
+            files.push((metadata.modified()?, metadata.len(), entry.path()));
+        } else if metadata
+            .modified()?
+            .elapsed()
+            .is_ok_and(|age| age > STALE_PARTIAL_AGE)
+        {
+            fs::remove_file(entry.path())?;
+            debug!(
+                "Removed stale {} from the transcoding cache",
+                entry.path().display()
+            );
+        }
+    }
+    files.sort();
+
+    let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+    for (_, size, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        fs::remove_file(&path)?;
+        debug!("Removed {} from the transcoding cache", path.display());
+        total -= size;
+    }
+    return Result::Ok(());
+}
+
+// Reads the output of the encoder as it is sent, and also writes it to
+// the cache. The cached copy is only kept if the encoder finishes.
+struct Transcoder {
+    child: Child,
+    output: ChildStdout,
+    // Errors from the encoder, which are read as it goes so that it never
+    // blocks on a full pipe
+    errors: Option<JoinHandle<String>>,
+    partial: Option<(File, PathBuf)>,
+    cache_path: PathBuf,
+    finished: bool,
+    // Kept until the encoder is done with it
+    _original: Download,
+}
+
+impl Transcoder {
+    fn discard_partial(&mut self) {
+        if let Some((_, path)) = self.partial.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finished = true;
+        let status = self.child.wait()?;
+        if !status.success() {
+            let errors = match self.errors.take() {
+                Some(errors) => errors.join().unwrap_or_default(),
+                None => String::new(),
+            };
+            self.discard_partial();
+            warn!("Transcoding failed with {}: {}", status, errors.trim());
+            return Result::Err(Error::other("transcoding failed"));
+        }
+
+        if let Some((file, path)) = self.partial.take() {
+            drop(file);
+            fs::rename(&path, &self.cache_path)?;
+            let max_bytes = config().transcoding.cache_size_mb * 1024 * 1024;
+            if let Err(error) = prune_cache(&cache_dir(), max_bytes) {
+                warn!("Unable to prune the transcoding cache: {}", error);
+            }
+        }
+        return Result::Ok(());
+    }
+}
+
+impl Read for Transcoder {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.finished {
+            return Ok(0);
+        }
+        let length = self.output.read(buf)?;
+        if length == 0 {
+            self.finish()?;
+            return Ok(0);
+        }
+
+        let written = match &mut self.partial {
+            Some((file, _)) => file.write_all(&buf[..length]).is_ok(),
+            None => true,
+        };
+        if !written {
+            warn!("Unable to write to the transcoding cache, not caching");
+            self.discard_partial();
+        }
+        return Ok(length);
+    }
+}
+
+// Stops the encoder if the client goes away before the song is finished
+impl Drop for Transcoder {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            self.discard_partial();
+        }
+    }
+}
+
+// Starts transcoding the song, once the original has been downloaded from
+// storage. The encoder reads it from disk, as some formats need seeking.
+fn start(path: &str, format: Format, bitrate: u32, cache_path: &Path) -> Result<Transcoder> {
+    let original = Download::new(path)?;
+    let mut child = Command::new(&config().transcoding.ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(&original.path)
+        .arg("-vn")
+        .args(format.ffmpeg_args())
+        .args(["-b:a", &format!("{}k", bitrate), "pipe:1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stderr = child.stderr.take().unwrap();
+    let errors = thread::spawn(move || {
+        let mut errors = String::new();
+        let _ = stderr.read_to_string(&mut errors);
+        return errors;
+    });
+
+    let partial = if config().transcoding.cache_size_mb > 0 {
+        let partial_path = cache_path.with_extension(format!("{}.partial", random_id()));
+        match fs::create_dir_all(cache_dir()).and_then(|_| File::create(&partial_path)) {
+            Ok(file) => Some((file, partial_path)),
+            Err(error) => {
+                warn!("Unable to write to the transcoding cache: {}", error);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    return Ok(Transcoder {
+        output: child.stdout.take().unwrap(),
+        errors: Some(errors),
+        child,
+        partial,
+        cache_path: cache_path.to_path_buf(),
+        finished: false,
+        _original: original,
+    });
+}
+
+// Sends the song re-encoded in a smaller format. The first request for a
+// song is streamed as the encoder runs, and later ones are served from
+// the cache with support for ranges.
+pub fn serve(request: &Request, id: &str, path: &str, format: Format, bitrate: u32) -> Response {
+    let original = match streaming::stat_song(path) {
+        Ok(metadata) => metadata,
+        Err(response) => return response,
+    };
+    let cache_path = cache_path(id, &original.version, format, bitrate);
+
+    if let Ok(metadata) = fs::metadata(&cache_path) {
+        // Marks the file as recently used, so that it is pruned last
+        if let Ok(file) = File::options().write(true).open(&cache_path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        let metadata = Metadata {
+            size: metadata.len(),
+            version: cache_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            last_modified: original.last_modified,
+            blocks: None,
+        };
+        return streaming::serve_ranges(
+            request,
+            metadata,
+            format.content_type(),
+            move |start, end| {
+                let mut file = File::open(&cache_path)?;
+                file.seek(SeekFrom::Start(start))?;
+                return Ok(Box::new(file.take(end - start)));
+            },
+        );
+    }
+
+    let mut transcoder = match start(path, format, bitrate, &cache_path) {
+        Ok(transcoder) => transcoder,
+        Err(error) => {
+            error!("Unable to start transcoding {}: {}", path, error);
+            return Response::text(format!("Unable to transcode song: {}", error))
+                .with_status_code(500);
+        }
+    };
+
+    // Waits for the first output, so that songs the encoder can't read at
+    // all get an error response rather than a connection that stops
+    let mut first = vec![0; FIRST_READ_SIZE];
+    match transcoder.read(&mut first) {
+        Ok(length) => first.truncate(length),
+        Err(error) => {
+            return Response::text(format!("Unable to transcode song: {}", error))
+                .with_status_code(500);
+        }
+    }
+
+    return Response {
+        status_code: 200,
+        headers: vec![("Content-Type".into(), format.content_type().into())],
+        data: ResponseBody::from_reader(io::Cursor::new(first).chain(transcoder)),
+        upgrade: None,
+    }
+    .with_unique_header("Cache-Control", "private, no-cache");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_are_parsed_by_name() {
+        assert_eq!(Format::parse("opus"), Some(Format::Opus));
+        assert_eq!(Format::parse("mp3"), Some(Format::Mp3));
+        assert_eq!(Format::parse("aac"), Some(Format::Aac));
+        assert_eq!(Format::parse("flac"), None);
+        assert_eq!(Format::parse("MP3"), None);
+    }
+
+    #[test]
+    fn cached_files_are_named_after_the_settings() {
+        crate::config::init_for_tests();
+        let path = cache_path("abc", "v1", Format::Mp3, 128);
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("abc-"));
+        assert!(name.ends_with("-128k.mp3"));
+        assert_eq!(path, cache_path("abc", "v1", Format::Mp3, 128));
+
+        assert_ne!(path, cache_path("abc", "v2", Format::Mp3, 128));
+        assert_ne!(path, cache_path("abc", "v1", Format::Mp3, 64));
+        assert_ne!(path, cache_path("abc", "v1", Format::Opus, 128));
+        assert_ne!(path, cache_path("abd", "v1", Format::Mp3, 128));
+    }
+
+    #[test]
+    fn pruning_removes_the_least_recently_used_files() {
+        let dir = std::env::temp_dir().join(format!("rhythmical-prune-{}", random_id()));
+        fs::create_dir_all(&dir).unwrap();
+        let now = SystemTime::now();
+        for (name, age) in [("old", 30), ("middle", 20), ("new", 10)] {
+            let path = dir.join(name);
+            fs::write(&path, [0; 100]).unwrap();
+            let file = File::options().write(true).open(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+
+        prune_cache(&dir, 250).unwrap();
+        assert!(!dir.join("old").exists());
+        assert!(dir.join("middle").exists());
+        assert!(dir.join("new").exists());
+
+        prune_cache(&dir, 200).unwrap();
+        assert!(dir.join("middle").exists());
+
+        // Transcodes still being written are left alone, but not ones that
+        // were abandoned
+        fs::write(dir.join("song.abc.partial"), [0; 100]).unwrap();
+        fs::write(dir.join("song.def.partial"), [0; 100]).unwrap();
+        let file = File::options()
+            .write(true)
+            .open(dir.join("song.def.partial"))
+            .unwrap();
+        file.set_modified(now - STALE_PARTIAL_AGE * 2).unwrap();
+        prune_cache(&dir, 0).unwrap();
+        assert!(dir.join("song.abc.partial").exists());
+        assert!(!dir.join("song.def.partial").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_file(dir.join("song.abc.partial")).unwrap();
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::library::Song;
+use crate::play_log::PlayLog;
+use crate::storage;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    pub song_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct StoredUserData {
+    ratings: HashMap<String, u32>,
+    #[serde(default)]
+    rating_revisions: HashMap<String, u64>,
+    #[serde(default)]
+    ratings_version: u64,
+    playlists: Vec<Playlist>,
+}
+
+// Everything that belongs to a single user rather than being shared by the
+// whole library. Ratings and playlists are stored together, while plays
+// go to the user's own append-only log.
+pub struct UserData {
+    pub name: String,
+    pub ratings: HashMap<String, u32>,
+    // Map from song ids to the library revision their rating is reported
+    // as changed at, for the user's other clients to find in the changes
+    pub rating_revisions: HashMap<String, u64>,
+    // Increases by one whenever a rating changes
+    pub ratings_version: u64,
+    pub playlists: Vec<Playlist>,
+    pub play_log: PlayLog,
+}
+
+impl UserData {
+    fn data_path(name: &str) -> String {
+        return format!("users/{}/data.json", name);
+    }
+
+    fn load(name: &str) -> Result<UserData> {
+        let path = UserData::data_path(name);
+        let stored: StoredUserData = if storage::exists(&path)? {
+            serde_json::from_slice(&storage::cat(&path)?)?
+        } else {
+            StoredUserData::default()
+        };
+
+        return Result::Ok(UserData {
+            name: name.to_string(),
+            ratings: stored.ratings,
+            rating_revisions: stored.rating_revisions,
+            ratings_version: stored.ratings_version,
+            playlists: stored.playlists,
+            play_log: PlayLog::load(&format!("users/{}/plays.log", name))?,
+        });
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let stored = StoredUserData {
+            ratings: self.ratings.clone(),
+            rating_revisions: self.rating_revisions.clone(),
+            ratings_version: self.ratings_version,
+            playlists: self.playlists.clone(),
+        };
+        let data = serde_json::to_vec(&stored)?;
+        return storage::put(&UserData::data_path(&self.name), data);
+    }
+
+    // The user's own rating, falling back to the rating in the library
+    pub fn rating(&self, song: &Song) -> u32 {
+        return match self.ratings.get(&song.id) {
+            Some(rating) => *rating,
+            None => song.rating,
+        };
+    }
+
+    // Ids of the songs whose rating changed after the given library revision
+    pub fn ratings_changed_since(&self, since: u64) -> Vec<&String> {
+        return self
+            .rating_revisions
+            .iter()
+            .filter(|(_, revision)| **revision > since)
+            .map(|(id, _)| id)
+            .collect();
+    }
+}
+
+// Loads each user's data the first time it is needed and keeps it in memory
+pub struct UserDataStore {
+    users: Mutex<HashMap<String, Arc<RwLock<UserData>>>>,
+}
+
+impl UserDataStore {
+    pub fn new() -> UserDataStore {
+        return UserDataStore {
+            users: Mutex::new(HashMap::new()),
+        };
+    }
+
+    // Compacts the play log of every user whose log needs it, returning how
+    // many were compacted. The user's data is locked throughout so that no
+    // plays are recorded part way through. A user whose log can't be
+    // compacted doesn't stop the others from being.
+    pub fn compact_play_logs(&self) -> Result<usize> {
+        let names: Vec<String> = storage::ls("users/")?
+            .iter()
+            .filter_map(|path| path.strip_prefix("users/")?.strip_suffix("/plays.log"))
+            .map(|name| name.to_string())
+            .collect();
+        let mut compacted = 0;
+        for name in &names {
+            let result = self.get(name).and_then(|user_data| {
+                let user_data = user_data.write().unwrap();
+                return user_data.play_log.compact();
+            });
+            match result {
+                Ok(true) => compacted += 1,
+                Ok(false) => {}
+                Err(error) => warn!("Unable to compact the play log of {}: {}", name, error),
+            }
+        }
+        return Result::Ok(compacted);
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<RwLock<UserData>>> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user_data) = users.get(name) {
+            return Result::Ok(user_data.clone());
+        }
+
+        let user_data = Arc::new(RwLock::new(UserData::load(name)?));
+        users.insert(name.to_string(), user_data.clone());
+        return Result::Ok(user_data);
+    }
+}
+
+// Data for a user who has made the given plays and nothing else, for tests
+#[cfg(test)]
+pub fn test_user_data(plays: Vec<crate::play_log::Play>) -> UserData {
+    return UserData {
+        name: "test".to_string(),
+        ratings: HashMap::new(),
+        rating_revisions: HashMap::new(),
+        ratings_version: 0,
+        playlists: Vec::new(),
+        play_log: PlayLog::from_plays(plays),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::test_song;
+
+    #[test]
+    fn ratings_fall_back_to_the_library() {
+        let mut song = test_song("a", "Artist", "Album");
+        song.rating = 2;
+        let mut user_data = test_user_data(Vec::new());
+        assert_eq!(user_data.rating(&song), 2);
+        user_data.ratings.insert("a".to_string(), 5);
+        assert_eq!(user_data.rating(&song), 5);
+        user_data.ratings.insert("a".to_string(), 0);
+        assert_eq!(user_data.rating(&song), 0);
+    }
+
+    #[test]
+    fn changed_ratings_are_found_by_revision() {
+        let mut user_data = test_user_data(Vec::new());
+        user_data.rating_revisions.insert("a".to_string(), 3);
+        user_data.rating_revisions.insert("b".to_string(), 5);
+        assert_eq!(user_data.ratings_changed_since(2).len(), 2);
+        assert_eq!(user_data.ratings_changed_since(3), vec!["b"]);
+        assert!(user_data.ratings_changed_since(5).is_empty());
+    }
+
+    #[test]
+    fn logs_on_disk_are_never_compacted() {
+        crate::config::init_for_tests();
+        storage::put("users/store-test/plays.log", Vec::new()).unwrap();
+        assert_eq!(UserDataStore::new().compact_play_logs().unwrap(), 0);
+    }
+}
@@ -0,0 +1,251 @@
+extern crate argon2;
+extern crate rand;
+extern crate rpassword;
+extern crate serde_json;
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::{rng, Rng};
+use std::collections::HashMap;
+use std::io::{Error, Result};
+
+use crate::args::UserArgs;
+use crate::storage;
+
+const USERS_PATH: &str = "users.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct User {
+    pub name: String,
+    // Argon2 hash of the password, in PHC string format
+    pub password_hash: String,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Users {
+    pub users: HashMap<String, User>,
+}
+
+impl Users {
+    pub fn new() -> Users {
+        return match Users::load() {
+            Ok(users) => users,
+            Err(error) => panic!("Unable to load users: {}", error),
+        };
+    }
+
+    pub fn load() -> Result<Users> {
+        if !storage::exists(USERS_PATH)? {
+            return Result::Ok(Users {
+                users: HashMap::new(),
+            });
+        }
+        let data = storage::cat(USERS_PATH)?;
+        return Result::Ok(serde_json::from_slice(&data)?);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec(self)?;
+        return storage::put(USERS_PATH, data);
+    }
+
+    // The version of the users in storage, which is None until one is added
+    pub fn version() -> Result<Option<String>> {
+        if !storage::exists(USERS_PATH)? {
+            return Result::Ok(None);
+        }
+        return storage::version(USERS_PATH).map(Some);
+    }
+
+    // Returns the user only if the password is correct
+    pub fn verify(&self, name: &str, password: &str) -> Option<&User> {
+        let user = self.users.get(name)?;
+        let hash = PasswordHash::new(&user.password_hash).ok()?;
+        return match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Some(user),
+            Err(_) => None,
+        };
+    }
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt_bytes: [u8; 16] = rng().random();
+    let salt = SaltString::encode_b64(&salt_bytes).map_err(|err| Error::other(err.to_string()))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| Error::other(err.to_string()))?;
+    return Result::Ok(hash.to_string());
+}
+
+fn read_new_password() -> String {
+    let password = rpassword::prompt_password("Password: ").expect("Unable to read password");
+    let confirmation =
+        rpassword::prompt_password("Confirm password: ").expect("Unable to read password");
+    if password != confirmation {
+        eprintln!("Passwords do not match");
+        std::process::exit(1);
+    }
+    if password.is_empty() {
+        eprintln!("Password must not be empty");
+        std::process::exit(1);
+    }
+    return password;
+}
+
+// Before there were users, plays and the queue belonged to a single
+// listener. They are moved to the first user added, who takes them over.
+fn move_single_user_data(name: &str) -> Result<()> {
+    // The play log is appended to, so it has to stay an append blob
+    let moves = [
+        (
+            "plays.log".to_string(),
+            format!("users/{}/plays.log", name),
+            true,
+        ),
+        (
+            "queues/default.json".to_string(),
+            format!("queues/{}.json", name),
+            false,
+        ),
+    ];
+    for (old_path, new_path, append_blob) in &moves {
+        if storage::exists(old_path)? && !storage::exists(new_path)? {
+            status!("Moving {} to {}", old_path, new_path);
+            let data = storage::cat(old_path)?;
+            if *append_blob {
+                storage::put_append(new_path, data, None)?;
+            } else {
+                storage::put(new_path, data)?;
+            }
+            storage::rm(old_path)?;
+        }
+    }
+    return Result::Ok(());
+}
+
+pub fn add_user(args: UserArgs) {
+    // Names are used in storage paths, so keep them simple
+    let valid_name = args
+        .name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if args.name.is_empty() || !valid_name {
+        eprintln!("User names may only contain letters, digits, '-' and '_'");
+        std::process::exit(1);
+    }
+
+    let mut users = Users::new();
+    if users.users.contains_key(&args.name) {
+        eprintln!("User {} already exists", args.name);
+        std::process::exit(1);
+    }
+
+    let password = read_new_password();
+    let first_user = users.users.is_empty();
+    users.users.insert(
+        args.name.clone(),
+        User {
+            name: args.name.clone(),
+            password_hash: hash_password(&password).expect("Unable to hash password"),
+            admin: args.admin,
+        },
+    );
+    users.save().expect("Unable to save users");
+    status!("Added user {}", args.name);
+    if first_user {
+        move_single_user_data(&args.name).expect("Unable to move existing plays and queue");
+    }
+}
+
+pub fn reset_password(args: UserArgs) {
+    let mut users = Users::new();
+    let user = match users.users.get_mut(&args.name) {
+        Some(user) => user,
+        None => {
+            eprintln!("User {} does not exist", args.name);
+            std::process::exit(1);
+        }
+    };
+
+    let password = read_new_password();
+    user.password_hash = hash_password(&password).expect("Unable to hash password");
+    if args.admin {
+        user.admin = true;
+    }
+    users.save().expect("Unable to save users");
+    status!("Reset password for user {}", args.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::play_log::{Play, PlayLog};
+
+    #[test]
+    fn verifies_passwords() {
+        let mut users = Users {
+            users: HashMap::new(),
+        };
+        users.users.insert(
+            "alice".to_string(),
+            User {
+                name: "alice".to_string(),
+                password_hash: hash_password("correct horse").unwrap(),
+                admin: false,
+            },
+        );
+        assert_eq!(
+            users.verify("alice", "correct horse").unwrap().name,
+            "alice"
+        );
+        assert!(users.verify("alice", "battery staple").is_none());
+        assert!(users.verify("bob", "correct horse").is_none());
+    }
+
+    #[test]
+    fn hashes_are_salted() {
+        assert_ne!(
+            hash_password("secret").unwrap(),
+            hash_password("secret").unwrap()
+        );
+    }
+
+    fn play(song_id: &str) -> Play {
+        return Play {
+            song_id: song_id.to_string(),
+            started_at: 10,
+            fraction: 1.0,
+        };
+    }
+
+    #[test]
+    fn single_user_data_moves_to_the_first_user() {
+        crate::config::init_for_tests();
+        let mut old_log = PlayLog::load("plays.log").unwrap();
+        old_log.record(play("a")).unwrap();
+        storage::put("queues/default.json", b"queue".to_vec()).unwrap();
+        storage::put("queues/migrate-test.json", b"existing".to_vec()).unwrap();
+
+        move_single_user_data("migrate-test").unwrap();
+        assert!(!storage::exists("plays.log").unwrap());
+        // Data the user already has is left alone
+        assert!(storage::exists("queues/default.json").unwrap());
+        assert_eq!(
+            storage::cat("queues/migrate-test.json").unwrap(),
+            b"existing"
+        );
+
+        // The moved log can still be appended to
+        let path = "users/migrate-test/plays.log";
+        PlayLog::load(path).unwrap().record(play("b")).unwrap();
+        let ids: Vec<String> = PlayLog::load(path)
+            .unwrap()
+            .plays
+            .into_iter()
+            .map(|play| play.song_id)
+            .collect();
+        assert_eq!(ids, vec!["a", "b"]);
+    }
+}
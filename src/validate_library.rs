@@ -2,11 +2,30 @@ use std::collections::HashSet;
 use std::iter::FromIterator;
 
 use crate::args::ValidateLibraryArgs;
+use crate::config::config;
 use crate::library::Library;
+use crate::output;
+use crate::probe;
 use crate::storage;
 
+// How far a song's real length can be from the one in the library, which
+// rhythmdb rounds to whole seconds
+const DURATION_TOLERANCE_SECONDS: f64 = 2.0;
+
+#[derive(Serialize)]
+struct ValidationSummary {
+    badly_located_songs: usize,
+    missing_songs: usize,
+    unknown_paths: usize,
+    // Only checked with --deep
+    corrupt_songs: usize,
+    duration_mismatches: usize,
+    dry_run: bool,
+}
+
 pub fn validate_library(args: ValidateLibraryArgs) {
     let mut library = Library::new();
+    library.next_revision();
 
     let mut badly_located_songs: Vec<String> = Vec::new();
     for song in library.songs.values() {
@@ -14,7 +33,7 @@ pub fn validate_library(args: ValidateLibraryArgs) {
             badly_located_songs.push(song.id.clone());
         }
     }
-    println!(
+    info!(
         "Found {} songs not at an expected file location",
         badly_located_songs.len()
     );
@@ -32,12 +51,36 @@ pub fn validate_library(args: ValidateLibraryArgs) {
             missing_songs.push(song.id.clone());
         }
     }
-    println!("Found {} paths to be deleted", unknown_paths.len());
-    println!(
+    info!("Found {} paths to be deleted", unknown_paths.len());
+    info!(
         "Found {} songs where the file is missing",
         missing_songs.len()
     );
 
+    let limits = &config().deletion;
+    if !args.dry_run {
+        if let Some(max_deleted_songs) = limits.max_deleted_songs {
+            if missing_songs.len() > max_deleted_songs {
+                error!(
+                    "Refusing to remove {} songs, as the limit is {}",
+                    missing_songs.len(),
+                    max_deleted_songs
+                );
+                std::process::exit(1);
+            }
+        }
+        if let Some(max_deleted_files) = limits.max_deleted_files {
+            if unknown_paths.len() > max_deleted_files {
+                error!(
+                    "Refusing to delete {} files, as the limit is {}",
+                    unknown_paths.len(),
+                    max_deleted_files
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
     // Copy any badly located songs to their new location
     let mut paths_to_delete: Vec<String> = Vec::new();
     for (i, id) in badly_located_songs.iter().enumerate() {
@@ -45,7 +88,7 @@ pub fn validate_library(args: ValidateLibraryArgs) {
         let new_file_location = song.correct_file_location();
         paths_to_delete.push(song.file_location.clone());
         if !args.dry_run {
-            println!(
+            info!(
                 "Copying {} to {} ({} / {})",
                 song.file_location,
                 new_file_location,
@@ -60,41 +103,114 @@ pub fn validate_library(args: ValidateLibraryArgs) {
 
             let mut updated_song = song.clone();
             updated_song.file_location = new_file_location;
-            library.songs.insert(id.clone(), updated_song);
+            library.update_song(updated_song);
         } else if args.verbose {
-            println!("Would copy {} to {}", song.file_location, new_file_location);
+            info!("Would copy {} to {}", song.file_location, new_file_location);
         }
 
         // Do a checkpoint of our progress so far
         if i % 100 == 0 && !args.dry_run {
-            println!("Uploading library");
+            info!("Uploading library");
             library.save().unwrap();
         }
     }
 
+    // Decode every song that has a file, recording its format and finding
+    // any that are corrupt or a different length than the library says.
+    // Durations are only reported, as syncing matches songs by them.
+    let mut corrupt_songs: Vec<String> = Vec::new();
+    let mut duration_mismatches: Vec<String> = Vec::new();
+    if args.deep {
+        let mut ids: Vec<String> = library
+            .songs
+            .keys()
+            .filter(|id| !missing_songs.contains(id))
+            .cloned()
+            .collect();
+        ids.sort();
+        for (i, id) in ids.iter().enumerate() {
+            let song = library.songs.get(id).unwrap();
+            info!(
+                "Checking {} ({} / {})",
+                song.file_location,
+                i + 1,
+                ids.len()
+            );
+            let probe = match probe::probe(&format!("Music{}", song.file_location)) {
+                Ok(probe) => probe,
+                Err(err) => {
+                    warn!("Unable to decode {}: {}", song.file_location, err);
+                    corrupt_songs.push(id.clone());
+                    continue;
+                }
+            };
+            if !probe.errors.is_empty() {
+                warn!(
+                    "Errors while decoding {}: {}",
+                    song.file_location,
+                    probe.errors.join("; ")
+                );
+                corrupt_songs.push(id.clone());
+            }
+            if (probe.duration - song.duration as f64).abs() > DURATION_TOLERANCE_SECONDS {
+                warn!(
+                    "{} is {:.1} seconds long, but {} in the library",
+                    song.file_location, probe.duration, song.duration
+                );
+                duration_mismatches.push(id.clone());
+            }
+
+            if song.audio.as_ref() != Some(&probe.properties) {
+                let mut updated_song = song.clone();
+                updated_song.audio = Some(probe.properties);
+                library.update_song(updated_song);
+            }
+
+            // Do a checkpoint of our progress so far
+            if (i + 1) % 100 == 0 && !args.dry_run {
+                info!("Uploading library");
+                library.save().unwrap();
+            }
+        }
+        info!("Found {} songs that are corrupt", corrupt_songs.len());
+        info!(
+            "Found {} songs with a different duration",
+            duration_mismatches.len()
+        );
+    }
+
+    let summary = ValidationSummary {
+        badly_located_songs: badly_located_songs.len(),
+        missing_songs: missing_songs.len(),
+        unknown_paths: unknown_paths.len(),
+        corrupt_songs: corrupt_songs.len(),
+        duration_mismatches: duration_mismatches.len(),
+        dry_run: args.dry_run,
+    };
+
     // Remove from the library any songs where the file is missing
     for id in missing_songs {
         let song = library.songs.get(&id).unwrap();
         if !args.dry_run {
-            println!("Removing {} from the library", song.file_location);
-            library.songs.remove(&id);
+            info!("Removing {} from the library", song.file_location);
+            library.remove_song(&id);
         } else if args.verbose {
-            println!("Would remove {} from the library", song.file_location);
+            info!("Would remove {} from the library", song.file_location);
         }
     }
 
     // Upload the updated library
     if !args.dry_run {
-        println!("Uploading library");
+        info!("Uploading library");
         library.save().unwrap();
     } else if args.verbose {
-        println!("Would upload new library");
+        info!("Would upload new library");
     }
 
     // Delete the old files for songs that were just moved
     for (i, path) in paths_to_delete.iter().enumerate() {
         if !args.dry_run {
-            println!(
+            info!(
                 "Cleaning up old file {} ({} / {})",
                 path,
                 i,
@@ -103,26 +219,30 @@ pub fn validate_library(args: ValidateLibraryArgs) {
             match storage::rm(&format!("Music{}", path)) {
                 Ok(()) => {}
                 Err(err) => {
-                    println!("Unable to delete path \"{}\": {}", path, err);
+                    error!("Unable to delete path \"{}\": {}", path, err);
                 }
             }
         } else if args.verbose {
-            println!("Would clean up old file {}", path);
+            info!("Would clean up old file {}", path);
         }
     }
 
     // Delete any files that we don't know about
     for (i, path) in unknown_paths.iter().enumerate() {
         if !args.dry_run {
-            println!("Deleting {} ({} / {})", path, i, unknown_paths.len());
+            info!("Deleting {} ({} / {})", path, i, unknown_paths.len());
             match storage::rm( &format!("Music{}", path)) {
                 Ok(()) => {}
                 Err(err) => {
-                    println!("Unable to delete path \"{}\": {}", path, err);
+                    error!("Unable to delete path \"{}\": {}", path, err);
                 }
             }
         } else if args.verbose {
-            println!("Would delete {}", path);
+            info!("Would delete {}", path);
         }
     }
+
+    if output::json() {
+        println!("{}", serde_json::to_string(&summary).unwrap());
+    }
 }